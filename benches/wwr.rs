@@ -0,0 +1,55 @@
+//! Benchmarks for the WWR state machine at various window sizes.
+//!
+//! Run with `cargo bench --bench wwr`.
+
+extern crate myodine;
+
+use std::time::{Duration, Instant};
+
+use myodine::myo_proto::xfer::WwrState;
+
+const WINDOW_SIZES: [u16; 6] = [4, 64, 256, 1024, 16384, 65535];
+
+fn main() {
+    for &size in WINDOW_SIZES.iter() {
+        // Send enough data to fill the window a few times over.
+        let num_chunks = (size as usize).max(1024) * 4;
+        report("in order", size, num_chunks, bench_transfer(size, num_chunks, false));
+        report("reversed", size, num_chunks, bench_transfer(size, num_chunks, true));
+    }
+}
+
+/// Transfer chunks one window at a time, acknowledging every chunk.
+///
+/// If `reverse` is set, each window is delivered back to front, which is the
+/// worst case for the receiver.
+fn bench_transfer(win_size: u16, num_chunks: usize, reverse: bool) -> Duration {
+    let mut sender = WwrState::new(1, win_size, 0);
+    let mut receiver = WwrState::new(win_size, 1, 0);
+    sender.set_retransmit_timeout(Duration::from_secs(3600));
+    let start = Instant::now();
+    let mut received = 0;
+    while received < num_chunks {
+        while sender.send_buffer_space() > 0 {
+            sender.push_send_buffer(vec![0x55; 32]);
+        }
+        let mut chunks = Vec::new();
+        while let Some(chunk) = sender.next_send_chunk() {
+            chunks.push(chunk);
+        }
+        if reverse {
+            chunks.reverse();
+        }
+        for chunk in chunks {
+            received += receiver.handle_chunk(chunk).unwrap().len();
+            sender.handle_ack(&receiver.next_send_ack()).unwrap();
+        }
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, win_size: u16, num_chunks: usize, elapsed: Duration) {
+    let nanos = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+    println!("window={:<6} {:<9} {:>8} ns/chunk", win_size, name,
+        nanos / (num_chunks as u64));
+}
@@ -1,19 +1,76 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use myodine::dns_proto::RecordType;
 use myodine::myo_proto::name_code::{NameCode, get_name_code};
 use myodine::myo_proto::record_code::{RecordCode, get_record_code};
 
 use flags::Flags;
+use logger::RawLogger;
 
 /// Information about the optimal transport parameters
 /// supported by a server.
+#[derive(Clone)]
 pub struct Features {
     pub record_type: RecordType,
     pub response_encoding: String,
     pub response_mtu: u16,
     pub name_encoding: String,
     pub query_mtu: u16,
-    pub name_code: Box<NameCode>,
-    pub record_code: Box<RecordCode>
+    pub name_code: Arc<dyn NameCode>,
+    pub record_code: Arc<dyn RecordCode>
+}
+
+/// Discovered features, shared between sessions.
+///
+/// Features are keyed by the resolver and host they were discovered for, and
+/// are discovered again once they are too old or after a session using them
+/// fails.
+#[derive(Clone)]
+pub struct FeatureCache {
+    max_age: Duration,
+    entries: Arc<Mutex<HashMap<(String, String), Entry>>>
+}
+
+struct Entry {
+    time: Instant,
+    features: Features
+}
+
+impl FeatureCache {
+    /// Create an empty cache whose entries expire after `max_age`.
+    pub fn new(max_age: Duration) -> FeatureCache {
+        FeatureCache{max_age: max_age, entries: Arc::new(Mutex::new(HashMap::new()))}
+    }
+
+    /// Get the features for the resolver and host in the flags, discovering
+    /// them if necessary.
+    pub fn get(&self, flags: &Flags, logger: &RawLogger) -> Result<Features, String> {
+        // Holding the lock keeps concurrent callers from probing the same path.
+        let mut entries = self.entries.lock().unwrap();
+        let key = cache_key(flags);
+        if let Some(entry) = entries.get(&key) {
+            if entry.time.elapsed() < self.max_age {
+                return Ok(entry.features.clone());
+            }
+        }
+        logger.log(format!("discovering features @{} for {}...", flags.host, flags.addr));
+        let features = discover_features(flags)
+            .map_err(|e| format!("failed to discover features: {}", e))?;
+        entries.insert(key, Entry{time: Instant::now(), features: features.clone()});
+        Ok(features)
+    }
+
+    /// Forget the features for the resolver and host in the flags, so that
+    /// the next session discovers them again.
+    pub fn invalidate(&self, flags: &Flags) {
+        self.entries.lock().unwrap().remove(&cache_key(flags));
+    }
+}
+
+fn cache_key(flags: &Flags) -> (String, String) {
+    (flags.addr.clone(), format!("{}", flags.host).to_lowercase())
 }
 
 /// Figure out the optimal transport parameters that the
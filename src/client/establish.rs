@@ -1,31 +1,44 @@
 use std::net::UdpSocket;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use myodine::conn::dial_udp;
 use myodine::dns_coding::{dns_decode, dns_encode};
-use myodine::dns_proto::{Message, Question, RecordClass, RecordType};
+use myodine::dns_proto::{Message, Question, RecordClass, RecordType, ResponseCode};
 use myodine::myo_proto::establish::{EstablishQuery, EstablishResponse, password_proof};
+use myodine::myo_proto::ip::IpLease;
 use myodine::myo_proto::name_code::NameCode;
 use myodine::myo_proto::record_code::RecordCode;
+use myodine::myo_proto::xfer::AckEncoding;
+use rand::{Rng, thread_rng};
 
 use discovery::Features;
 use flags::Flags;
 
 /// Information about an established session.
 pub struct Establishment {
-    pub name_code: Box<NameCode>,
-    pub record_code: Box<RecordCode>,
+    pub name_code: Arc<dyn NameCode>,
+    pub record_code: Arc<dyn RecordCode>,
     pub record_type: RecordType,
     pub session_id: u16,
     pub seq_start: u32,
     pub query_mtu: u16,
     pub response_mtu: u16,
     pub query_window: u16,
-    pub response_window: u16
+    pub response_window: u16,
+    pub ack_encoding: AckEncoding,
+    pub ip_lease: Option<IpLease>
 }
 
 /// Open a new session.
-pub fn establish(flags: &Flags, features: Features) -> Result<Establishment, String> {
+///
+/// The channel is "mux" for a multiplexed session, "ip" for an IP tunnel, or
+/// "udp" for datagrams to the remote host and port.
+pub fn establish(
+    flags: &Flags,
+    features: Features,
+    channel: &str
+) -> Result<Establishment, String> {
     let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
     let query = EstablishQuery{
         response_encoding: features.response_encoding,
@@ -33,12 +46,21 @@ pub fn establish(flags: &Flags, features: Features) -> Result<Establishment, Str
         name_encoding: features.name_encoding,
         query_window: flags.query_window,
         response_window: flags.response_window,
+        ack_encoding: flags.ack_encoding.name().to_owned(),
         proof: password_proof(&flags.password, epoch),
-        port: flags.remote_port,
-        host: flags.remote_host.clone()
+        nonce: thread_rng().gen(),
+        user: flags.user.clone(),
+        channel: channel.to_owned(),
+        port: if channel == "udp" { flags.remote_port } else { 0 },
+        host: if channel == "udp" {
+            flags.remote_host.clone()
+        } else {
+            // Streams and IP packets name their own destinations.
+            "localhost".parse().unwrap()
+        }
     };
     let message = Message::new_query(Question{
-        domain: query.to_domain(&flags.host)?,
+        domain: query.to_domain(&flags.host).map_err(|e| format!("encode query: {}", e))?,
         record_type: features.record_type,
         record_class: RecordClass::IN
     });
@@ -46,31 +68,38 @@ pub fn establish(flags: &Flags, features: Features) -> Result<Establishment, Str
     conn.set_read_timeout(Some(Duration::new(5, 0))).map_err(|e| format!("{}", e))?;
     let response = query_with_retries(&conn, &message, 5)
         .ok_or("no establishment response".to_owned())?;
-    if response.answers.len() != 1 {
+    if response.header.response_code != ResponseCode::NoError {
+        return Err(format!("error response: {:?}", response.header.response_code));
+    } else if response.answers.len() != 1 {
         return Err("invalid response message".to_owned());
     }
-    let raw_data = features.record_code.decode_body(&response.answers[0].body)?;
-    match dns_decode(raw_data)? {
-        EstablishResponse::Success{id, seq} => {
-            Ok(Establishment{
-                name_code: features.name_code,
-                record_code: features.record_code,
-                record_type: features.record_type,
-                session_id: id,
-                seq_start: seq,
-                query_mtu: features.query_mtu,
-                response_mtu: features.response_mtu,
-                query_window: flags.query_window,
-                response_window: flags.response_window
-            })
-        },
+    let raw_data = features.record_code.decode_body(&response.answers[0].body)
+        .map_err(|e| format!("decode response: {}", e))?;
+    let (id, seq, lease) = match dns_decode(raw_data)
+        .map_err(|e| format!("decode response: {}", e))?
+    {
+        EstablishResponse::Success{id, seq} => (id, seq, None),
+        EstablishResponse::IpSuccess{id, seq, lease} => (id, seq, Some(lease)),
         EstablishResponse::Failure(msg) => {
-            Err(format!("error from server: {}", msg))
+            return Err(format!("error from server: {}", msg));
         },
         EstablishResponse::Unknown(x) => {
-            Err(format!("unknown establishment response type: {}", x))
+            return Err(format!("unknown establishment response type: {}", x));
         }
-    }
+    };
+    Ok(Establishment{
+        name_code: features.name_code,
+        record_code: features.record_code,
+        record_type: features.record_type,
+        session_id: id,
+        seq_start: seq,
+        query_mtu: features.query_mtu,
+        response_mtu: features.response_mtu,
+        query_window: flags.query_window,
+        response_window: flags.response_window,
+        ack_encoding: flags.ack_encoding,
+        ip_lease: lease
+    })
 }
 
 fn query_with_retries(conn: &UdpSocket, msg: &Message, tries: usize) -> Option<Message> {
@@ -83,10 +112,11 @@ fn query_with_retries(conn: &UdpSocket, msg: &Message, tries: usize) -> Option<M
 }
 
 fn attempt_query(conn: &UdpSocket, msg: &Message) -> Result<Message, String> {
-    conn.send(&dns_encode(msg)?).map_err(|e| format!("{}", e))?;
+    let data = dns_encode(msg).map_err(|e| format!("{}", e))?;
+    conn.send(&data).map_err(|e| format!("{}", e))?;
     let mut res_data = [0u8; 2048];
     let size = conn.recv(&mut res_data).map_err(|e| format!("{}", e))?;
-    let res = dns_decode::<Message>(res_data[..size].to_vec())?;
+    let res = dns_decode::<Message>(res_data[..size].to_vec()).map_err(|e| format!("{}", e))?;
     if res.header.identifier == msg.header.identifier {
         Ok(res)
     } else {
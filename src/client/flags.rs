@@ -3,7 +3,11 @@ use std::time::Duration;
 
 use clap::{App, Arg};
 
+use myodine::config::Config;
 use myodine::dns_proto::Domain;
+use myodine::myo_proto::xfer::AckEncoding;
+
+use options::Options;
 
 #[derive(Clone)]
 pub struct Flags {
@@ -12,16 +16,41 @@ pub struct Flags {
     pub concurrency: usize,
     pub query_window: u16,
     pub response_window: u16,
+    pub ack_encoding: AckEncoding,
     pub password: String,
+    pub user: Option<String>,
     pub remote_host: Domain,
     pub remote_port: u16,
     pub listen_port: u16,
+    pub socks: bool,
+    pub http: bool,
+    pub stdio: bool,
+    pub tun: Option<String>,
+    pub udp: bool,
+    pub local_forwards: Vec<Forward>,
+    pub remote_forwards: Vec<Forward>,
     pub query_min_time: Duration,
     pub query_max_time: Duration,
+    pub retransmit_time: Duration,
+    pub coalesce_time: Duration,
+    pub idle_timeout: Duration,
+    pub rediscover_time: Duration,
     pub query_mtu: Option<u16>,
     pub response_mtu: Option<u16>
 }
 
+/// A port to listen on, and the host and port to connect its connections to.
+///
+/// For local forwards, the client listens and the server connects. For
+/// remote forwards, it is the other way around.
+#[derive(Clone)]
+pub struct Forward {
+    pub listen_host: String,
+    pub listen_port: u16,
+    pub host: String,
+    pub port: u16
+}
+
 impl Flags {
     pub fn parse() -> Result<Flags, String> {
         let matches = App::new("myodine-client")
@@ -43,6 +72,11 @@ impl Flags {
                 .value_name("NUM")
                 .help("Set the window size for incoming data")
                 .takes_value(true))
+            .arg(Arg::with_name("ack-encoding")
+                .long("ack-encoding")
+                .value_name("NAME")
+                .help("Set the acknowledgement encoding (mask or sack)")
+                .takes_value(true))
             .arg(Arg::with_name("remote-host")
                 .short("r")
                 .long("remote-host")
@@ -61,12 +95,68 @@ impl Flags {
                 .value_name("PORT")
                 .help("Set the local port to listen on")
                 .takes_value(true))
+            .arg(Arg::with_name("socks")
+                .long("socks")
+                .help("Act as a SOCKS5 proxy instead of forwarding to a single remote"))
+            .arg(Arg::with_name("http")
+                .long("http")
+                .conflicts_with("socks")
+                .help("Act as an HTTP CONNECT proxy instead of forwarding to a single remote"))
+            .arg(Arg::with_name("stdio")
+                .long("stdio")
+                .conflicts_with_all(&["socks", "http", "listen-port"])
+                .help("Forward standard input and output instead of listening on a port"))
+            .arg(Arg::with_name("tun")
+                .long("tun")
+                .value_name("NAME")
+                .conflicts_with_all(&["socks", "http", "stdio", "listen-port"])
+                .help("Tunnel IP packets through a tun device with the given name")
+                .takes_value(true))
+            .arg(Arg::with_name("udp")
+                .long("udp")
+                .conflicts_with_all(&["socks", "http", "stdio", "tun"])
+                .help("Forward UDP datagrams from the local port, without retransmission"))
+            .arg(Arg::with_name("local-forward")
+                .short("L")
+                .long("local-forward")
+                .value_name("[ADDR:]PORT:HOST:PORT")
+                .conflicts_with_all(&["stdio", "tun", "udp"])
+                .help("Listen on a local port and forward connections to a remote host")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(Arg::with_name("remote-forward")
+                .short("R")
+                .long("remote-forward")
+                .value_name("[ADDR:]PORT:HOST:PORT")
+                .conflicts_with_all(&["stdio", "tun", "udp"])
+                .help("Have the server listen on a port and forward connections to a local host")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
             .arg(Arg::with_name("password")
                 .short("p")
                 .long("password")
                 .value_name("VALUE")
                 .help("Set the server password")
                 .takes_value(true))
+            .arg(Arg::with_name("user")
+                .short("u")
+                .long("user")
+                .value_name("NAME")
+                .help("Authenticate as a user, with the password as their secret")
+                .takes_value(true))
+            .arg(Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Read default flag values from a config file")
+                .takes_value(true))
+            .arg(Arg::with_name("password-file")
+                .long("password-file")
+                .value_name("FILE")
+                .conflicts_with("password")
+                .help("Read the server password from a file")
+                .takes_value(true))
             .arg(Arg::with_name("query-max-time")
                 .long("query-max-time")
                 .value_name("INT")
@@ -77,6 +167,26 @@ impl Flags {
                 .value_name("INT")
                 .help("Set the minimum query delay in milliseconds")
                 .takes_value(true))
+            .arg(Arg::with_name("retransmit-time")
+                .long("retransmit-time")
+                .value_name("INT")
+                .help("Set the chunk retransmission timeout in milliseconds")
+                .takes_value(true))
+            .arg(Arg::with_name("coalesce-time")
+                .long("coalesce-time")
+                .value_name("INT")
+                .help("Set the time to wait for small writes to coalesce, in milliseconds")
+                .takes_value(true))
+            .arg(Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .value_name("INT")
+                .help("Set how long to keep a session with no connections, in seconds")
+                .takes_value(true))
+            .arg(Arg::with_name("rediscover-time")
+                .long("rediscover-time")
+                .value_name("INT")
+                .help("Set how long to reuse discovered features, in seconds")
+                .takes_value(true))
             .arg(Arg::with_name("query-mtu")
                 .long("query-mtu")
                 .value_name("INT")
@@ -89,13 +199,19 @@ impl Flags {
                 .takes_value(true))
             .arg(Arg::with_name("addr")
                 .help("Set the address of the proxy")
-                .required(true)
                 .index(1))
             .arg(Arg::with_name("host")
                 .help("Set the root domain name of the proxy")
-                .required(true)
                 .index(2))
+            .after_help("The password may also be set with the MYODINE_PASSWORD environment \
+                variable.\nA config file has one `name = value` line per flag, using long \
+                flag names.")
             .get_matches();
+        let config = match matches.value_of("config") {
+            Some(path) => Config::load(path).map_err(|e| format!("{}: {}", path, e))?,
+            None => Config::new()
+        };
+        let matches = Options::new(matches, config);
 
         macro_rules! parse_arg {
             ( $name:expr, $default:expr ) => {
@@ -104,29 +220,106 @@ impl Flags {
             }
         }
 
+        let ack_name = matches.value_of("ack-encoding").unwrap_or("mask");
+        let ack_encoding = AckEncoding::from_name(ack_name)
+            .ok_or(format!("bad ack-encoding argument: {}", ack_name))?;
         let min_time: u64 = parse_arg!("query-min-time", "50")?;
         let max_time: u64 = parse_arg!("query-max-time", "5000")?;
-        Ok(Flags{
-            addr: matches.value_of("addr").unwrap_or("localhost:53").to_owned(),
-            host: parse_arg!("host", "")?,
+        let retransmit_time: u64 = parse_arg!("retransmit-time", "1000")?;
+        let coalesce_time: u64 = parse_arg!("coalesce-time", "0")?;
+        let remote_host: Domain = parse_arg!("remote-host", "127.0.0.1")?;
+        let remote_port = parse_arg!("remote-port", "22")?;
+        let listen_port = parse_arg!("listen-port", "2222")?;
+        let socks = matches.is_present("socks").map_err(|e| e.to_string())?;
+        let http = matches.is_present("http").map_err(|e| e.to_string())?;
+        let mut local_forwards = parse_forwards(&matches, "local-forward")?;
+        if local_forwards.is_empty() && !socks && !http {
+            local_forwards.push(Forward{
+                listen_host: "localhost".to_owned(),
+                listen_port: listen_port,
+                host: format!("{}", remote_host),
+                port: remote_port
+            });
+        }
+        let flags = Flags{
+            addr: matches.required("addr").map_err(|e| e.to_string())?.to_owned(),
+            host: matches.required("host").map_err(|e| e.to_string())?.parse()
+                .map_err(|e| format!("bad host argument: {}", e))?,
             concurrency: parse_arg!("concurrency", "2")?,
             query_window: parse_arg!("query-window", "4")?,
             response_window: parse_arg!("response-window", "4")?,
-            password: matches.value_of("password").unwrap_or("").to_owned(),
-            remote_host: parse_arg!("remote-host", "127.0.0.1")?,
-            remote_port: parse_arg!("remote-port", "22")?,
-            listen_port: parse_arg!("listen-port", "2222")?,
+            ack_encoding: ack_encoding,
+            password: matches.password().map_err(|e| e.to_string())?,
+            user: match matches.value_of("user") {
+                Some(name) => Some(parse_user(name)?),
+                None => None
+            },
+            remote_host: remote_host,
+            remote_port: remote_port,
+            listen_port: listen_port,
+            socks: socks,
+            http: http,
+            stdio: matches.is_present("stdio").map_err(|e| e.to_string())?,
+            tun: matches.value_of("tun").map(String::from),
+            udp: matches.is_present("udp").map_err(|e| e.to_string())?,
+            local_forwards: local_forwards,
+            remote_forwards: parse_forwards(&matches, "remote-forward")?,
             query_min_time: Duration::from_millis(min_time),
             query_max_time: Duration::from_millis(max_time),
+            retransmit_time: Duration::from_millis(retransmit_time),
+            coalesce_time: Duration::from_millis(coalesce_time),
+            idle_timeout: Duration::from_secs(parse_arg!("idle-timeout", "30")?),
+            rediscover_time: Duration::from_secs(parse_arg!("rediscover-time", "600")?),
             query_mtu: parse_optional(matches.value_of("query-mtu"))?,
             response_mtu: parse_optional(matches.value_of("response-mtu"))?
+        };
+        matches.check_unused().map_err(|e| e.to_string())?;
+        Ok(flags)
+    }
+}
+
+impl FromStr for Forward {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Forward, String> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (listen_host, rest) = match parts.len() {
+            3 => ("localhost", &parts[..]),
+            4 => (parts[0], &parts[1..]),
+            _ => return Err(format!("bad forward: {}", s))
+        };
+        let parse_port = |x: &str| x.parse()
+            .map_err(|e| format!("bad forward: {}: {}", s, e));
+        Ok(Forward{
+            listen_host: listen_host.to_owned(),
+            listen_port: parse_port(rest[0])?,
+            host: rest[1].to_owned(),
+            port: parse_port(rest[2])?
         })
     }
 }
 
+fn parse_forwards(matches: &Options, name: &str) -> Result<Vec<Forward>, String> {
+    match matches.values_of(name) {
+        Some(specs) => specs.map(|x| x.parse()).collect(),
+        None => Ok(Vec::new())
+    }
+}
+
 fn parse_optional<T: FromStr>(x: Option<&str>) -> Result<Option<T>, String> {
     match x {
         Some(s) => s.parse().map_err(|_| format!("bad argument: {}", s)).map(Some),
         None => Ok(None),
     }
 }
+
+fn parse_user(name: &str) -> Result<String, String> {
+    // The name is sent as part of a domain label.
+    let name = name.to_lowercase();
+    if name.is_empty() || name.len() > 62 || name.ends_with('-') ||
+        !name.chars().all(|x| x.is_ascii_alphanumeric() || x == '-')
+    {
+        return Err(format!("bad user argument: {}", name));
+    }
+    Ok(name)
+}
@@ -0,0 +1,191 @@
+use std::io::{Read, Write};
+
+use myodine::myo_proto::mux::OpenError;
+
+/// The maximum size of a request head.
+const MAX_HEAD_SIZE: usize = 8192;
+
+/// Read an HTTP CONNECT request from a client.
+///
+/// Requests with other methods, and malformed requests, are answered with an
+/// error before returning.
+///
+/// Returns the requested host and port. Once the connection succeeds or
+/// fails, the client should be told with `send_reply()`.
+pub fn read_request<S: Read + Write>(conn: &mut S) -> Result<(String, u16), String> {
+    let head = read_head(conn)?;
+    let fields: Vec<&str> = head.lines().next().unwrap_or("").split_whitespace().collect();
+    if fields.len() != 3 || !fields[2].starts_with("HTTP/") {
+        write_response(conn, "400 Bad Request", "malformed request line")?;
+        return Err("malformed request line".to_owned());
+    }
+    if fields[0] != "CONNECT" {
+        write_response(conn, "405 Method Not Allowed", "only CONNECT is supported")?;
+        return Err(format!("unsupported method: {}", fields[0]));
+    }
+    match parse_authority(fields[1]) {
+        Some(target) => Ok(target),
+        None => {
+            write_response(conn, "400 Bad Request", "expected host:port")?;
+            Err(format!("bad authority: {}", fields[1]))
+        }
+    }
+}
+
+/// Tell a client whether its CONNECT request succeeded.
+pub fn send_reply<S: Write>(conn: &mut S, result: &Result<(), OpenError>) -> Result<(), String> {
+    match *result {
+        Ok(_) => write_bytes(conn, b"HTTP/1.1 200 Connection established\r\n\r\n"),
+        Err(ref err @ OpenError::Timeout) => {
+            write_response(conn, "504 Gateway Timeout", &err.to_string())
+        },
+        Err(ref err @ OpenError::Refused(_)) => {
+            write_response(conn, "502 Bad Gateway", &err.to_string())
+        }
+    }
+}
+
+fn read_head<S: Read + Write>(conn: &mut S) -> Result<String, String> {
+    // Read one byte at a time so that nothing past the head is consumed.
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") && !head.ends_with(b"\n\n") {
+        if head.len() >= MAX_HEAD_SIZE {
+            write_response(conn, "431 Request Header Fields Too Large", "request is too large")?;
+            return Err("request head is too large".to_owned());
+        }
+        let mut byte = [0u8];
+        conn.read_exact(&mut byte).map_err(|e| format!("HTTP read error: {}", e))?;
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).map_err(|_| "request is not valid UTF-8".to_owned())
+}
+
+fn parse_authority(authority: &str) -> Option<(String, u16)> {
+    let split = authority.rfind(':')?;
+    let port = authority[(split + 1)..].parse().ok()?;
+    let host = &authority[0..split];
+    let host = if host.starts_with('[') && host.ends_with(']') {
+        &host[1..(host.len() - 1)]
+    } else {
+        host
+    };
+    if host.is_empty() {
+        None
+    } else {
+        Some((host.to_owned(), port))
+    }
+}
+
+fn write_response<S: Write>(conn: &mut S, status: &str, body: &str) -> Result<(), String> {
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+        Connection: close\r\n\r\n{}", status, body.len() + 1, body);
+    write_bytes(conn, format!("{}\n", response).as_bytes())
+}
+
+fn write_bytes<S: Write>(conn: &mut S, data: &[u8]) -> Result<(), String> {
+    conn.write_all(data).map_err(|e| format!("HTTP write error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::io::Cursor;
+
+    #[test]
+    fn authorities() {
+        assert_eq!(parse_authority("example.com:443"), Some(("example.com".to_owned(), 443)));
+        assert_eq!(parse_authority("[2001:db8::1]:8080"),
+            Some(("2001:db8::1".to_owned(), 8080)));
+        assert_eq!(parse_authority("192.0.2.1:22"), Some(("192.0.2.1".to_owned(), 22)));
+        assert_eq!(parse_authority("example.com"), None);
+        assert_eq!(parse_authority("example.com:"), None);
+        assert_eq!(parse_authority("example.com:99999"), None);
+        assert_eq!(parse_authority(":443"), None);
+        assert_eq!(parse_authority("[]:443"), None);
+    }
+
+    #[test]
+    fn connect_requests() {
+        let mut conn = MockConn::new(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n\
+            \r\nextra");
+        assert_eq!(read_request(&mut conn), Ok(("example.com".to_owned(), 443)));
+        assert_eq!(conn.output.len(), 0);
+        let mut rest = Vec::new();
+        conn.input.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"extra");
+
+        let mut conn = MockConn::new(b"CONNECT [::1]:22 HTTP/1.0\n\n");
+        assert_eq!(read_request(&mut conn), Ok(("::1".to_owned(), 22)));
+    }
+
+    #[test]
+    fn rejected_requests() {
+        let cases: Vec<(&[u8], &str, &str)> = vec![
+            (b"CONNECT\r\n\r\n", "malformed request line", "400 Bad Request"),
+            (b"CONNECT example.com:443 SPDY\r\n\r\n", "malformed request line",
+                "400 Bad Request"),
+            (b"GET http://example.com/ HTTP/1.1\r\n\r\n", "unsupported method: GET",
+                "405 Method Not Allowed"),
+            (b"CONNECT example.com HTTP/1.1\r\n\r\n", "bad authority: example.com",
+                "400 Bad Request")
+        ];
+        for (request, error, status) in cases {
+            let mut conn = MockConn::new(request);
+            assert_eq!(read_request(&mut conn), Err(error.to_owned()));
+            assert!(conn.response().starts_with(&format!("HTTP/1.1 {}\r\n", status)), "{}",
+                error);
+        }
+
+        let mut conn = MockConn::new(&[b'x'; MAX_HEAD_SIZE + 100]);
+        assert_eq!(read_request(&mut conn), Err("request head is too large".to_owned()));
+        assert!(conn.response().starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        assert_eq!(conn.input.position(), MAX_HEAD_SIZE as u64);
+    }
+
+    #[test]
+    fn replies() {
+        let cases = vec![
+            (Ok(()), "HTTP/1.1 200 Connection established\r\n\r\n"),
+            (Err(OpenError::Timeout), "HTTP/1.1 504 Gateway Timeout\r\n"),
+            (Err(OpenError::Refused("connect error".to_owned())), "HTTP/1.1 502 Bad Gateway\r\n")
+        ];
+        for (result, start) in cases {
+            let mut conn = MockConn::new(b"");
+            send_reply(&mut conn, &result).unwrap();
+            assert!(conn.response().starts_with(start), "{:?}", result);
+        }
+    }
+
+    /// A connection with canned input, which records its output.
+    struct MockConn {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>
+    }
+
+    impl MockConn {
+        fn new(input: &[u8]) -> MockConn {
+            MockConn{input: Cursor::new(input.to_vec()), output: Vec::new()}
+        }
+
+        fn response(&self) -> String {
+            String::from_utf8(self.output.clone()).unwrap()
+        }
+    }
+
+    impl Read for MockConn {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockConn {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -11,11 +11,17 @@ pub struct RawLogger {
 }
 
 impl RawLogger {
-    pub fn new() -> RawLogger {
+    /// Create a logger which writes to standard output, or to standard error
+    /// if standard output is being used for something else.
+    pub fn new(use_stderr: bool) -> RawLogger {
         let (sender, receiver) = channel();
-        spawn(|| {
+        spawn(move || {
             for msg in receiver {
-                println!("{} {}", Local::now().to_rfc3339(), msg);
+                if use_stderr {
+                    eprintln!("{} {}", Local::now().to_rfc3339(), msg);
+                } else {
+                    println!("{} {}", Local::now().to_rfc3339(), msg);
+                }
             }
         });
         RawLogger{sender: sender}
@@ -1,22 +1,36 @@
 extern crate chrono;
 extern crate clap;
 extern crate myodine;
+extern crate rand;
 
 mod flags;
+mod http;
 mod logger;
 mod discovery;
 mod establish;
 mod session;
+mod socks;
+#[path = "../options.rs"]
+mod options;
 
-use std::net::{TcpListener, TcpStream};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::process::exit;
-use std::thread::spawn;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread::{sleep, spawn};
+use std::time::Duration;
 
-use flags::Flags;
+use myodine::conn::DatagramSocket;
+#[cfg(target_os = "linux")]
+use myodine::conn::TunDevice;
+#[cfg(target_os = "linux")]
+use myodine::myo_proto::ip::PacketChunker;
+use myodine::myo_proto::mux::OpenError;
+
+use flags::{Flags, Forward};
 use logger::RawLogger;
-use discovery::discover_features;
-use establish::establish;
-use session::run_session;
+use discovery::FeatureCache;
+use establish::{Establishment, establish};
+use session::{LocalConn, StreamRequest, run_datagram_session, run_ip_session, run_session};
 
 fn main() {
     if let Err(msg) = main_or_err() {
@@ -27,33 +41,231 @@ fn main() {
 
 fn main_or_err() -> Result<(), String> {
     let flags = Flags::parse()?;
+    let features = FeatureCache::new(flags.rediscover_time);
+    if flags.stdio {
+        return run_stdio(flags, &features);
+    } else if let Some(name) = flags.tun.clone() {
+        return run_tun(flags, &features, &name);
+    } else if flags.udp {
+        return run_udp(flags, &features);
+    }
+
+    let logger = RawLogger::new(false);
+    let (requests, receiver) = channel();
 
-    let listener = TcpListener::bind(&format!("localhost:{}", flags.listen_port)).
-        map_err(|e| format!("listen error: {}", e))?;
+    // Every accept loop runs until it fails, which ends the process.
+    let (errors, first_error) = channel();
+    if flags.socks || flags.http {
+        let listener = listen("localhost", flags.listen_port)?;
+        let protocol = if flags.socks {
+            Protocol{read_request: socks::read_request, send_reply: socks::send_reply}
+        } else {
+            Protocol{read_request: http::read_request, send_reply: http::send_reply}
+        };
+        let (errors, requests, logger) = (errors.clone(), requests.clone(), logger.clone());
+        spawn(move || {
+            errors.send(accept_proxies(listener, protocol, requests, logger)).ok();
+        });
+    }
+    for forward in flags.local_forwards.clone() {
+        let listener = listen(&forward.listen_host, forward.listen_port)?;
+        logger.log(format!("forwarding {}:{} to {}:{}", forward.listen_host, forward.listen_port,
+            forward.host, forward.port));
+        let (errors, requests, logger) = (errors.clone(), requests.clone(), logger.clone());
+        spawn(move || {
+            errors.send(accept_forwards(listener, forward, requests, logger)).ok();
+        });
+    }
 
-    let logger = RawLogger::new();
     logger.log("listening for connections...".to_owned());
+    spawn(move || {
+        run_sessions(flags, features, receiver, logger);
+    });
+    first_error.recv().unwrap()
+}
+
+fn listen(host: &str, port: u16) -> Result<TcpListener, String> {
+    TcpListener::bind((host, port)).map_err(|e| format!("listen on {}:{}: {}", host, port, e))
+}
+
+/// Pass every connection to a local forward's listener to the session.
+fn accept_forwards(
+    listener: TcpListener,
+    forward: Forward,
+    requests: Sender<StreamRequest>,
+    logger: RawLogger
+) -> Result<(), String> {
+    loop {
+        let (conn, addr) = listener.accept().map_err(|e| format!("accept error: {}", e))?;
+        logger.log(format!("new connection from {}", addr));
+        requests.send(StreamRequest{
+            conn: LocalConn::Tcp(conn),
+            host: forward.host.clone(),
+            port: forward.port,
+            on_open: None
+        }).map_err(|_| "session manager has exited".to_owned())?;
+    }
+}
+
+/// Handle the proxy handshake for every connection to the listener.
+fn accept_proxies(
+    listener: TcpListener,
+    protocol: Protocol,
+    requests: Sender<StreamRequest>,
+    logger: RawLogger
+) -> Result<(), String> {
     loop {
         let (conn, addr) = listener.accept().map_err(|e| format!("accept error: {}", e))?;
         logger.log(format!("new connection from {}", addr));
-        let local_flags = flags.clone();
+        let local_requests = requests.clone();
         let local_logger = logger.clone();
         spawn(move || {
-            if let Err(msg) = handle_connection(local_flags, conn, &local_logger) {
-                local_logger.log(format!("error for {}: {}", addr, msg));
-            } else {
-                local_logger.log(format!("session ended for {}", addr));
+            let res = handle_proxy(conn, addr, protocol, &local_requests, &local_logger);
+            if let Err(msg) = res {
+                local_logger.log(format!("proxy error for {}: {}", addr, msg));
             }
         });
     }
 }
 
-fn handle_connection(flags: Flags, conn: TcpStream, logger: &RawLogger) -> Result<(), String> {
-    logger.log(format!("discovering features @{} for {}...", flags.host, flags.addr));
-    let features = discover_features(&flags)
-        .map_err(|e| format!("failed to discover features: {}", e))?;
+/// Tunnel standard input and output to the remote host, for use as an SSH
+/// ProxyCommand.
+fn run_stdio(mut flags: Flags, features: &FeatureCache) -> Result<(), String> {
+    // Exit as soon as the stream is done.
+    flags.idle_timeout = Duration::from_secs(0);
+    let logger = RawLogger::new(true);
+    let establishment = start_session(&flags, features, "mux", &logger)?;
+    let request = StreamRequest{
+        conn: LocalConn::Stdio,
+        host: format!("{}", flags.remote_host),
+        port: flags.remote_port,
+        on_open: None
+    };
+    // No other streams are opened, but the sender must stay alive.
+    let (_requests, receiver) = channel();
+    run_session(flags.clone(), establishment, Some(request), &receiver, &logger)
+}
+
+/// Forward datagrams from a local UDP port to the remote host.
+fn run_udp(flags: Flags, features: &FeatureCache) -> Result<(), String> {
+    let socket = DatagramSocket::listen(&format!("localhost:{}", flags.listen_port))
+        .map_err(|e| format!("listen error: {}", e))?;
+    let logger = RawLogger::new(false);
+    let establishment = start_session(&flags, features, "udp", &logger)?;
+    logger.log(format!("forwarding datagrams to {}:{}", flags.remote_host, flags.remote_port));
+    run_datagram_session(flags, establishment, socket, &logger)
+}
+
+/// Tunnel IP packets between a tun device and the server.
+#[cfg(target_os = "linux")]
+fn run_tun(flags: Flags, features: &FeatureCache, name: &str) -> Result<(), String> {
+    let logger = RawLogger::new(false);
+    let device = TunDevice::open(name).map_err(|e| format!("open tun device: {}", e))?;
+    let establishment = start_session(&flags, features, "ip", &logger)?;
+    let lease = establishment.ip_lease.clone()
+        .ok_or("server did not assign an IP address".to_owned())?;
+    device.configure(lease.addr, lease.prefix_len)
+        .map_err(|e| format!("configure {}: {}", device.name(), e))?;
+    logger.log(format!("assigned {}/{} on {} (gateway {})", lease.addr, lease.prefix_len,
+        device.name(), lease.gateway));
+    let conn = PacketChunker::from_device(device, establishment.query_mtu as usize)
+        .map_err(|e| format!("tun device error: {}", e))?;
+    run_ip_session(flags, establishment, conn, &logger)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn run_tun(_: Flags, _: &FeatureCache, _: &str) -> Result<(), String> {
+    Err("tun devices are only supported on Linux".to_owned())
+}
+
+/// The handshake for a proxy protocol.
+#[derive(Clone, Copy)]
+struct Protocol {
+    read_request: fn(&mut TcpStream) -> Result<(String, u16), String>,
+    send_reply: fn(&mut TcpStream, &Result<(), OpenError>) -> Result<(), String>
+}
+
+fn handle_proxy(
+    mut conn: TcpStream,
+    addr: SocketAddr,
+    protocol: Protocol,
+    requests: &Sender<StreamRequest>,
+    logger: &RawLogger
+) -> Result<(), String> {
+    let (host, port) = (protocol.read_request)(&mut conn)?;
+    let mut reply_conn = conn.try_clone().map_err(|e| format!("clone socket: {}", e))?;
+    let local_logger = logger.clone();
+    requests.send(StreamRequest{
+        conn: LocalConn::Tcp(conn),
+        host: host,
+        port: port,
+        on_open: Some(Box::new(move |result| {
+            if let Err(ref msg) = result {
+                local_logger.log(format!("stream for {} failed: {}", addr, msg));
+            }
+            (protocol.send_reply)(&mut reply_conn, &result).ok();
+        }))
+    }).map_err(|_| "session manager has exited".to_owned())
+}
+
+/// Establish sessions as they are needed by incoming connections.
+///
+/// All of the connections share one session, which is closed after it has
+/// been idle for a while and re-established for the next connection. With
+/// remote forwards, there is always a session so that the server can pass
+/// connections back.
+///
+/// Features are discovered up front, so that the first connection does not
+/// have to wait for discovery, and again whenever a session fails.
+fn run_sessions(
+    flags: Flags,
+    features: FeatureCache,
+    requests: Receiver<StreamRequest>,
+    logger: RawLogger
+) {
+    let persistent = !flags.remote_forwards.is_empty();
+    if let Err(msg) = features.get(&flags, &logger) {
+        logger.log(msg);
+    }
+    loop {
+        let first = if persistent {
+            None
+        } else if let Ok(request) = requests.recv() {
+            Some(request)
+        } else {
+            return;
+        };
+        let result = match start_session(&flags, &features, "mux", &logger) {
+            Ok(establishment) => {
+                logger.log("running session...".to_owned());
+                run_session(flags.clone(), establishment, first, &requests, &logger)
+            },
+            Err(msg) => {
+                if let Some(on_open) = first.and_then(|x| x.on_open) {
+                    on_open(Err(OpenError::Refused(msg.clone())));
+                }
+                Err(msg)
+            }
+        };
+        if let Err(msg) = result {
+            logger.log(format!("session error: {}", msg));
+            features.invalidate(&flags);
+            if persistent {
+                sleep(Duration::from_secs(5));
+            }
+        } else {
+            logger.log("session ended".to_owned());
+        }
+    }
+}
+
+fn start_session(
+    flags: &Flags,
+    features: &FeatureCache,
+    channel: &str,
+    logger: &RawLogger
+) -> Result<Establishment, String> {
+    let features = features.get(flags, logger)?;
     logger.log("establishing session...".to_owned());
-    let establishment = establish(&flags, features)?;
-    logger.log("running session...".to_owned());
-    run_session(flags, conn, establishment, logger)
+    establish(flags, features, channel)
 }
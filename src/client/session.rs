@@ -1,58 +1,214 @@
+use std::io::{stdin, stdout};
 use std::net::TcpStream;
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::{Duration, Instant};
 
-use myodine::conn::{Highway, Event, TcpChunker, UDPHighway};
-use myodine::dns_proto::{Domain, Message, Question, RecordClass};
+use myodine::conn::{Chunker, DatagramSocket, Highway, Event, IoPair, UDPHighway};
+use myodine::dns_proto::{Domain, Message, Question, RecordClass, ResponseCode};
+use myodine::myo_proto::datagram::{DatagramPacket, DatagramState};
+use myodine::myo_proto::ip::PacketChunker;
+use myodine::myo_proto::mux::{Mux, OpenCallback, Side};
 use myodine::myo_proto::xfer::{Packet, WwrState, handle_packet_in, next_packet_out};
 
 use flags::Flags;
 use establish::Establishment;
 use logger::{RawLogger, SessionLogger};
 
+/// A local connection to carry as a stream in the session.
+pub struct StreamRequest {
+    pub conn: LocalConn,
+    pub host: String,
+    pub port: u16,
+    pub on_open: Option<OpenCallback>
+}
+
+/// The local end of a stream.
+pub enum LocalConn {
+    Tcp(TcpStream),
+    Stdio
+}
+
+/// Run a multiplexed session until it has had no streams or remote forwards
+/// for the idle timeout, and everything it sent has been acknowledged.
+///
+/// # Arguments
+///
+/// * `flags` - The configuration flags.
+/// * `info` - The established session.
+/// * `first` - The connection that caused the session to be established, if
+///   any.
+/// * `requests` - New connections to add to the session as it runs.
+/// * `logger` - The destination for log messages.
 pub fn run_session(
     flags: Flags,
-    conn: TcpStream,
     info: Establishment,
+    first: Option<StreamRequest>,
+    requests: &Receiver<StreamRequest>,
+    logger: &RawLogger
+) -> Result<(), String> {
+    // Connections from remote forwards get the same timeout as the server
+    // uses for its own connections.
+    let mut mux = Mux::new(Side::Client, info.query_mtu as usize, flags.coalesce_time,
+        Duration::from_secs(5));
+    for forward in &flags.remote_forwards {
+        let desc = format!("{}:{} to {}:{}", forward.listen_host, forward.listen_port,
+            forward.host, forward.port);
+        let local_logger = logger.clone();
+        let result = mux.listen(&forward.listen_host, forward.listen_port, &forward.host,
+            forward.port, Some(Box::new(move |result| {
+                match result {
+                    Ok(()) => local_logger.log(format!("forwarding {}", desc)),
+                    Err(msg) => local_logger.log(format!("cannot forward {}: {}", desc, msg))
+                }
+            })));
+        if let Err(err) = result {
+            logger.log(format!("error requesting remote forward: {}", err));
+        }
+    }
+    let transport = Transport::stream(&flags, &info, Channel::Mux(mux));
+    let mut session = Session::new(flags, info, transport, logger);
+    if let Some(request) = first {
+        session.open_stream(request);
+    }
+    session.run(Some(requests))
+}
+
+/// Run an IP tunnel session until it fails.
+///
+/// # Arguments
+///
+/// * `flags` - The configuration flags.
+/// * `info` - The established session.
+/// * `conn` - The local end of the tunnel.
+/// * `logger` - The destination for log messages.
+pub fn run_ip_session(
+    flags: Flags,
+    info: Establishment,
+    conn: PacketChunker,
     logger: &RawLogger
 ) -> Result<(), String> {
-    let (highway, events) = UDPHighway::open(&flags.addr, flags.concurrency,
-        flags.query_min_time, flags.query_max_time);
-    let conn = TcpChunker::new(
-        conn,
-        info.query_mtu as usize,
-        info.query_window as usize,
-        info.response_window as usize
-    ).map_err(|e| format!("error creating chunker: {}", e))?;
-    let mut session = Session{
-        highway: Box::new(highway),
-        state: WwrState::new(info.response_window, info.query_window, info.seq_start),
-        conn: conn,
-        info: info,
-        host: flags.host,
-        logger: SessionLogger::new(logger.clone())
+    let transport = Transport::stream(&flags, &info, Channel::Packets(conn));
+    Session::new(flags, info, transport, logger).run(None)
+}
+
+/// Run a datagram session until it fails.
+///
+/// # Arguments
+///
+/// * `flags` - The configuration flags.
+/// * `info` - The established session.
+/// * `socket` - The local socket to relay datagrams for.
+/// * `logger` - The destination for log messages.
+pub fn run_datagram_session(
+    flags: Flags,
+    info: Establishment,
+    socket: DatagramSocket,
+    logger: &RawLogger
+) -> Result<(), String> {
+    let transport = Transport::Datagram{
+        state: DatagramState::new(info.query_mtu as usize),
+        socket: socket
     };
-    session.run(events)
+    Session::new(flags, info, transport, logger).run(None)
+}
+
+/// The way that a session carries data.
+enum Transport {
+    /// A reliable byte stream.
+    Stream{state: WwrState, channel: Channel},
+
+    /// Best-effort datagrams.
+    Datagram{state: DatagramState, socket: DatagramSocket}
+}
+
+impl Transport {
+    fn stream(flags: &Flags, info: &Establishment, channel: Channel) -> Transport {
+        let mut state = WwrState::new(info.response_window, info.query_window, info.seq_start);
+        state.set_retransmit_timeout(flags.retransmit_time);
+        Transport::Stream{state: state, channel: channel}
+    }
+
+    fn is_done(&self) -> bool {
+        match *self {
+            Transport::Stream{ref state, ..} => state.is_done(),
+            Transport::Datagram{..} => false
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        match *self {
+            Transport::Stream{ref state, ref channel} => channel.is_idle() && state.all_acked(),
+            Transport::Datagram{..} => false
+        }
+    }
+}
+
+/// The data carried by a stream transport.
+enum Channel {
+    Mux(Mux),
+    Packets(PacketChunker)
+}
+
+impl Channel {
+    fn chunker(&mut self) -> &mut dyn Chunker {
+        match *self {
+            Channel::Mux(ref mut mux) => mux,
+            Channel::Packets(ref mut conn) => conn
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        match *self {
+            Channel::Mux(ref mux) => mux.num_streams() == 0 && mux.num_listeners() == 0,
+            Channel::Packets(_) => false
+        }
+    }
 }
 
 struct Session {
     highway: Box<Highway>,
-    state: WwrState,
-    conn: TcpChunker,
+    events: Receiver<Event>,
+    transport: Transport,
     info: Establishment,
     host: Domain,
-    logger: SessionLogger
+    idle_timeout: Duration,
+    logger: SessionLogger,
+    raw_logger: RawLogger
 }
 
 impl Session {
-    pub fn run(&mut self, events: Receiver<Event>) -> Result<(), String> {
+    fn new(flags: Flags, info: Establishment, transport: Transport, logger: &RawLogger) -> Session {
+        let (highway, events) = UDPHighway::open(&flags.addr, flags.concurrency,
+            flags.query_min_time, flags.query_max_time);
+        Session{
+            highway: Box::new(highway),
+            events: events,
+            transport: transport,
+            info: info,
+            host: flags.host,
+            idle_timeout: flags.idle_timeout,
+            logger: SessionLogger::new(logger.clone()),
+            raw_logger: logger.clone()
+        }
+    }
+
+    fn run(&mut self, requests: Option<&Receiver<StreamRequest>>) -> Result<(), String> {
         for lane in 0..self.highway.num_lanes() {
             self.populate_lane(lane)?;
         }
-        for event in events {
+        let mut idle_start = None;
+        while let Ok(event) = self.events.recv() {
+            while let Some(requests) = requests {
+                match requests.try_recv() {
+                    Ok(request) => self.open_stream(request),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(())
+                }
+            }
             match event {
                 Event::Response(lane, msg) => {
                     self.logger.log_response();
-                    self.handle_message(msg);
+                    self.handle_message(msg)?;
                     self.populate_lane(lane)?;
                 },
                 Event::Timeout(lane) => {
@@ -69,37 +225,112 @@ impl Session {
                     return Err(format!("lane {}: error on socket: {}", lane, err));
                 }
             }
-            if self.state.is_done() {
+            if self.transport.is_done() {
+                break;
+            }
+            if !self.transport.is_idle() {
+                idle_start = None;
+            } else if idle_start.get_or_insert_with(Instant::now).elapsed() > self.idle_timeout {
                 break;
             }
         }
         Ok(())
     }
 
-    fn handle_message(&mut self, msg: Message) {
+    fn open_stream(&mut self, request: StreamRequest) {
+        let mux = match self.transport {
+            Transport::Stream{channel: Channel::Mux(ref mut mux), ..} => mux,
+            _ => panic!("streams require a multiplexed session")
+        };
+        let dest = format!("{}:{}", request.host, request.port);
+        let result = match request.conn {
+            LocalConn::Tcp(conn) => mux.open(conn, &request.host, request.port, request.on_open),
+            LocalConn::Stdio => {
+                let stdio = IoPair{reader: stdin(), writer: stdout()};
+                mux.open(stdio, &request.host, request.port, request.on_open)
+            }
+        };
+        match result {
+            Ok(id) => self.raw_logger.log(format!("opened stream {} to {}", id, dest)),
+            Err(err) => self.raw_logger.log(format!("error opening stream to {}: {}", dest, err))
+        }
+    }
+
+    fn handle_message(&mut self, msg: Message) -> Result<(), String> {
+        match msg.header.response_code {
+            ResponseCode::NoError => (),
+            ResponseCode::NXDomain => {
+                return Err("session no longer exists on the server".to_owned());
+            },
+            ResponseCode::Refused => {
+                return Err(format!("server refused queries for {}", self.host));
+            },
+            ResponseCode::FormatError => {
+                // The packet will be retransmitted if it mattered.
+                self.logger.log_raw("server rejected a malformed packet".to_owned());
+                return Ok(());
+            },
+            code => {
+                // Usually a resolver failure, which we treat like a timeout.
+                self.logger.log_raw(format!("error response: {:?}", code));
+                return Ok(());
+            }
+        }
         if msg.answers.len() != 1 || msg.header.truncated {
             self.logger.log_raw(format!("invalid response (truncated={}, answers={})",
                 msg.header.truncated, msg.answers.len()));
-            return;
+            return Ok(());
         }
         if let Ok(raw_body) = self.info.record_code.decode_body(&msg.answers[0].body) {
-            if let Ok(packet) = Packet::decode_response(&raw_body, self.info.query_window) {
-                self.handle_packet(packet);
-            }
+            self.handle_body(&raw_body);
         }
+        Ok(())
     }
 
-    fn handle_packet(&mut self, packet: Packet) {
-        self.logger.log_inbound(handle_packet_in(packet, &mut self.state, &mut self.conn));
+    fn handle_body(&mut self, body: &[u8]) {
+        match self.transport {
+            Transport::Stream{ref mut state, ref mut channel} => {
+                let packet_res = Packet::decode_response(body, self.info.query_window,
+                    self.info.ack_encoding);
+                if let Ok(packet) = packet_res {
+                    match handle_packet_in(packet, state, channel.chunker()) {
+                        Ok(size) => self.logger.log_inbound(size),
+                        Err(err) => self.logger.log_raw(format!("dropping invalid packet: {}", err))
+                    }
+                }
+            },
+            Transport::Datagram{ref mut state, ref mut socket} => {
+                if let Ok(packet) = DatagramPacket::decode_response(body) {
+                    if let Some(datagram) = packet.fragment.and_then(|x| state.handle_fragment(x)) {
+                        self.logger.log_inbound(datagram.len());
+                        socket.send(&datagram);
+                    }
+                }
+            }
+        }
     }
 
     fn populate_lane(&mut self, lane: usize) -> Result<(), String> {
-        let (packet, sent_size) = next_packet_out(&mut self.state, &mut self.conn);
-        self.logger.log_outbound(sent_size);
-        let (api_code, data) = packet.encode_query()?;
+        let query = match self.transport {
+            Transport::Stream{ref mut state, ref mut channel} => {
+                let (packet, sent_size) = next_packet_out(state, channel.chunker());
+                self.logger.log_outbound(sent_size);
+                packet.encode_query(self.info.ack_encoding)
+            },
+            Transport::Datagram{ref mut state, ref mut socket} => {
+                while let Some(datagram) = socket.recv() {
+                    let size = datagram.len();
+                    if state.push_send(datagram) {
+                        self.logger.log_outbound(size);
+                    }
+                }
+                DatagramPacket{fragment: state.next_send()}.encode_query()
+            }
+        };
+        let (api_code, data) = query.map_err(|e| format!("encode packet: {}", e))?;
         let message = Message::new_query(Question{
             domain: self.info.name_code.encode_domain(api_code, self.info.session_id, &data,
-                &self.host)?,
+                &self.host).map_err(|e| format!("encode domain: {}", e))?,
             record_type: self.info.record_type,
             record_class: RecordClass::IN
         });
@@ -0,0 +1,174 @@
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use myodine::myo_proto::mux::OpenError;
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+const COMMAND_CONNECT: u8 = 1;
+const ADDR_IPV4: u8 = 1;
+const ADDR_DOMAIN: u8 = 3;
+const ADDR_IPV6: u8 = 4;
+const REPLY_SUCCESS: u8 = 0;
+const REPLY_FAILURE: u8 = 1;
+const REPLY_BAD_COMMAND: u8 = 7;
+const REPLY_BAD_ADDRESS: u8 = 8;
+
+/// Read a SOCKS5 handshake and CONNECT request from a client.
+///
+/// Only unauthenticated CONNECT requests are supported. Unsupported requests
+/// are answered with an error before returning.
+///
+/// Returns the requested host and port. Once the connection succeeds or
+/// fails, the client should be told with `send_reply()`.
+pub fn read_request<S: Read + Write>(conn: &mut S) -> Result<(String, u16), String> {
+    let header = read_bytes(conn, 2)?;
+    if header[0] != VERSION {
+        return Err(format!("unsupported SOCKS version: {}", header[0]));
+    }
+    let methods = read_bytes(conn, header[1] as usize)?;
+    if !methods.contains(&METHOD_NO_AUTH) {
+        write_bytes(conn, &[VERSION, METHOD_UNACCEPTABLE])?;
+        return Err("client requires authentication".to_owned());
+    }
+    write_bytes(conn, &[VERSION, METHOD_NO_AUTH])?;
+
+    let request = read_bytes(conn, 4)?;
+    if request[0] != VERSION {
+        return Err(format!("unsupported SOCKS version: {}", request[0]));
+    }
+    let host = match request[3] {
+        ADDR_IPV4 => {
+            let ip = read_bytes(conn, 4)?;
+            format!("{}", Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))
+        },
+        ADDR_DOMAIN => {
+            let len = read_bytes(conn, 1)?[0] as usize;
+            String::from_utf8(read_bytes(conn, len)?)
+                .map_err(|_| "invalid domain name".to_owned())?
+        },
+        ADDR_IPV6 => {
+            let ip = read_bytes(conn, 16)?;
+            let mut segments = [0u16; 8];
+            for i in 0..8 {
+                segments[i] = ((ip[i * 2] as u16) << 8) | (ip[i * 2 + 1] as u16);
+            }
+            format!("{}", Ipv6Addr::from(segments))
+        },
+        x => {
+            write_reply(conn, REPLY_BAD_ADDRESS)?;
+            return Err(format!("unsupported address type: {}", x));
+        }
+    };
+    let port_bytes = read_bytes(conn, 2)?;
+    if request[1] != COMMAND_CONNECT {
+        write_reply(conn, REPLY_BAD_COMMAND)?;
+        return Err(format!("unsupported command: {}", request[1]));
+    }
+    Ok((host, ((port_bytes[0] as u16) << 8) | (port_bytes[1] as u16)))
+}
+
+/// Tell a client whether its CONNECT request succeeded.
+pub fn send_reply<S: Write>(conn: &mut S, result: &Result<(), OpenError>) -> Result<(), String> {
+    write_reply(conn, if result.is_ok() { REPLY_SUCCESS } else { REPLY_FAILURE })
+}
+
+fn write_reply<S: Write>(conn: &mut S, code: u8) -> Result<(), String> {
+    // The bound address is not meaningful for a tunnel, so it is all zeros.
+    write_bytes(conn, &[VERSION, code, 0, ADDR_IPV4, 0, 0, 0, 0, 0, 0])
+}
+
+fn read_bytes<S: Read>(conn: &mut S, len: usize) -> Result<Vec<u8>, String> {
+    let mut result = vec![0u8; len];
+    conn.read_exact(&mut result).map_err(|e| format!("SOCKS read error: {}", e))?;
+    Ok(result)
+}
+
+fn write_bytes<S: Write>(conn: &mut S, data: &[u8]) -> Result<(), String> {
+    conn.write_all(data).map_err(|e| format!("SOCKS write error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::io::Cursor;
+
+    #[test]
+    fn connect_requests() {
+        let cases: Vec<(Vec<u8>, &str, u16)> = vec![
+            (vec![ADDR_IPV4, 192, 0, 2, 1, 0, 80], "192.0.2.1", 80),
+            (vec![ADDR_IPV6, 0x20, 1, 0xd, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 0xbb],
+                "2001:db8::1", 443),
+            (vec![ADDR_DOMAIN, 11, b'e', b'x', b'a', b'm', b'p', b'l', b'e', b'.', b'c', b'o',
+                b'm', 0x1f, 0x90], "example.com", 8080)
+        ];
+        for (target, host, port) in cases {
+            let mut input = vec![VERSION, 2, 2, METHOD_NO_AUTH, VERSION, COMMAND_CONNECT, 0];
+            input.extend(target);
+            let mut conn = MockConn::new(input);
+            assert_eq!(read_request(&mut conn), Ok((host.to_owned(), port)));
+            assert_eq!(conn.output, vec![VERSION, METHOD_NO_AUTH]);
+        }
+    }
+
+    #[test]
+    fn rejected_requests() {
+        let mut conn = MockConn::new(vec![VERSION, 1, 2]);
+        assert_eq!(read_request(&mut conn), Err("client requires authentication".to_owned()));
+        assert_eq!(conn.output, vec![VERSION, METHOD_UNACCEPTABLE]);
+
+        let mut conn = MockConn::new(vec![VERSION, 1, METHOD_NO_AUTH, VERSION, 2, 0, ADDR_IPV4,
+            127, 0, 0, 1, 0, 80]);
+        assert_eq!(read_request(&mut conn), Err("unsupported command: 2".to_owned()));
+        assert_eq!(conn.output, vec![VERSION, METHOD_NO_AUTH, VERSION, REPLY_BAD_COMMAND, 0,
+            ADDR_IPV4, 0, 0, 0, 0, 0, 0]);
+
+        let mut conn = MockConn::new(vec![VERSION, 1, METHOD_NO_AUTH, VERSION, COMMAND_CONNECT,
+            0, 9]);
+        assert_eq!(read_request(&mut conn), Err("unsupported address type: 9".to_owned()));
+        assert_eq!(conn.output[2..4], [VERSION, REPLY_BAD_ADDRESS]);
+
+        let mut conn = MockConn::new(vec![4, 1, METHOD_NO_AUTH]);
+        assert_eq!(read_request(&mut conn), Err("unsupported SOCKS version: 4".to_owned()));
+        assert_eq!(conn.output.len(), 0);
+    }
+
+    #[test]
+    fn replies() {
+        let mut conn = MockConn::new(Vec::new());
+        send_reply(&mut conn, &Ok(())).unwrap();
+        send_reply(&mut conn, &Err(OpenError::Refused("connect error".to_owned()))).unwrap();
+        assert_eq!(conn.output, vec![VERSION, REPLY_SUCCESS, 0, ADDR_IPV4, 0, 0, 0, 0, 0, 0,
+            VERSION, REPLY_FAILURE, 0, ADDR_IPV4, 0, 0, 0, 0, 0, 0]);
+    }
+
+    /// A connection with canned input, which records its output.
+    struct MockConn {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>
+    }
+
+    impl MockConn {
+        fn new(input: Vec<u8>) -> MockConn {
+            MockConn{input: Cursor::new(input), output: Vec::new()}
+        }
+    }
+
+    impl Read for MockConn {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for MockConn {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
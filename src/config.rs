@@ -0,0 +1,182 @@
+//! Configuration files, which provide defaults for command-line flags.
+//!
+//! A configuration file has one option per line, written as `name = value`,
+//! where `name` is the long name of a flag. A value may be wrapped in double
+//! quotes, in which case `\"` and `\\` are escapes. Flags that take no value
+//! are written as `name = true`, or just `name`. Flags that may be repeated
+//! may appear on several lines. Blank lines and lines starting with `#` or
+//! `;` are ignored.
+
+use std::error;
+use std::fmt::{Display, Formatter};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::str::FromStr;
+
+/// The environment variable that may hold the password.
+pub const PASSWORD_VAR: &str = "MYODINE_PASSWORD";
+
+/// An error from reading a configuration file or looking up an option.
+#[derive(Debug)]
+pub enum Error {
+    /// A file could not be read.
+    Io(io::Error),
+
+    /// A line was not of the form `name = value`.
+    Syntax{line: usize},
+
+    /// An option in the configuration file is not the name of any flag.
+    UnknownOption(String),
+
+    /// An option that is either true or false had some other value.
+    BadValue{name: String, value: String},
+
+    /// An option that must be given was not.
+    MissingOption(String)
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "read error: {}", err),
+            Error::Syntax{line} => write!(f, "line {}: expected name = value", line),
+            Error::UnknownOption(ref name) => write!(f, "unknown option in config file: {}", name),
+            Error::BadValue{ref name, ref value} => {
+                write!(f, "bad {} option: expected true or false, got {}", name, value)
+            },
+            Error::MissingOption(ref name) => write!(f, "missing {} argument", name)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+/// The options in a configuration file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    entries: Vec<(String, String)>
+}
+
+impl Config {
+    /// Create a configuration with no options.
+    pub fn new() -> Config {
+        Config{entries: Vec::new()}
+    }
+
+    /// Read a configuration file.
+    pub fn load(path: &str) -> Result<Config, Error> {
+        read_file(path)?.parse()
+    }
+
+    /// Get the last value of an option.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).pop()
+    }
+
+    /// Get every value of an option, in order.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.entries.iter().filter(|x| x.0 == name).map(|x| x.1.as_str()).collect()
+    }
+
+    /// Get the name of every option, in order.
+    ///
+    /// Options that appear on several lines are listed once per line.
+    pub fn names(&self) -> Vec<&str> {
+        self.entries.iter().map(|x| x.0.as_str()).collect()
+    }
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Config, Error> {
+        let mut entries = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let (name, value) = match line.find('=') {
+                Some(idx) => (line[..idx].trim(), parse_value(line[idx + 1..].trim())),
+                None => (line, Some("true".to_owned()))
+            };
+            match value {
+                Some(value) if !name.is_empty() && !name.contains(char::is_whitespace) => {
+                    entries.push((name.to_owned(), value));
+                },
+                _ => return Err(Error::Syntax{line: i + 1})
+            }
+        }
+        Ok(Config{entries: entries})
+    }
+}
+
+/// Read a password from a file.
+pub fn read_password(path: &str) -> Result<String, Error> {
+    // Editors usually end the file with a newline, which is not part of the
+    // password.
+    Ok(read_file(path)?.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+fn parse_value(value: &str) -> Option<String> {
+    if !value.starts_with('"') {
+        return Some(value.to_owned());
+    }
+    let mut result = String::new();
+    let mut chars = value[1..].chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '"' => return if chars.next().is_none() { Some(result) } else { None },
+            '\\' => result.push(chars.next()?),
+            x => result.push(x)
+        }
+    }
+    None
+}
+
+fn read_file(path: &str) -> Result<String, Error> {
+    let mut contents = String::new();
+    File::open(path)?.read_to_string(&mut contents)?;
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config: Config = "# A comment.\n\n  ; Another comment.\nhost = example.com\n\
+            socks\nns=ns1.example.com\nns = \"ns2.example.com\"\n\
+            password = \"a \\\"quoted\\\" = value\"\n".parse().unwrap();
+        assert_eq!(config.get("host"), Some("example.com"));
+        assert_eq!(config.get("socks"), Some("true"));
+        assert_eq!(config.get_all("ns"), vec!["ns1.example.com", "ns2.example.com"]);
+        assert_eq!(config.get("ns"), Some("ns2.example.com"));
+        assert_eq!(config.get("password"), Some("a \"quoted\" = value"));
+        assert_eq!(config.get("missing"), None);
+        assert_eq!(config.names(), vec!["host", "socks", "ns", "ns", "password"]);
+        for bad in &["x y = 1", "= 1", "x = \"open", "x = \"a\" b"] {
+            assert!(bad.parse::<Config>().is_err());
+        }
+        match "a = 1\nb c\n".parse::<Config>() {
+            Err(Error::Syntax{line}) => assert_eq!(line, 2),
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+}
@@ -1,58 +1,130 @@
 use std::io;
-use std::io::{Read, Write};
+use std::io::Read;
 use std::mem::replace;
-use std::net::{Shutdown, TcpStream};
-use std::sync::mpsc::{SyncSender, Receiver, TrySendError, sync_channel};
+use std::sync::mpsc::{SyncSender, Receiver, TryRecvError, TrySendError, sync_channel};
 use std::thread::spawn;
+use std::time::{Duration, Instant};
 
-/// A TCP connection that reads and writes data in chunks.
+use super::{Interrupt, SplitStream, WriteHalf};
+
+/// A source and sink for the chunks of data in a WWR stream.
+pub trait Chunker {
+    /// Check if there is room in the send buffer.
+    ///
+    /// If this returns false, it means that the source of data should apply
+    /// backpressure.
+    fn can_send(&mut self) -> bool;
+
+    /// Send a chunk of data to the remote end.
+    ///
+    /// Before calling this, you should check can_send().
+    fn send(&mut self, chunk: Vec<u8>);
+
+    /// Send an EOF to the remote end.
+    ///
+    /// After calling this, you should not call send() again.
+    /// This should be fine, since can_send() will return false.
+    fn send_finished(&mut self);
+
+    /// Receive the next chunk if one is available.
+    ///
+    /// If no new chunks are available, None is returned.
+    /// An empty chunk represents EOF.
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+/// A connection that reads and writes data in chunks.
+///
+/// Small reads are coalesced into chunks of up to the receive MTU.
+///
+/// This is usually a TCP connection, but it can be any `SplitStream`.
 pub struct TcpChunker {
-    stream: TcpStream,
+    interrupt: Interrupt,
     incoming: Receiver<Vec<u8>>,
     outgoing: Option<SyncSender<Vec<u8>>>,
-    buffer_chunk: Option<Vec<u8>>
+    buffer_chunk: Option<Vec<u8>>,
+    recv_mtu: usize,
+    coalesce_delay: Duration,
+    coalesce_buffer: Vec<u8>,
+    coalesce_start: Option<Instant>,
+    recv_eof: bool,
+    recv_eof_reported: bool
 }
 
 impl TcpChunker {
-    /// Create a new TCP chunker.
+    /// Create a new chunker.
     ///
     /// # Arguments
     ///
-    /// * `stream` - A TCP stream to wrap.
+    /// * `stream` - A stream to wrap, such as a `TcpStream`.
     /// * `recv_mtu` - The maximum incoming chunk size.
     /// * `in_buf` - The number of incoming chunks to buffer.
     /// * `out_buf` - The number of outgoing chunks to buffer.
-    pub fn new(
-        stream: TcpStream,
+    /// * `coalesce_delay` - The maximum amount of time to hold on to a partial
+    ///   chunk while waiting for more data to fill it up.
+    pub fn new<S: SplitStream>(
+        stream: S,
         recv_mtu: usize,
         in_buf: usize,
-        out_buf: usize
+        out_buf: usize,
+        coalesce_delay: Duration
     ) -> io::Result<TcpChunker> {
         let (in_sender, in_receiver) = sync_channel(in_buf);
         let (out_sender, out_receiver) = sync_channel(out_buf);
-        let clone1 = stream.try_clone()?;
-        let clone2 = stream.try_clone()?;
-        // TODO: why is `move` necessary here, but not below?
+        let (reader, writer, interrupt) = stream.split()?;
         spawn(move || {
-            TcpChunker::read_loop(&in_sender, clone1, recv_mtu);
+            TcpChunker::read_loop(&in_sender, reader, recv_mtu);
             in_sender.send(Vec::new()).ok();
         });
         spawn(|| {
-            TcpChunker::write_loop(out_receiver, clone2);
+            TcpChunker::write_loop(out_receiver, writer);
         });
         Ok(TcpChunker{
-            stream: stream,
+            interrupt: interrupt,
             incoming: in_receiver,
             outgoing: Some(out_sender),
-            buffer_chunk: None
+            buffer_chunk: None,
+            recv_mtu: recv_mtu,
+            coalesce_delay: coalesce_delay,
+            coalesce_buffer: Vec::new(),
+            coalesce_start: None,
+            recv_eof: false,
+            recv_eof_reported: false
         })
     }
 
-    /// Check if there is room in the send buffer.
-    ///
-    /// If this returns false, it means that the source of data should apply
-    /// backpressure.
-    pub fn can_send(&mut self) -> bool {
+    fn write_loop<W: WriteHalf>(channel: Receiver<Vec<u8>>, mut stream: W) {
+        for chunk in channel {
+            if stream.write_all(&chunk).and_then(|_| stream.flush()).is_err() {
+                return;
+            }
+        }
+        stream.close_write();
+    }
+
+    fn read_loop<R: Read>(channel: &SyncSender<Vec<u8>>, mut stream: R, chunk_size: usize) {
+        let mut data = Vec::new();
+        for _ in 0..chunk_size {
+            data.push(0u8);
+        }
+        loop {
+            if let Ok(size) = stream.read(&mut data) {
+                if size == 0 {
+                    // For some reason, this seems to happen on EOF.
+                    return;
+                }
+                if channel.send(data[0..size].to_vec()).is_err() {
+                    return;
+                }
+            } else {
+                return;
+            }
+        }
+    }
+}
+
+impl Chunker for TcpChunker {
+    fn can_send(&mut self) -> bool {
         if self.outgoing.is_none() {
             return false;
         }
@@ -65,10 +137,7 @@ impl TcpChunker {
         }
     }
 
-    /// Send a chunk of data to the remote end.
-    ///
-    /// Before calling this, you should check can_send().
-    pub fn send(&mut self, chunk: Vec<u8>) {
+    fn send(&mut self, chunk: Vec<u8>) {
         assert!(self.outgoing.is_some());
         assert!(self.buffer_chunk.is_none());
         self.buffer_chunk = match self.outgoing.as_ref().unwrap().try_send(chunk) {
@@ -77,11 +146,7 @@ impl TcpChunker {
         }
     }
 
-    /// Send an EOF to the remote end.
-    ///
-    /// After calling this, you should not call send() again.
-    /// This should be fine, since can_send() will return false.
-    pub fn send_finished(&mut self) {
+    fn send_finished(&mut self) {
         assert!(self.outgoing.is_some());
         if let Some(data) = replace(&mut self.buffer_chunk, None) {
             let ch = replace(&mut self.outgoing, None);
@@ -95,38 +160,48 @@ impl TcpChunker {
 
     /// Receive the next chunk if one is available.
     ///
-    /// If no new chunks are available, None is returned.
-    /// An empty chunk represents EOF.
-    pub fn recv(&mut self) -> Option<Vec<u8>> {
-        self.incoming.try_recv().ok()
-    }
-
-    fn write_loop(channel: Receiver<Vec<u8>>, mut stream: TcpStream) {
-        for chunk in channel {
-            if let Err(_) = stream.write_all(&chunk) {
-                return;
+    /// Chunks are at most `recv_mtu` bytes. A chunk smaller than `recv_mtu` is
+    /// only returned once `coalesce_delay` has passed since its first byte was
+    /// read, or once the stream has reached EOF.
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        while !self.recv_eof && self.coalesce_buffer.len() < self.recv_mtu {
+            match self.incoming.try_recv() {
+                Ok(data) => {
+                    if data.len() == 0 {
+                        self.recv_eof = true;
+                    } else {
+                        if self.coalesce_start.is_none() {
+                            self.coalesce_start = Some(Instant::now());
+                        }
+                        self.coalesce_buffer.extend(data);
+                    }
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return None
             }
         }
-        stream.shutdown(Shutdown::Write).ok();
-    }
-
-    fn read_loop(channel: &SyncSender<Vec<u8>>, mut stream: TcpStream, chunk_size: usize) {
-        let mut data = Vec::new();
-        for _ in 0..chunk_size {
-            data.push(0u8);
-        }
-        loop {
-            if let Ok(size) = stream.read(&mut data) {
-                if size == 0 {
-                    // For some reason, this seems to happen on EOF.
-                    return;
-                }
-                if channel.send(data[0..size].to_vec()).is_err() {
-                    return;
-                }
+        if self.coalesce_buffer.len() >= self.recv_mtu {
+            let rest = self.coalesce_buffer.split_off(self.recv_mtu);
+            let chunk = replace(&mut self.coalesce_buffer, rest);
+            self.coalesce_start = if self.coalesce_buffer.len() > 0 {
+                Some(Instant::now())
             } else {
-                return;
-            }
+                None
+            };
+            return Some(chunk);
+        }
+        let timed_out = match self.coalesce_start {
+            Some(start) => start.elapsed() >= self.coalesce_delay,
+            None => false
+        };
+        if timed_out || (self.recv_eof && self.coalesce_buffer.len() > 0) {
+            self.coalesce_start = None;
+            Some(replace(&mut self.coalesce_buffer, Vec::new()))
+        } else if self.recv_eof && !self.recv_eof_reported {
+            self.recv_eof_reported = true;
+            Some(Vec::new())
+        } else {
+            None
         }
     }
 }
@@ -134,6 +209,77 @@ impl TcpChunker {
 impl Drop for TcpChunker {
     fn drop(&mut self) {
         // Force the read loop to die.
-        self.stream.shutdown(Shutdown::Read).ok();
+        (self.interrupt)();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::thread::sleep;
+    use conn::{IoPair, pipe};
+
+    #[test]
+    fn coalesce_small_writes() {
+        let (mut local, remote) = tcp_pair();
+        let mut chunker = TcpChunker::new(remote, 8, 16, 16, Duration::from_millis(200)).unwrap();
+        for i in 0..5 {
+            local.write_all(&[i, i]).unwrap();
+        }
+        sleep(Duration::from_millis(50));
+        assert_eq!(chunker.recv(), Some(vec![0, 0, 1, 1, 2, 2, 3, 3]));
+        assert_eq!(chunker.recv(), None);
+        sleep(Duration::from_millis(200));
+        assert_eq!(chunker.recv(), Some(vec![4, 4]));
+        assert_eq!(chunker.recv(), None);
+
+        local.write_all(&[5]).unwrap();
+        local.shutdown(Shutdown::Write).unwrap();
+        sleep(Duration::from_millis(50));
+        assert_eq!(chunker.recv(), Some(vec![5]));
+        assert_eq!(chunker.recv(), Some(Vec::new()));
+        assert_eq!(chunker.recv(), None);
+    }
+
+    #[test]
+    fn no_coalesce_delay() {
+        let (mut local, remote) = tcp_pair();
+        let mut chunker = TcpChunker::new(remote, 8, 16, 16, Duration::from_millis(0)).unwrap();
+        local.write_all(&[1, 2, 3]).unwrap();
+        sleep(Duration::from_millis(50));
+        assert_eq!(chunker.recv(), Some(vec![1, 2, 3]));
+        assert_eq!(chunker.recv(), None);
+    }
+
+    #[test]
+    fn in_memory_pipes() {
+        let (in_writer, in_reader) = pipe();
+        let (out_writer, out_reader) = pipe();
+        let stream = IoPair{reader: in_reader, writer: out_writer};
+        let mut chunker = TcpChunker::new(stream, 8, 16, 16, Duration::from_millis(0)).unwrap();
+
+        let mut in_writer = in_writer;
+        in_writer.write_all(&[1, 2, 3]).unwrap();
+        in_writer.close_write();
+        sleep(Duration::from_millis(50));
+        assert_eq!(chunker.recv(), Some(vec![1, 2, 3]));
+        assert_eq!(chunker.recv(), Some(Vec::new()));
+
+        assert!(chunker.can_send());
+        chunker.send(vec![4, 5]);
+        chunker.send_finished();
+        let mut output = Vec::new();
+        let mut out_reader = out_reader;
+        out_reader.read_to_end(&mut output).unwrap();
+        assert_eq!(output, vec![4, 5]);
+    }
+
+    fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let local = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (remote, _) = listener.accept().unwrap();
+        (local, remote)
     }
 }
@@ -0,0 +1,106 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, TrySendError, sync_channel};
+use std::thread::spawn;
+use std::time::Duration;
+
+/// The number of incoming datagrams to buffer.
+const RECV_BUFFER: usize = 64;
+
+/// A UDP socket that is read in the background.
+///
+/// Datagrams are dropped if they arrive faster than they are received.
+pub struct DatagramSocket {
+    socket: UdpSocket,
+    incoming: Receiver<Vec<u8>>,
+    peer: Arc<Mutex<Option<SocketAddr>>>,
+    closed: Arc<AtomicBool>
+}
+
+impl DatagramSocket {
+    /// Exchange datagrams with a remote address.
+    pub fn connect(addr: &SocketAddr) -> io::Result<DatagramSocket> {
+        let local_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local_addr)?;
+        socket.connect(addr)?;
+        DatagramSocket::start(socket, Some(*addr))
+    }
+
+    /// Listen for datagrams on a local address.
+    ///
+    /// Datagrams are sent to whichever address sent the latest datagram.
+    pub fn listen(addr: &str) -> io::Result<DatagramSocket> {
+        DatagramSocket::start(UdpSocket::bind(addr)?, None)
+    }
+
+    fn start(socket: UdpSocket, peer: Option<SocketAddr>) -> io::Result<DatagramSocket> {
+        // Wake up every so often to notice when the socket is dropped.
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        let reader = socket.try_clone()?;
+        let (sender, receiver) = sync_channel(RECV_BUFFER);
+        let peer = Arc::new(Mutex::new(peer));
+        let closed = Arc::new(AtomicBool::new(false));
+        let (reader_peer, reader_closed) = (peer.clone(), closed.clone());
+        spawn(move || {
+            let mut buffer = vec![0u8; 0x10000];
+            while !reader_closed.load(Ordering::SeqCst) {
+                if let Ok((size, addr)) = reader.recv_from(&mut buffer) {
+                    *reader_peer.lock().unwrap() = Some(addr);
+                    if let Err(TrySendError::Disconnected(_)) =
+                        sender.try_send(buffer[0..size].to_vec())
+                    {
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(DatagramSocket{socket: socket, incoming: receiver, peer: peer, closed: closed})
+    }
+
+    /// Send a datagram.
+    ///
+    /// The datagram is silently dropped if there is nowhere to send it yet.
+    pub fn send(&self, datagram: &[u8]) {
+        if let Some(addr) = *self.peer.lock().unwrap() {
+            self.socket.send_to(datagram, addr).ok();
+        }
+    }
+
+    /// Get the next incoming datagram, if there is one.
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        self.incoming.try_recv().ok()
+    }
+}
+
+impl Drop for DatagramSocket {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+
+    #[test]
+    fn relay_datagrams() {
+        let mut listener = DatagramSocket::listen("127.0.0.1:0").unwrap();
+        let addr = listener.socket.local_addr().unwrap();
+        let mut client = DatagramSocket::connect(&addr).unwrap();
+
+        // Nobody has sent the listener anything to reply to.
+        listener.send(&[9]);
+        client.send(&[1, 2, 3]);
+        sleep(Duration::from_millis(50));
+        assert_eq!(client.recv(), None);
+        assert_eq!(listener.recv(), Some(vec![1, 2, 3]));
+        assert_eq!(listener.recv(), None);
+
+        listener.send(&[4, 5]);
+        sleep(Duration::from_millis(50));
+        assert_eq!(client.recv(), Some(vec![4, 5]));
+    }
+}
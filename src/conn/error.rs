@@ -0,0 +1,46 @@
+use std::error;
+use std::fmt::{Display, Formatter};
+use std::fmt;
+use std::io;
+
+use dns_coding;
+
+/// An error from sending a message over a connection.
+#[derive(Debug)]
+pub enum Error {
+    /// The message could not be encoded.
+    Encode(dns_coding::Error),
+
+    /// The underlying socket failed.
+    Io(io::Error)
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Error::Encode(ref err) => write!(f, "error encoding message: {}", err),
+            Error::Io(ref err) => write!(f, "socket error: {}", err)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Encode(ref err) => Some(err),
+            Error::Io(ref err) => Some(err)
+        }
+    }
+}
+
+impl From<dns_coding::Error> for Error {
+    fn from(err: dns_coding::Error) -> Error {
+        Error::Encode(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
@@ -1,13 +1,14 @@
 use std::io;
 
 use dns_proto::Message;
+use super::Error;
 
 /// An event from a `Highway`.
 #[derive(Debug)]
 pub enum Event {
     Response(usize, Message),
     Timeout(usize),
-    SendError(usize, String),
+    SendError(usize, Error),
     ConnectError(usize, io::Error),
     SocketError(usize, io::Error)
 }
@@ -11,6 +11,7 @@ use dns_coding::{dns_decode, dns_encode};
 use dns_proto::Message;
 
 use super::highway::{Event, Highway};
+use super::Error;
 use super::dial_tcp;
 
 /// A highway that multiplexes queries over one TCP socket.
@@ -114,14 +115,13 @@ fn write_loop(
                 data_1.extend(data);
                 // TODO: look for built-in function for this.
                 if let Err(err) = write_data(&mut socket, &data_1) {
-                    let msg = format!("failed to write to socket: {}", err);
-                    sender.send(Event::SendError(lane, msg)).ok();
+                    sender.send(Event::SendError(lane, Error::Io(err))).ok();
                 } else {
                     pending.add(msg, lane, max_time.clone());
                 }
             },
             Err(err) => {
-                sender.send(Event::SendError(lane, err)).ok();
+                sender.send(Event::SendError(lane, Error::Encode(err))).ok();
             }
         }
     }
@@ -9,6 +9,7 @@ use dns_coding::{dns_decode, dns_encode};
 use dns_proto::Message;
 
 use super::highway::{Event, Highway};
+use super::Error;
 use super::dial_udp;
 
 /// A highway that opens one UDP socket per lane.
@@ -111,13 +112,10 @@ impl Lane {
         }
     }
 
-    fn send_message(&mut self, mut message: Message) -> Result<(), String> {
+    fn send_message(&mut self, mut message: Message) -> Result<(), Error> {
         message.header.identifier = self.next_seq();
-        if let Err(err) = self.socket.send(&dns_encode(&message)?) {
-            Err(format!("error sending datagram: {}", err))
-        } else {
-            Ok(())
-        }
+        self.socket.send(&dns_encode(&message)?)?;
+        Ok(())
     }
 
     fn recv_response(
@@ -1,13 +1,25 @@
-//! APIs for managing UDP and DNS connections.
+//! APIs for managing UDP and DNS connections, and the streams they carry.
 
 mod chunker;
+mod datagram;
 mod dial;
+mod error;
 mod highway;
 mod highway_tcp;
 mod highway_udp;
+mod pipe;
+mod stream;
+#[cfg(target_os = "linux")]
+mod tun;
 
-pub use self::chunker::TcpChunker;
+pub use self::chunker::{Chunker, TcpChunker};
+pub use self::datagram::DatagramSocket;
 pub use self::dial::{dial_tcp, dial_udp};
+pub use self::error::Error;
 pub use self::highway::{Event, Highway};
 pub use self::highway_tcp::TCPHighway;
 pub use self::highway_udp::UDPHighway;
+pub use self::pipe::{PipeReader, PipeWriter, pipe};
+pub use self::stream::{Interrupt, IoPair, SplitStream, WriteHalf};
+#[cfg(target_os = "linux")]
+pub use self::tun::TunDevice;
@@ -0,0 +1,67 @@
+use std::cmp::min;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::mpsc::{Receiver, Sender, channel};
+
+use super::WriteHalf;
+
+/// Create an in-memory pipe.
+///
+/// Every write is delivered by a single read, as long as the read buffer is
+/// big enough, so the pipe can stand in for a packet device.
+pub fn pipe() -> (PipeWriter, PipeReader) {
+    let (sender, receiver) = channel();
+    (PipeWriter{sender: Some(sender)}, PipeReader{receiver: receiver, partial: Vec::new()})
+}
+
+/// The writing end of an in-memory pipe.
+pub struct PipeWriter {
+    sender: Option<Sender<Vec<u8>>>
+}
+
+/// The reading end of an in-memory pipe.
+///
+/// Reads return zero bytes once the writer is closed or dropped.
+pub struct PipeReader {
+    receiver: Receiver<Vec<u8>>,
+    partial: Vec<u8>
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.sender {
+            Some(ref sender) => sender.send(buf.to_vec())
+                .map(|_| buf.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "reader was dropped")),
+            None => Err(io::Error::new(io::ErrorKind::BrokenPipe, "writer was closed"))
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteHalf for PipeWriter {
+    fn close_write(&mut self) {
+        self.sender = None;
+    }
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.partial.is_empty() {
+            match self.receiver.recv() {
+                Ok(data) => self.partial = data,
+                Err(_) => return Ok(0)
+            }
+        }
+        let size = min(buf.len(), self.partial.len());
+        buf[0..size].copy_from_slice(&self.partial[0..size]);
+        self.partial.drain(0..size);
+        Ok(size)
+    }
+}
@@ -0,0 +1,110 @@
+use std::fs::File;
+use std::io;
+use std::io::{Read, Stdout, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::process::ChildStdin;
+
+/// A function which unblocks any pending reads on a stream.
+pub type Interrupt = Box<dyn FnMut() + Send>;
+
+/// A two-way byte stream that can be wrapped by a `TcpChunker`.
+///
+/// The stream is split into halves so that reads and writes can block on
+/// separate threads.
+pub trait SplitStream {
+    type Reader: Read + Send + 'static;
+    type Writer: WriteHalf + Send + 'static;
+
+    /// Split the stream into its reading and writing halves.
+    ///
+    /// Also returns a function which interrupts the reading half once the
+    /// stream is no longer needed.
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer, Interrupt)>;
+}
+
+/// The writing half of a stream.
+pub trait WriteHalf: Write {
+    /// Tell the other end that no more data will be written.
+    fn close_write(&mut self);
+}
+
+/// A stream made of a separate reader and writer, such as standard input and
+/// output or the pipes of a child process.
+///
+/// Reads cannot be interrupted, so a blocked read lasts until the reader sees
+/// an EOF.
+pub struct IoPair<R, W> {
+    pub reader: R,
+    pub writer: W
+}
+
+impl SplitStream for TcpStream {
+    type Reader = TcpStream;
+    type Writer = TcpStream;
+
+    fn split(self) -> io::Result<(TcpStream, TcpStream, Interrupt)> {
+        let reader = self.try_clone()?;
+        let writer = self.try_clone()?;
+        Ok((reader, writer, Box::new(move || {
+            self.shutdown(Shutdown::Read).ok();
+        })))
+    }
+}
+
+impl WriteHalf for TcpStream {
+    fn close_write(&mut self) {
+        self.shutdown(Shutdown::Write).ok();
+    }
+}
+
+#[cfg(unix)]
+impl SplitStream for UnixStream {
+    type Reader = UnixStream;
+    type Writer = UnixStream;
+
+    fn split(self) -> io::Result<(UnixStream, UnixStream, Interrupt)> {
+        let reader = self.try_clone()?;
+        let writer = self.try_clone()?;
+        Ok((reader, writer, Box::new(move || {
+            self.shutdown(Shutdown::Read).ok();
+        })))
+    }
+}
+
+#[cfg(unix)]
+impl WriteHalf for UnixStream {
+    fn close_write(&mut self) {
+        self.shutdown(Shutdown::Write).ok();
+    }
+}
+
+impl<R, W> SplitStream for IoPair<R, W>
+    where R: Read + Send + 'static, W: WriteHalf + Send + 'static
+{
+    type Reader = R;
+    type Writer = W;
+
+    fn split(self) -> io::Result<(R, W, Interrupt)> {
+        Ok((self.reader, self.writer, Box::new(|| ())))
+    }
+}
+
+impl WriteHalf for File {
+    fn close_write(&mut self) {
+        // The file is closed once the writer is dropped.
+    }
+}
+
+impl WriteHalf for Stdout {
+    fn close_write(&mut self) {
+        // Standard output is closed when the process exits.
+    }
+}
+
+impl WriteHalf for ChildStdin {
+    fn close_write(&mut self) {
+        // The pipe is closed once the writer is dropped.
+    }
+}
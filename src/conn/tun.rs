@@ -0,0 +1,85 @@
+extern crate libc;
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::unix::io::AsRawFd;
+use std::process::Command;
+
+use super::{Interrupt, SplitStream};
+
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const IFNAMSIZ: usize = 16;
+
+#[repr(C)]
+struct IfReq {
+    name: [u8; IFNAMSIZ],
+    flags: libc::c_short,
+    padding: [u8; 22]
+}
+
+/// A Linux tun device, which reads and writes raw IP packets.
+///
+/// Opening a device usually requires root privileges.
+pub struct TunDevice {
+    file: File,
+    name: String
+}
+
+impl TunDevice {
+    /// Create or attach to a tun device.
+    ///
+    /// If the name is empty, the kernel picks one.
+    pub fn open(name: &str) -> io::Result<TunDevice> {
+        if name.len() >= IFNAMSIZ {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "device name is too long"));
+        }
+        let file = OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+        let mut req = IfReq{name: [0; IFNAMSIZ], flags: IFF_TUN | IFF_NO_PI, padding: [0; 22]};
+        req.name[0..name.len()].copy_from_slice(name.as_bytes());
+        if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = req.name.iter().position(|&x| x == 0).unwrap_or(IFNAMSIZ);
+        Ok(TunDevice{
+            file: file,
+            name: String::from_utf8_lossy(&req.name[0..len]).into_owned()
+        })
+    }
+
+    /// Get the name of the device.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Assign an address to the device and bring it up.
+    ///
+    /// This uses the `ip` command.
+    pub fn configure(&self, addr: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+        run_ip(&["addr", "add", &format!("{}/{}", addr, prefix_len), "dev", &self.name])?;
+        run_ip(&["link", "set", "dev", &self.name, "up"])
+    }
+}
+
+impl SplitStream for TunDevice {
+    type Reader = File;
+    type Writer = File;
+
+    fn split(self) -> io::Result<(File, File, Interrupt)> {
+        // Reads from a tun device cannot be interrupted, but the device stays
+        // open for the life of the process anyway.
+        let writer = self.file.try_clone()?;
+        Ok((self.file, writer, Box::new(|| ())))
+    }
+}
+
+fn run_ip(args: &[&str]) -> io::Result<()> {
+    let status = Command::new("ip").args(args).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("ip {}: {}", args.join(" "), status)))
+    }
+}
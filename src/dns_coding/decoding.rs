@@ -1,5 +1,7 @@
+use super::Error;
+
 /// Decode a packet using a Decoder implementation.
-pub fn dns_decode<T: Decoder>(data: Vec<u8>) -> Result<T, String> {
+pub fn dns_decode<T: Decoder>(data: Vec<u8>) -> Result<T, T::Error> {
     let mut dec = DecPacket::new(data);
     T::dns_decode(&mut dec)
 }
@@ -28,9 +30,9 @@ impl DecPacket {
     /// seeked to the given offset.
     ///
     /// Fails if a parameter is out of bounds.
-    pub fn seek(&self, new_offset: usize, new_size: usize) -> Result<DecPacket, String> {
+    pub fn seek(&self, new_offset: usize, new_size: usize) -> Result<DecPacket, Error> {
         if new_offset >= new_size || new_size > self.buffer.len() {
-            return Err("seek out of bounds".to_owned());
+            return Err(Error::SeekOutOfBounds);
         }
         let mut res = Vec::new();
         for x in &self.buffer[0..new_size] {
@@ -42,13 +44,18 @@ impl DecPacket {
         })
     }
 
+    /// Take back the whole buffer, including the bytes that were read.
+    pub fn into_buffer(self) -> Vec<u8> {
+        self.buffer
+    }
+
     /// Get the bytes remaining in the buffer.
     pub fn remaining(&self) -> usize {
         self.buffer.len() - self.offset
     }
 
     /// Read the given number of bytes from the current position.
-    pub fn read_bytes(&mut self, num_bytes: usize) -> Result<Vec<u8>, String> {
+    pub fn read_bytes(&mut self, num_bytes: usize) -> Result<Vec<u8>, Error> {
         let mut res = Vec::new();
         for _ in 0..num_bytes {
             res.push(u8::dns_decode(self)?);
@@ -57,7 +64,7 @@ impl DecPacket {
     }
 
     /// Decode a sequence of homogeneously-typed objects.
-    pub fn decode_all<T: Decoder>(&mut self, num_items: usize) -> Result<Vec<T>, String> {
+    pub fn decode_all<T: Decoder>(&mut self, num_items: usize) -> Result<Vec<T>, T::Error> {
         let mut res = Vec::new();
         for _ in 0..num_items {
             res.push(T::dns_decode(self)?);
@@ -69,14 +76,14 @@ impl DecPacket {
     ///
     /// Passes the length to `f`, and ensures that `f` reads exactly the correct
     /// number of bytes.
-    pub fn decode_with_length<F, T>(&mut self, f: F) -> Result<T, String>
-        where F: FnOnce(&mut DecPacket, usize) -> Result<T, String>
+    pub fn decode_with_length<F, T, E>(&mut self, f: F) -> Result<T, E>
+        where F: FnOnce(&mut DecPacket, usize) -> Result<T, E>, E: From<Error>
     {
         let len = u16::dns_decode(self)? as usize;
         let offset = self.offset;
         let result = f(self, len)?;
         if self.offset < len || self.offset - len != offset {
-            Err("incorrect length field".to_owned())
+            Err(Error::LengthMismatch.into())
         } else {
             Ok(result)
         }
@@ -85,13 +92,21 @@ impl DecPacket {
 
 /// A type that can decode itself from a `DecPacket`.
 pub trait Decoder where Self: Sized {
-    fn dns_decode(packet: &mut DecPacket) -> Result<Self, String>;
+    /// The error produced for invalid data.
+    ///
+    /// This must include low-level decoding errors, since every decoder is
+    /// ultimately built out of primitive reads.
+    type Error: From<Error>;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<Self, Self::Error>;
 }
 
 impl Decoder for u8 {
-    fn dns_decode(packet: &mut DecPacket) -> Result<u8, String> {
+    type Error = Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<u8, Error> {
         if packet.offset >= packet.buffer.len() {
-            Err("buffer underflow".to_owned())
+            Err(Error::BufferUnderflow)
         } else {
             packet.offset += 1;
             Ok(packet.buffer[packet.offset - 1])
@@ -100,7 +115,9 @@ impl Decoder for u8 {
 }
 
 impl Decoder for u16 {
-    fn dns_decode(packet: &mut DecPacket) -> Result<u16, String> {
+    type Error = Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<u16, Error> {
         let big_byte = u8::dns_decode(packet)?;
         let small_byte = u8::dns_decode(packet)?;
         Ok(((big_byte as u16) << 8) | (small_byte as u16))
@@ -108,7 +125,9 @@ impl Decoder for u16 {
 }
 
 impl Decoder for u32 {
-    fn dns_decode(packet: &mut DecPacket) -> Result<u32, String> {
+    type Error = Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<u32, Error> {
         let big_word = u16::dns_decode(packet)?;
         let small_word = u16::dns_decode(packet)?;
         Ok(((big_word as u32) << 16) | (small_word as u32))
@@ -116,7 +135,9 @@ impl Decoder for u32 {
 }
 
 impl Decoder for u64 {
-    fn dns_decode(packet: &mut DecPacket) -> Result<u64, String> {
+    type Error = Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<u64, Error> {
         let big_word = u32::dns_decode(packet)?;
         let small_word = u32::dns_decode(packet)?;
         Ok(((big_word as u64) << 32) | (small_word as u64))
@@ -1,8 +1,10 @@
 use std::iter::IntoIterator;
 use std::mem::size_of;
 
+use super::Error;
+
 /// Encode an object as binary data using an Encoder implementation.
-pub fn dns_encode<T: Encoder>(x: &T) -> Result<Vec<u8>, String> {
+pub fn dns_encode<T: Encoder>(x: &T) -> Result<Vec<u8>, Error> {
     let mut packet = EncPacket::new();
     x.dns_encode(&mut packet)?;
     Ok(packet.0)
@@ -18,8 +20,8 @@ impl EncPacket {
     }
 
     /// Encode some data, and prefix it with a 16-bit length field.
-    pub fn encode_with_length<F>(&mut self, f: F) -> Result<(), String>
-        where F: FnOnce(&mut EncPacket) -> Result<(), String>
+    pub fn encode_with_length<F>(&mut self, f: F) -> Result<(), Error>
+        where F: FnOnce(&mut EncPacket) -> Result<(), Error>
     {
         let offset = self.0.len();
         0u16.dns_encode(self)?;
@@ -28,7 +30,7 @@ impl EncPacket {
 
         let delta_length = self.0.len() - offset - 2;
         if delta_length > 0xffff {
-            Err("length field overflow".to_owned())
+            Err(Error::LengthOverflow)
         } else {
             self.0[offset] = (delta_length >> 8) as u8;
             self.0[offset + 1] = (delta_length & 0xff) as u8;
@@ -44,18 +46,18 @@ impl EncPacket {
 
 /// An object which can be serialized into an `EncPacket`.
 pub trait Encoder {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String>;
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), Error>;
 }
 
 impl Encoder for u8 {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), Error> {
         packet.0.push(*self);
         Ok(())
     }
 }
 
 impl Encoder for u16 {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), Error> {
         packet.0.push((*self >> 8) as u8);
         packet.0.push((*self & 0xff) as u8);
         Ok(())
@@ -63,7 +65,7 @@ impl Encoder for u16 {
 }
 
 impl Encoder for u32 {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), Error> {
         packet.0.push((*self >> 24) as u8);
         packet.0.push(((*self >> 16) & 0xff) as u8);
         packet.0.push(((*self >> 8) & 0xff) as u8);
@@ -73,7 +75,7 @@ impl Encoder for u32 {
 }
 
 impl Encoder for u64 {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), Error> {
         for i in 0..8 {
             packet.0.push((*self >> (56 - i * 8)) as u8);
         }
@@ -82,7 +84,7 @@ impl Encoder for u64 {
 }
 
 impl<T: Encoder> Encoder for Vec<T> {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), Error> {
         for item in self.into_iter() {
             item.dns_encode(packet)?;
         }
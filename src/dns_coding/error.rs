@@ -0,0 +1,40 @@
+use std::error;
+use std::fmt::{Display, Formatter};
+use std::fmt;
+
+/// An error from encoding or decoding binary data.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// A read went past the end of the packet.
+    BufferUnderflow,
+
+    /// A seek went outside of the packet.
+    SeekOutOfBounds,
+
+    /// A length or count field did not match the data it describes.
+    LengthMismatch,
+
+    /// Some data was too long for its length field.
+    LengthOverflow,
+
+    /// There was data left over after decoding.
+    TrailingData,
+
+    /// The value cannot be represented in binary form.
+    Unencodable(&'static str)
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Error::BufferUnderflow => write!(f, "buffer underflow"),
+            Error::SeekOutOfBounds => write!(f, "seek out of bounds"),
+            Error::LengthMismatch => write!(f, "incorrect length field"),
+            Error::LengthOverflow => write!(f, "length field overflow"),
+            Error::TrailingData => write!(f, "trailing data in packet"),
+            Error::Unencodable(what) => write!(f, "cannot encode {}", what)
+        }
+    }
+}
+
+impl error::Error for Error {}
@@ -3,6 +3,8 @@
 #[macro_use]
 mod encoding;
 mod decoding;
+mod error;
 
 pub use self::decoding::{DecPacket, Decoder, BitReader, dns_decode};
+pub use self::error::Error;
 pub use self::encoding::{EncPacket, Encoder, BitWriter, dns_encode};
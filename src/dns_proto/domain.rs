@@ -1,7 +1,10 @@
-use std::fmt::{Display, Error, Formatter};
+use std::fmt::{Display, Formatter};
+use std::fmt;
 use std::str::FromStr;
 
+use dns_coding;
 use dns_coding::{Decoder, DecPacket, Encoder, EncPacket};
+use super::Error;
 
 /// A DNS domain name.
 #[derive(Clone, PartialEq, Eq, Debug)]
@@ -11,30 +14,30 @@ impl Domain {
     /// Create a domain name from the labels.
     ///
     /// This may fail if the requested domain name is invalid.
-    pub fn from_parts(labels: Vec<String>) -> Result<Domain, String> {
+    pub fn from_parts(labels: Vec<String>) -> Result<Domain, Error> {
         let mut total_len = 1usize;
         for label in &labels {
             if label.len() == 0 {
-                return Err(format!("empty domain name label"));
+                return Err(Error::EmptyLabel);
             } else if !label.is_ascii() {
-                return Err(format!("domain label is not ASCII: {}", label));
+                return Err(Error::InvalidLabel(label.clone()));
             } else if label.len() > 63 {
-                return Err(format!("domain label is too long: {}", label));
+                return Err(Error::LabelTooLong(label.clone()));
             }
             total_len += label.len() + 1usize;
             let chars: Vec<char> = label.chars().collect();
             if chars[chars.len() - 1] == '-'  || chars[0] == '-' {
-                return Err(format!("domain label may not end or start with -"));
+                return Err(Error::LabelHyphen(label.clone()));
             }
             for b in chars {
                 if !in_char_range('a', 'z', b) && !in_char_range('A', 'Z', b) &&
                     !in_char_range('0', '9', b) && b != '-' {
-                    return Err(format!("domain label may only contain a-zA-Z0-9-"));
+                    return Err(Error::InvalidLabel(label.clone()));
                 }
             }
         }
         if total_len > 255 {
-            return Err(format!("domain name is too long: {:?}", &labels));
+            return Err(Error::DomainTooLong);
         }
         Ok(Domain(labels))
     }
@@ -66,15 +69,15 @@ impl Domain {
 }
 
 impl FromStr for Domain {
-    type Err = String;
+    type Err = Error;
 
-    fn from_str(s: &str) -> Result<Domain, String> {
+    fn from_str(s: &str) -> Result<Domain, Error> {
         Domain::from_parts(s.split(".").map(String::from).collect())
     }
 }
 
 impl Display for Domain {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         for (i, x) in (&self.0).into_iter().enumerate() {
             if i != 0 {
                 write!(f, ".")?;
@@ -86,18 +89,17 @@ impl Display for Domain {
 }
 
 impl Encoder for Domain {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), dns_coding::Error> {
         if self.0.len() == 0 {
             return 0u8.dns_encode(packet);
         }
 
         let raw_data = self.encode_raw();
         if raw_data.len() <= packet.data().len() {
-            for i in 0..(packet.data().len() - raw_data.len()) {
+            // Pointers can only reference the first 16K of the packet.
+            let max_offset = (packet.data().len() - raw_data.len()).min(0x4000);
+            for i in 0..max_offset {
                 if packet.data()[i..(i + raw_data.len())] == raw_data[0..raw_data.len()] {
-                    if i >= 0x3f00 {
-                        return Err("pointer address too high".to_owned());
-                    }
                     let ptr_high = ((i & 0x3f00) >> 8) as u8;
                     let ptr_low = (i & 0xff) as u8;
                     (0xc0u8 | ptr_high).dns_encode(packet)?;
@@ -114,7 +116,9 @@ impl Encoder for Domain {
 }
 
 impl Decoder for Domain {
-    fn dns_decode(packet: &mut DecPacket) -> Result<Domain, String> {
+    type Error = Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<Domain, Error> {
         let mut parts = Vec::<String>::new();
         loop {
             let size = u8::dns_decode(packet)?;
@@ -128,13 +132,13 @@ impl Decoder for Domain {
                 }
                 return Domain::from_parts(parts);
             } else if size & 0xc0 != 0 {
-                return Err("invalid label length field".to_owned())
+                return Err(Error::InvalidLabelLength)
             } else if size == 0 {
                 return Domain::from_parts(parts);
             } else {
                 match String::from_utf8(packet.read_bytes(size as usize)?) {
                     Ok(s) => parts.push(s),
-                    Err(_) => return Err("invalid UTF-8 label".to_owned())
+                    Err(_) => return Err(Error::InvalidUtf8)
                 }
             }
         }
@@ -166,6 +170,17 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Domain::from_str("foo..com"), Err(Error::EmptyLabel));
+        assert_eq!(Domain::from_str("-foo.com"), Err(Error::LabelHyphen("-foo".to_owned())));
+        assert_eq!(Domain::from_str("f_o.com"), Err(Error::InvalidLabel("f_o".to_owned())));
+        assert_eq!(Domain::dns_decode(&mut DecPacket::new(vec![3, 0x61])),
+            Err(Error::Coding(dns_coding::Error::BufferUnderflow)));
+        assert_eq!(Domain::dns_decode(&mut DecPacket::new(vec![0x40])),
+            Err(Error::InvalidLabelLength));
+    }
+
     #[test]
     fn display_domain() {
         let examples = vec!["zoo-1bar.Aol9.AOE", "play.google.com"];
@@ -219,6 +234,34 @@ mod tests {
         assert!(u8::dns_decode(&mut dec_packet).is_err());
     }
 
+    #[test]
+    fn encode_pointers_far() {
+        let mut enc_packet = EncPacket::new();
+        for _ in 0..0x4000 {
+            13u8.dns_encode(&mut enc_packet).unwrap();
+        }
+        let domain = Domain::from_str("bar.apple.com").unwrap();
+        domain.dns_encode(&mut enc_packet).unwrap();
+        domain.dns_encode(&mut enc_packet).unwrap();
+        assert_eq!(enc_packet.data().len(), 0x4000 + 15 + 15);
+    }
+
+    #[test]
+    fn encode_pointers_high() {
+        let mut enc_packet = EncPacket::new();
+        for _ in 0..0x3ff0 {
+            13u8.dns_encode(&mut enc_packet).unwrap();
+        }
+        let domain = Domain::from_str("bar.apple.com").unwrap();
+        domain.dns_encode(&mut enc_packet).unwrap();
+        domain.dns_encode(&mut enc_packet).unwrap();
+        assert_eq!(&enc_packet.data()[(0x3ff0 + 15)..], &[3, b'b', b'a', b'r', 0xff, 0xf4]);
+
+        let mut dec_packet = DecPacket::new(enc_packet.data().clone());
+        dec_packet.read_bytes(0x3ff0 + 15).unwrap();
+        assert_eq!(Domain::dns_decode(&mut dec_packet).unwrap(), domain);
+    }
+
     #[test]
     fn encode_pointers_start() {
         let mut enc_packet = EncPacket::new();
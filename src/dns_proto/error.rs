@@ -0,0 +1,67 @@
+use std::error;
+use std::fmt::{Display, Formatter};
+use std::fmt;
+
+use dns_coding;
+
+/// An error from parsing or decoding a DNS message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The binary data could not be decoded.
+    Coding(dns_coding::Error),
+
+    /// A domain name contained an empty label.
+    EmptyLabel,
+
+    /// A domain label contained a character other than a-z, A-Z, 0-9 or -.
+    InvalidLabel(String),
+
+    /// A domain label started or ended with a hyphen.
+    LabelHyphen(String),
+
+    /// A domain label was longer than 63 bytes.
+    LabelTooLong(String),
+
+    /// A domain name was longer than 255 bytes.
+    DomainTooLong,
+
+    /// An encoded domain label had a reserved length prefix.
+    InvalidLabelLength,
+
+    /// An encoded domain label was not valid UTF-8.
+    InvalidUtf8
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Error::Coding(ref err) => write!(f, "{}", err),
+            Error::EmptyLabel => write!(f, "empty domain name label"),
+            Error::InvalidLabel(ref label) => {
+                write!(f, "domain label may only contain a-zA-Z0-9-: {}", label)
+            },
+            Error::LabelHyphen(ref label) => {
+                write!(f, "domain label may not end or start with -: {}", label)
+            },
+            Error::LabelTooLong(ref label) => write!(f, "domain label is too long: {}", label),
+            Error::DomainTooLong => write!(f, "domain name is too long"),
+            Error::InvalidLabelLength => write!(f, "invalid label length field"),
+            Error::InvalidUtf8 => write!(f, "invalid UTF-8 label")
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Coding(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<dns_coding::Error> for Error {
+    fn from(err: dns_coding::Error) -> Error {
+        Error::Coding(err)
+    }
+}
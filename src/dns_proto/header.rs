@@ -1,3 +1,4 @@
+use dns_coding;
 use dns_coding::{Decoder, DecPacket, BitReader, Encoder, EncPacket, BitWriter};
 
 #[derive(PartialEq, Clone, Copy, Debug)]
@@ -46,7 +47,7 @@ pub struct Header {
 }
 
 impl Encoder for Header {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), dns_coding::Error> {
         self.identifier.dns_encode(packet)?;
 
         let mut flags = BitWriter::new();
@@ -67,7 +68,9 @@ impl Encoder for Header {
 }
 
 impl Decoder for Header {
-    fn dns_decode(packet: &mut DecPacket) -> Result<Header, String> {
+    type Error = dns_coding::Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<Header, dns_coding::Error> {
         let identifier = Decoder::dns_decode(packet)?;
 
         let mut flags = BitReader::new(u16::dns_decode(packet)? as usize, 16);
@@ -1,5 +1,7 @@
+use dns_coding;
 use dns_coding::{Encoder, EncPacket, Decoder, DecPacket};
 use super::domain::Domain;
+use super::error::Error;
 use super::header::{Header, Opcode, ResponseCode};
 use super::record::{RecordType, RecordClass, Record};
 
@@ -46,12 +48,12 @@ impl Message {
 }
 
 impl Encoder for Message {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), dns_coding::Error> {
         if self.questions.len() != self.header.question_count as usize ||
             self.answers.len() != self.header.answer_count as usize ||
             self.authorities.len() != self.header.authority_count as usize ||
             self.additional.len() != self.header.additional_count as usize {
-            Err("mismatching length in header and vector".to_owned())
+            Err(dns_coding::Error::LengthMismatch)
         } else {
             encode_all!(packet, self.header, self.questions, self.answers, self.authorities,
                 self.additional)
@@ -60,14 +62,16 @@ impl Encoder for Message {
 }
 
 impl Decoder for Message {
-    fn dns_decode(packet: &mut DecPacket) -> Result<Message, String> {
+    type Error = Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<Message, Error> {
         let header = Header::dns_decode(packet)?;
         let questions = packet.decode_all(header.question_count as usize)?;
         let answers = packet.decode_all(header.answer_count as usize)?;
         let authorities = packet.decode_all(header.authority_count as usize)?;
         let additional = packet.decode_all(header.additional_count as usize)?;
         if packet.remaining() > 0 {
-            Err("trailing data in packet".to_owned())
+            Err(dns_coding::Error::TrailingData.into())
         } else {
             Ok(Message{
                 header: header,
@@ -81,13 +85,15 @@ impl Decoder for Message {
 }
 
 impl Encoder for Question {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), dns_coding::Error> {
         encode_all!(packet, self.domain, self.record_type, self.record_class)
     }
 }
 
 impl Decoder for Question {
-    fn dns_decode(packet: &mut DecPacket) -> Result<Question, String> {
+    type Error = Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<Question, Error> {
         let domain = Decoder::dns_decode(packet)?;
         let record_type = Decoder::dns_decode(packet)?;
         let record_class = Decoder::dns_decode(packet)?;
@@ -1,11 +1,13 @@
 //! Types for representing DNS messages.
 
 mod domain;
+mod error;
 mod header;
 mod record;
 mod message;
 
 pub use self::domain::Domain;
+pub use self::error::Error;
 pub use self::header::{Header, Opcode, ResponseCode};
 pub use self::message::{Message, Question};
 pub use self::record::{Record, RecordBody, RecordClass, RecordHeader, RecordType, SOADetails};
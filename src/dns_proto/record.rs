@@ -1,7 +1,9 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
 
+use dns_coding;
 use dns_coding::{Decoder, DecPacket, Encoder, EncPacket};
 use super::domain::Domain;
+use super::error::Error;
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum RecordType {
@@ -57,7 +59,7 @@ pub struct Record {
 }
 
 impl Encoder for Record {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), dns_coding::Error> {
         encode_all!(packet, self.header.domain, self.header.record_type,
             self.header.record_class, self.header.ttl)?;
         packet.encode_with_length(|packet| {
@@ -76,7 +78,9 @@ impl Encoder for Record {
 }
 
 impl Decoder for Record {
-    fn dns_decode(packet: &mut DecPacket) -> Result<Record, String> {
+    type Error = Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<Record, Error> {
         let domain = Decoder::dns_decode(packet)?;
         let record_type = Decoder::dns_decode(packet)?;
         let record_class = Decoder::dns_decode(packet)?;
@@ -88,7 +92,7 @@ impl Decoder for Record {
                 record_class: record_class,
                 ttl: ttl
             },
-            body: packet.decode_with_length(|packet, len| {
+            body: packet.decode_with_length(|packet, len| -> Result<RecordBody, Error> {
                 Ok(match record_type {
                     RecordType::A => RecordBody::A(From::from(u32::dns_decode(packet)?)),
                     RecordType::AAAA => {
@@ -124,7 +128,7 @@ impl Decoder for Record {
 }
 
 impl Encoder for RecordType {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), dns_coding::Error> {
         (match *self {
             RecordType::A => 1,
             RecordType::NS => 2,
@@ -140,7 +144,7 @@ impl Encoder for RecordType {
 }
 
 impl Encoder for RecordClass {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), dns_coding::Error> {
         (match *self {
             RecordClass::IN => 1,
             RecordClass::Unknown(x) => x
@@ -149,7 +153,9 @@ impl Encoder for RecordClass {
 }
 
 impl Decoder for RecordType {
-    fn dns_decode(packet: &mut DecPacket) -> Result<RecordType, String> {
+    type Error = dns_coding::Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<RecordType, dns_coding::Error> {
         Ok(match u16::dns_decode(packet)? {
             1 => RecordType::A,
             2 => RecordType::NS,
@@ -165,7 +171,9 @@ impl Decoder for RecordType {
 }
 
 impl Decoder for RecordClass {
-    fn dns_decode(packet: &mut DecPacket) -> Result<RecordClass, String> {
+    type Error = dns_coding::Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<RecordClass, dns_coding::Error> {
         Ok(match u16::dns_decode(packet)? {
             1 => RecordClass::IN,
             x => RecordClass::Unknown(x)
@@ -3,6 +3,7 @@
 #[macro_use]
 pub mod dns_coding;
 
+pub mod config;
 pub mod dns_proto;
 pub mod myo_proto;
 pub mod conn;
@@ -0,0 +1,259 @@
+//! APIs for sending best-effort datagrams over a session.
+//!
+//! Unlike the WWR transfer protocol, nothing is acknowledged or
+//! retransmitted. Datagrams that do not fit in one query or response are split
+//! into fragments, and a datagram is lost if any of its fragments are lost.
+
+extern crate rand;
+use self::rand::{Rng, thread_rng};
+
+use std::collections::VecDeque;
+
+use dns_coding;
+use dns_coding::{DecPacket, Decoder, EncPacket, Encoder};
+
+use super::Error;
+
+/// The number of bytes that a fragment adds to its payload.
+pub const FRAGMENT_OVERHEAD: usize = 4;
+
+/// The maximum number of fragments waiting to be sent.
+pub const SEND_BUFFER: usize = 64;
+
+/// The maximum number of datagrams that can be partially received at once.
+pub const REASSEMBLY_SLOTS: usize = 8;
+
+/// A piece of a datagram.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fragment {
+    pub id: u16,
+    pub index: u8,
+    pub count: u8,
+    pub data: Vec<u8>
+}
+
+/// The payload of a datagram query or response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DatagramPacket {
+    pub fragment: Option<Fragment>
+}
+
+/// The state of one end of a datagram session.
+pub struct DatagramState {
+    mtu: usize,
+    next_id: u16,
+    send_queue: VecDeque<Fragment>,
+    partial: VecDeque<Vec<Option<Fragment>>>
+}
+
+impl DatagramState {
+    /// Create a new datagram state.
+    ///
+    /// The MTU is the maximum payload size for outgoing fragments.
+    pub fn new(mtu: usize) -> DatagramState {
+        DatagramState{
+            mtu: mtu,
+            next_id: 0,
+            send_queue: VecDeque::new(),
+            partial: VecDeque::new()
+        }
+    }
+
+    /// Queue a datagram to be sent.
+    ///
+    /// Returns false if the datagram was dropped because it is too large or
+    /// because the send buffer is full.
+    pub fn push_send(&mut self, datagram: Vec<u8>) -> bool {
+        let count = datagram.len().div_ceil(self.mtu);
+        if count == 0 || count > 255 || self.send_queue.len() + count > SEND_BUFFER {
+            return false;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        for (i, data) in datagram.chunks(self.mtu).enumerate() {
+            self.send_queue.push_back(Fragment{
+                id: id,
+                index: i as u8,
+                count: count as u8,
+                data: data.to_vec()
+            });
+        }
+        true
+    }
+
+    /// Get the next fragment to send, if there is one.
+    pub fn next_send(&mut self) -> Option<Fragment> {
+        self.send_queue.pop_front()
+    }
+
+    /// Handle a fragment from the remote end.
+    ///
+    /// Returns a datagram if the fragment completed one.
+    pub fn handle_fragment(&mut self, fragment: Fragment) -> Option<Vec<u8>> {
+        if fragment.index >= fragment.count {
+            return None;
+        } else if fragment.count == 1 {
+            return Some(fragment.data);
+        }
+        let slot = self.partial.iter().position(|pieces| {
+            let first = pieces.iter().filter_map(|x| x.as_ref()).next().unwrap();
+            first.id == fragment.id && first.count == fragment.count
+        });
+        let slot = match slot {
+            Some(slot) => slot,
+            None => {
+                if self.partial.len() == REASSEMBLY_SLOTS {
+                    self.partial.pop_front();
+                }
+                self.partial.push_back(vec![None; fragment.count as usize]);
+                self.partial.len() - 1
+            }
+        };
+        let index = fragment.index as usize;
+        self.partial[slot][index] = Some(fragment);
+        if self.partial[slot].iter().all(|x| x.is_some()) {
+            let pieces = self.partial.remove(slot).unwrap();
+            Some(pieces.into_iter().flat_map(|x| x.unwrap().data).collect())
+        } else {
+            None
+        }
+    }
+}
+
+impl Encoder for Fragment {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), dns_coding::Error> {
+        encode_all!(packet, self.id, self.index, self.count)?;
+        self.data.dns_encode(packet)
+    }
+}
+
+impl Decoder for Fragment {
+    type Error = dns_coding::Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<Fragment, dns_coding::Error> {
+        let id = Decoder::dns_decode(packet)?;
+        let index = Decoder::dns_decode(packet)?;
+        let count = Decoder::dns_decode(packet)?;
+        let remaining = packet.remaining();
+        Ok(Fragment{id: id, index: index, count: count, data: packet.read_bytes(remaining)?})
+    }
+}
+
+impl DatagramPacket {
+    /// Encode the packet as a datagram query.
+    ///
+    /// Returns a tuple (api_code, data), where data is to be encoded in the
+    /// domain name.
+    pub fn encode_query(&self) -> Result<(char, Vec<u8>), dns_coding::Error> {
+        let mut packet = EncPacket::new();
+        // A random nonce keeps resolvers from answering with cached responses.
+        thread_rng().gen::<u32>().dns_encode(&mut packet)?;
+        if let Some(ref fragment) = self.fragment {
+            fragment.dns_encode(&mut packet)?;
+        }
+        Ok(('d', packet.data().clone()))
+    }
+
+    /// Decode a datagram query.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The raw data that was encoded in the domain name.
+    /// * `api_code` - The API code accompanying this query.
+    pub fn decode_query(data: &[u8], api_code: char) -> Result<DatagramPacket, Error> {
+        if api_code != 'd' {
+            return Err(Error::UnknownApiCode(api_code));
+        }
+        let mut packet = DecPacket::new(data.to_vec());
+        u32::dns_decode(&mut packet)?;
+        Ok(DatagramPacket{
+            fragment: if packet.remaining() > 0 {
+                Some(Fragment::dns_decode(&mut packet)?)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Encode the packet for transmission in a DNS response.
+    pub fn encode_response(&self) -> Result<Vec<u8>, dns_coding::Error> {
+        let mut packet = EncPacket::new();
+        if let Some(ref fragment) = self.fragment {
+            1u8.dns_encode(&mut packet)?;
+            fragment.dns_encode(&mut packet)?;
+        } else {
+            0u8.dns_encode(&mut packet)?;
+        }
+        Ok(packet.data().clone())
+    }
+
+    /// Decode the packet from a DNS response.
+    pub fn decode_response(data: &[u8]) -> Result<DatagramPacket, Error> {
+        let mut packet = DecPacket::new(data.to_vec());
+        Ok(DatagramPacket{
+            fragment: if u8::dns_decode(&mut packet)? != 0 {
+                Some(Fragment::dns_decode(&mut packet)?)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragment_datagrams() {
+        let mut sender = DatagramState::new(10);
+        let mut receiver = DatagramState::new(10);
+        let datagrams: Vec<Vec<u8>> = vec![vec![1; 5], vec![2; 10], vec![3; 35]];
+        for datagram in &datagrams {
+            assert!(sender.push_send(datagram.clone()));
+        }
+        assert!(!sender.push_send(Vec::new()));
+        assert!(!sender.push_send(vec![0; 2560]));
+        let mut fragments = Vec::new();
+        while let Some(fragment) = sender.next_send() {
+            assert!(fragment.data.len() <= 10);
+            fragments.push(fragment);
+        }
+        assert_eq!(fragments.len(), 6);
+
+        // Interleave the fragments and lose one of them.
+        let mut received = Vec::new();
+        for &i in &[2, 0, 5, 1, 3] {
+            if let Some(datagram) = receiver.handle_fragment(fragments[i].clone()) {
+                received.push(datagram);
+            }
+        }
+        assert_eq!(received, vec![datagrams[0].clone(), datagrams[1].clone()]);
+        assert_eq!(receiver.handle_fragment(fragments[4].clone()), Some(datagrams[2].clone()));
+    }
+
+    #[test]
+    fn send_buffer_limit() {
+        let mut state = DatagramState::new(1);
+        assert!(state.push_send(vec![0; SEND_BUFFER - 1]));
+        assert!(!state.push_send(vec![0; 2]));
+        assert!(state.push_send(vec![0; 1]));
+        assert!(!state.push_send(vec![0; 1]));
+    }
+
+    #[test]
+    fn packet_encodings() {
+        let packets = vec![
+            DatagramPacket{fragment: None},
+            DatagramPacket{fragment: Some(Fragment{id: 7, index: 1, count: 3, data: vec![1, 2]})}
+        ];
+        for packet in packets {
+            let (api_code, data) = packet.encode_query().unwrap();
+            assert_eq!(DatagramPacket::decode_query(&data, api_code).unwrap(), packet);
+            let data = packet.encode_response().unwrap();
+            assert_eq!(DatagramPacket::decode_response(&data).unwrap(), packet);
+        }
+        assert_eq!(DatagramPacket::decode_query(&[0; 4], 't').unwrap_err(),
+            Error::UnknownApiCode('t'));
+    }
+}
@@ -2,6 +2,7 @@ use std::net::Ipv4Addr;
 
 use dns_proto::{Domain, Message, Record, RecordHeader, RecordType, RecordBody};
 
+use super::Error;
 use super::record_code::get_record_code;
 use super::util::{domain_part_lowercase, is_api_query};
 
@@ -19,9 +20,9 @@ pub fn is_download_gen_query(query: &Message) -> bool {
 }
 
 /// Produce a response message for a domain hash query.
-pub fn domain_hash_response(query: &Message) -> Result<Message, String> {
+pub fn domain_hash_response(query: &Message) -> Result<Message, Error> {
     if !is_domain_hash_query(query) {
-        return Err("not a domain hash query".to_owned());
+        return Err(Error::WrongQueryType);
     }
     let mut result = query.clone();
     let question = &query.questions[0];
@@ -41,14 +42,14 @@ pub fn domain_hash_response(query: &Message) -> Result<Message, String> {
 }
 
 /// Produce a response message for a download generation query.
-pub fn download_gen_response(query: &Message) -> Result<Message, String> {
+pub fn download_gen_response(query: &Message) -> Result<Message, Error> {
     if !is_download_gen_query(query) {
-        return Err("not a download generation query".to_owned());
+        return Err(Error::WrongQueryType);
     }
     let question = &query.questions[0];
     let parsed_query = DownloadGenQuery::from_domain(&question.domain)?;
     let encoder = get_record_code(question.record_type, &parsed_query.encoding)
-        .ok_or("no record code found".to_owned())?;
+        .ok_or(Error::UnknownRecordCode(parsed_query.encoding.clone()))?;
     let mut result = query.clone();
     let encoded = encoder.encode_body(&parsed_query.generated_data())?;
     result.answers.push(Record{
@@ -80,30 +81,33 @@ pub struct DownloadGenQuery {
     pub len: u16,
     pub bias: u8,
     pub coefficient: u8,
-    pub modulus: u8
+    pub modulus: u8,
+    pub nonce: u32
 }
 
 impl DownloadGenQuery {
     /// Decode a `DownloadGenQuery` from a requested domain.
-    pub fn from_domain(domain: &Domain) -> Result<DownloadGenQuery, String> {
-        if domain.parts().len() < 5 {
-            return Err("not enough domain parts".to_owned());
+    pub fn from_domain(domain: &Domain) -> Result<DownloadGenQuery, Error> {
+        if domain.parts().len() < 6 {
+            return Err(Error::NotEnoughLabels);
         }
         let encoding = domain_part_lowercase(&domain.parts()[0]).chars().skip(1).collect();
         let len = domain.parts()[1].parse();
         let bias = domain.parts()[2].parse();
         let coefficient = domain.parts()[3].parse();
         let modulus = domain.parts()[4].parse();
+        let nonce = u32::from_str_radix(&domain.parts()[5], 16);
         if len.is_err() || bias.is_err() || coefficient.is_err() || modulus.is_err() ||
-            *modulus.as_ref().unwrap() < 2 {
-            Err("invalid number in domain".to_owned())
+            nonce.is_err() || *modulus.as_ref().unwrap() < 2 {
+            Err(Error::InvalidNumber)
         } else {
             Ok(DownloadGenQuery{
                 encoding: encoding,
                 len: len.unwrap(),
                 bias: bias.unwrap(),
                 coefficient: coefficient.unwrap(),
-                modulus: modulus.unwrap()
+                modulus: modulus.unwrap(),
+                nonce: nonce.unwrap()
             })
         }
     }
@@ -126,13 +130,14 @@ impl DownloadGenQuery {
     /// * `host` - The root domain name of the server.
     /// * `pad_to_len` - The total number of bytes for the encoded domain name
     ///   to consume. Maximum value is 255.
-    pub fn to_domain(&self, host: &Domain, pad_to_len: usize) -> Result<Domain, String> {
+    pub fn to_domain(&self, host: &Domain, pad_to_len: usize) -> Result<Domain, Error> {
         let mut parts = Vec::new();
         parts.push(format!("f{}", self.encoding));
         parts.push(format!("{}", self.len));
         for x in &[self.bias, self.coefficient, self.modulus] {
             parts.push(format!("{}", *x));
         }
+        parts.push(format!("{:x}", self.nonce));
         let mut total_bytes = host.parts().iter().chain((&parts).into_iter())
             .map(|x| x.len() + 1).sum::<usize>() + 1;
         if total_bytes % 2 != pad_to_len % 2 {
@@ -144,10 +149,10 @@ impl DownloadGenQuery {
             total_bytes += 2;
         }
         if total_bytes > pad_to_len {
-            Err("target length is too short".to_owned())
+            Err(Error::TargetTooShort)
         } else {
             parts.extend(host.parts().to_vec());
-            Domain::from_parts(parts)
+            Ok(Domain::from_parts(parts)?)
         }
     }
 }
@@ -167,21 +172,32 @@ mod tests {
             len: 100,
             bias: 123,
             coefficient: 13,
-            modulus: 178
+            modulus: 178,
+            nonce: 0xbeef
         };
-        assert_eq!(query.to_domain(&("fo.com".parse().unwrap()), 28).unwrap(),
-            "fraw.100.123.13.178.fo.com".parse().unwrap());
-        assert_eq!(query.to_domain(&("fo.com".parse().unwrap()), 30).unwrap(),
-            "fraw.100.123.13.178.x.fo.com".parse().unwrap());
-        assert_eq!(query.to_domain(&("fo.com".parse().unwrap()), 31).unwrap(),
-            "fraw.100.123.13.178.xx.fo.com".parse().unwrap());
         assert_eq!(query.to_domain(&("fo.com".parse().unwrap()), 33).unwrap(),
-            "fraw.100.123.13.178.xx.x.fo.com".parse().unwrap());
-        assert_eq!(query.to_domain(&("fo.bar.com".parse().unwrap()), 32).unwrap(),
-            "fraw.100.123.13.178.fo.bar.com".parse().unwrap());
-        assert_eq!(query.to_domain(&("fo.bar.com".parse().unwrap()), 34).unwrap(),
-            "fraw.100.123.13.178.x.fo.bar.com".parse().unwrap());
+            "fraw.100.123.13.178.beef.fo.com".parse().unwrap());
+        assert_eq!(query.to_domain(&("fo.com".parse().unwrap()), 35).unwrap(),
+            "fraw.100.123.13.178.beef.x.fo.com".parse().unwrap());
+        assert_eq!(query.to_domain(&("fo.com".parse().unwrap()), 36).unwrap(),
+            "fraw.100.123.13.178.beef.xx.fo.com".parse().unwrap());
+        assert_eq!(query.to_domain(&("fo.com".parse().unwrap()), 38).unwrap(),
+            "fraw.100.123.13.178.beef.xx.x.fo.com".parse().unwrap());
+        assert_eq!(query.to_domain(&("fo.bar.com".parse().unwrap()), 37).unwrap(),
+            "fraw.100.123.13.178.beef.fo.bar.com".parse().unwrap());
+        assert_eq!(query.to_domain(&("fo.bar.com".parse().unwrap()), 39).unwrap(),
+            "fraw.100.123.13.178.beef.x.fo.bar.com".parse().unwrap());
         assert!(query.to_domain(&("fo.bar.com".parse().unwrap()), 10).is_err());
-        assert!(query.to_domain(&("fo.bar.com".parse().unwrap()), 33).is_err());
+        assert!(query.to_domain(&("fo.bar.com".parse().unwrap()), 38).is_err());
+    }
+
+    #[test]
+    fn gen_query_from_domain() {
+        let query = DownloadGenQuery::from_domain(&"fraw.100.123.13.178.beef.xx.fo.com".parse()
+            .unwrap()).unwrap();
+        assert_eq!(query.nonce, 0xbeef);
+        assert_eq!(query.modulus, 178);
+        assert_eq!(DownloadGenQuery::from_domain(&"fraw.100.123.13.178.fo.com".parse().unwrap())
+            .unwrap_err(), Error::InvalidNumber);
     }
 }
@@ -0,0 +1,129 @@
+use std::error;
+use std::fmt::{Display, Formatter};
+use std::fmt;
+
+use dns_coding;
+use dns_proto;
+use super::xfer::WwrError;
+
+/// An error from encoding, decoding, or serving a myodine API call.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The binary payload could not be encoded or decoded.
+    Coding(dns_coding::Error),
+
+    /// A DNS-level value, such as a domain name, was invalid.
+    Dns(dns_proto::Error),
+
+    /// A transfer packet was rejected by the WWR state machine.
+    Transfer(WwrError),
+
+    /// The message was not the kind of API call that was expected.
+    WrongQueryType,
+
+    /// The query name was not under the server's root domain.
+    IncorrectHost,
+
+    /// The query name did not have enough labels for the API call.
+    NotEnoughLabels,
+
+    /// The session ID in a transfer query could not be parsed.
+    InvalidSessionId,
+
+    /// A numeric label could not be parsed.
+    InvalidNumber,
+
+    /// Data encoded in a domain name or record was malformed.
+    InvalidData(String),
+
+    /// The requested name encoding is not supported.
+    UnknownNameCode(String),
+
+    /// The requested record encoding is not supported for the record type.
+    UnknownRecordCode(String),
+
+    /// The record had a different type than the record encoding expects.
+    UnexpectedRecordType,
+
+    /// A transfer query had an unrecognized API code.
+    UnknownApiCode(char),
+
+    /// A window size was zero.
+    ZeroWindow,
+
+    /// A range in an acknowledgement extended past the window.
+    AckRangeOutOfBounds,
+
+    /// A range in an acknowledgement started before the end of the one
+    /// before it.
+    AckRangeOutOfOrder,
+
+    /// A domain name could not be padded to the requested length.
+    TargetTooShort,
+
+    /// A multiplexed stream frame had an unrecognized type.
+    UnknownFrameType(u8),
+
+    /// A network was not written in CIDR notation.
+    InvalidNetwork(String),
+
+    /// A network for tunnel addresses had no room for a client, given its
+    /// prefix length.
+    NetworkTooSmall(u8)
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            Error::Coding(ref err) => write!(f, "{}", err),
+            Error::Dns(ref err) => write!(f, "{}", err),
+            Error::Transfer(ref err) => write!(f, "{}", err),
+            Error::WrongQueryType => write!(f, "wrong type of query"),
+            Error::IncorrectHost => write!(f, "incorrect host domain"),
+            Error::NotEnoughLabels => write!(f, "not enough labels"),
+            Error::InvalidSessionId => write!(f, "invalid session ID"),
+            Error::InvalidNumber => write!(f, "invalid number in domain"),
+            Error::InvalidData(ref msg) => write!(f, "invalid data: {}", msg),
+            Error::UnknownNameCode(ref name) => write!(f, "unknown name encoding: {}", name),
+            Error::UnknownRecordCode(ref name) => write!(f, "unknown record encoding: {}", name),
+            Error::UnexpectedRecordType => write!(f, "unexpected record type"),
+            Error::UnknownApiCode(code) => write!(f, "unknown API code: {}", code),
+            Error::ZeroWindow => write!(f, "window size must be non-zero"),
+            Error::AckRangeOutOfBounds => write!(f, "ack range out of bounds"),
+            Error::AckRangeOutOfOrder => write!(f, "ack ranges out of order"),
+            Error::TargetTooShort => write!(f, "target length is too short"),
+            Error::UnknownFrameType(t) => write!(f, "unknown frame type: {}", t),
+            Error::InvalidNetwork(ref s) => write!(f, "invalid network: {}", s),
+            Error::NetworkTooSmall(len) => write!(f, "network /{} is too small", len)
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Coding(ref err) => Some(err),
+            Error::Dns(ref err) => Some(err),
+            Error::Transfer(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<dns_coding::Error> for Error {
+    fn from(err: dns_coding::Error) -> Error {
+        Error::Coding(err)
+    }
+}
+
+impl From<dns_proto::Error> for Error {
+    fn from(err: dns_proto::Error) -> Error {
+        Error::Dns(err)
+    }
+}
+
+impl From<WwrError> for Error {
+    fn from(err: WwrError) -> Error {
+        Error::Transfer(err)
+    }
+}
@@ -1,9 +1,12 @@
 extern crate sha1;
 use self::sha1::Sha1;
 
+use dns_coding;
 use dns_coding::{DecPacket, Decoder, EncPacket, Encoder, dns_encode};
 use dns_proto::{Domain, Message, Record, RecordHeader};
 
+use super::Error;
+use super::ip::IpLease;
 use super::record_code::{get_record_code};
 use super::util::{is_api_query, domain_ends_with, domain_part_lowercase};
 
@@ -23,11 +26,11 @@ pub fn establish_response(
     query: &Message,
     host: &Domain,
     resp: EstablishResponse
-) -> Result<Message, String> {
+) -> Result<Message, Error> {
     let equery = EstablishQuery::from_query(query, host)?;
     let question = &query.questions[0];
     let code = get_record_code(question.record_type, &equery.response_encoding)
-        .ok_or("no response encoding".to_owned())?;
+        .ok_or(Error::UnknownRecordCode(equery.response_encoding.clone()))?;
     let body = code.encode_body(&dns_encode(&resp)?)?;
     let mut result = query.clone();
     result.answers.push(Record{
@@ -63,7 +66,11 @@ pub struct EstablishQuery {
     pub name_encoding: String,
     pub query_window: u16,
     pub response_window: u16,
+    pub ack_encoding: String,
     pub proof: u64,
+    pub nonce: u32,
+    pub user: Option<String>,
+    pub channel: String,
     pub port: u16,
     pub host: Domain
 }
@@ -75,19 +82,19 @@ impl EstablishQuery {
     ///
     /// * `query` - The query to decode.
     /// * `host` - The root domain name of the server.
-    pub fn from_query(query: &Message, host: &Domain) -> Result<EstablishQuery, String> {
+    pub fn from_query(query: &Message, host: &Domain) -> Result<EstablishQuery, Error> {
         if !is_establish_query(query) {
-            return Err("not an establish query".to_owned());
+            return Err(Error::WrongQueryType);
         }
         EstablishQuery::from_domain(&query.questions[0].domain, host)
     }
 
-    fn from_domain(domain: &Domain, host: &Domain) -> Result<EstablishQuery, String> {
+    fn from_domain(domain: &Domain, host: &Domain) -> Result<EstablishQuery, Error> {
         if !domain_ends_with(domain, host) {
-            return Err("incorrect host domain".to_owned());
+            return Err(Error::IncorrectHost);
         }
-        if domain.parts().len() - host.parts().len() < 8 {
-            return Err("not enough labels".to_owned());
+        if domain.parts().len() - host.parts().len() < 12 {
+            return Err(Error::NotEnoughLabels);
         }
         let response_encoding = domain_part_lowercase(&domain.parts()[0])
             .chars().skip(1).collect();
@@ -95,12 +102,20 @@ impl EstablishQuery {
         let name_encoding = domain_part_lowercase(&domain.parts()[2]);
         let query_window = domain.parts()[3].parse();
         let response_window = domain.parts()[4].parse();
-        let proof = u64::from_str_radix(&domain.parts()[5], 16);
-        let port = domain.parts()[6].parse();
-        let host = &domain.parts()[7..(domain.parts().len() - host.parts().len())];
+        let ack_encoding = domain_part_lowercase(&domain.parts()[5]);
+        let proof = u64::from_str_radix(&domain.parts()[6], 16);
+        let nonce = u32::from_str_radix(&domain.parts()[7], 16);
+        let user_label = domain_part_lowercase(&domain.parts()[8]);
+        if !user_label.starts_with('u') {
+            return Err(Error::InvalidData(format!("bad user label: {}", user_label)));
+        }
+        let user = if user_label.len() > 1 { Some(user_label[1..].to_owned()) } else { None };
+        let channel = domain_part_lowercase(&domain.parts()[9]);
+        let port = domain.parts()[10].parse();
+        let host = &domain.parts()[11..(domain.parts().len() - host.parts().len())];
         if mtu.is_err() || query_window.is_err() || response_window.is_err() || proof.is_err() ||
-            port.is_err() {
-            Err("invalid number in domain".to_owned())
+            nonce.is_err() || port.is_err() {
+            Err(Error::InvalidNumber)
         } else {
             Ok(EstablishQuery{
                 response_encoding: response_encoding,
@@ -108,7 +123,11 @@ impl EstablishQuery {
                 name_encoding: name_encoding,
                 query_window: query_window.unwrap(),
                 response_window: response_window.unwrap(),
+                ack_encoding: ack_encoding,
                 proof: proof.unwrap(),
+                nonce: nonce.unwrap(),
+                user: user,
+                channel: channel,
                 port: port.unwrap(),
                 host: Domain::from_parts(host.to_vec())?
             })
@@ -117,25 +136,29 @@ impl EstablishQuery {
 
     /// Encode the request into a domain name, given the root domain name of the
     /// server, `host`.
-    pub fn to_domain(&self, host: &Domain) -> Result<Domain, String> {
+    pub fn to_domain(&self, host: &Domain) -> Result<Domain, Error> {
         let mut parts = Vec::new();
         parts.push(format!("e{}", self.response_encoding));
         macro_rules! push_fmt {
             ( $($x:expr),* ) => { { $(parts.push(format!("{}", $x));)* } }
         }
-        push_fmt!(self.mtu, self.name_encoding, self.query_window, self.response_window);
+        push_fmt!(self.mtu, self.name_encoding, self.query_window, self.response_window,
+            self.ack_encoding);
         parts.push(format!("{:x}", self.proof));
-        push_fmt!(self.port);
+        parts.push(format!("{:x}", self.nonce));
+        parts.push(format!("u{}", self.user.as_deref().unwrap_or("")));
+        push_fmt!(self.channel, self.port);
         parts.extend(self.host.parts().to_vec());
         parts.extend(host.parts().to_vec());
-        Domain::from_parts(parts)
+        Ok(Domain::from_parts(parts)?)
     }
 
     /// Check the password proof in the query.
     ///
     /// # Arguments
     ///
-    /// * `password` - The correct password.
+    /// * `password` - The correct password, which is the user's secret if
+    ///   the query names a user.
     /// * `cur_time` - The current epoch time, in seconds.
     /// * `window` - The number of seconds by which the client's clock is allowed
     ///   to be off from `cur_time`. The higher this value, the slower the check.
@@ -150,15 +173,18 @@ impl EstablishQuery {
 }
 
 /// A response to an establishment query.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum EstablishResponse {
     Success{id: u16, seq: u32},
     Failure(String),
+    IpSuccess{id: u16, seq: u32, lease: IpLease},
     Unknown(u8)
 }
 
 impl Decoder for EstablishResponse {
-    fn dns_decode(packet: &mut DecPacket) -> Result<EstablishResponse, String> {
+    type Error = dns_coding::Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<EstablishResponse, dns_coding::Error> {
         Ok(match u8::dns_decode(packet)? {
             0 => {
                 let session_id = Decoder::dns_decode(packet)?;
@@ -170,6 +196,12 @@ impl Decoder for EstablishResponse {
                 let raw = packet.read_bytes(size)?;
                 EstablishResponse::Failure(String::from(String::from_utf8_lossy(&raw)))
             },
+            2 => {
+                let session_id = Decoder::dns_decode(packet)?;
+                let seq_num = Decoder::dns_decode(packet)?;
+                let lease = IpLease::dns_decode(packet)?;
+                EstablishResponse::IpSuccess{id: session_id, seq: seq_num, lease: lease}
+            },
             x => {
                 let size = packet.remaining();
                 packet.read_bytes(size)?;
@@ -180,7 +212,7 @@ impl Decoder for EstablishResponse {
 }
 
 impl Encoder for EstablishResponse {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), dns_coding::Error> {
         match self {
             &EstablishResponse::Success{id: ref session_id, seq: ref seq_num} => {
                 0u8.dns_encode(packet)?;
@@ -191,8 +223,14 @@ impl Encoder for EstablishResponse {
                 1u8.dns_encode(packet)?;
                 message.as_bytes().to_vec().dns_encode(packet)
             },
+            &EstablishResponse::IpSuccess{id: ref session_id, seq: ref seq_num, ref lease} => {
+                2u8.dns_encode(packet)?;
+                session_id.dns_encode(packet)?;
+                seq_num.dns_encode(packet)?;
+                lease.dns_encode(packet)
+            },
             &EstablishResponse::Unknown(_) => {
-                Err("cannot encode unknown establish response".to_owned())
+                Err(dns_coding::Error::Unencodable("unknown establish response"))
             }
         }
     }
@@ -201,6 +239,7 @@ impl Encoder for EstablishResponse {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use dns_coding::dns_decode;
 
     #[test]
     fn query_encoding() {
@@ -210,19 +249,25 @@ mod tests {
             name_encoding: "b64".to_owned(),
             query_window: 64,
             response_window: 32,
+            ack_encoding: "sack".to_owned(),
             proof: 0x913379,
+            nonce: 0xbeef,
+            user: Some("alice".to_owned()),
+            channel: "mux".to_owned(),
             port: 1337,
             host: "foo.bob.com".parse().unwrap()
         };
         let encoded = query.to_domain(&"baz.proxy.com".parse().unwrap()).unwrap();
-        let expected = "eraw.123.b64.64.32.913379.1337.foo.bob.com.baz.proxy.com";
+        let expected =
+            "eraw.123.b64.64.32.sack.913379.beef.ualice.mux.1337.foo.bob.com.baz.proxy.com";
         assert_eq!(expected.parse::<Domain>().unwrap(), encoded);
     }
 
     #[test]
     fn query_decoding() {
         let query = EstablishQuery::from_domain(
-            &"eraw.123.b64.64.32.913379.1337.foo.bob.com.baz.proxy.com".parse().unwrap(),
+            &"eraw.123.b64.64.32.sack.913379.beef.uAlice.mux.1337.foo.bob.com.baz.proxy.com"
+                .parse().unwrap(),
             &"baz.proxy.com".parse().unwrap()
         ).unwrap();
         assert_eq!(query, EstablishQuery{
@@ -231,9 +276,49 @@ mod tests {
             name_encoding: "b64".to_owned(),
             query_window: 64,
             response_window: 32,
+            ack_encoding: "sack".to_owned(),
             proof: 0x913379,
+            nonce: 0xbeef,
+            user: Some("alice".to_owned()),
+            channel: "mux".to_owned(),
             port: 1337,
             host: "foo.bob.com".parse().unwrap()
         });
+        let anonymous = EstablishQuery::from_domain(
+            &"eraw.123.b64.64.32.sack.913379.beef.u.mux.1337.foo.baz.proxy.com".parse().unwrap(),
+            &"baz.proxy.com".parse().unwrap()
+        ).unwrap();
+        assert_eq!(anonymous.user, None);
+    }
+
+    #[test]
+    fn query_decoding_errors() {
+        let host = "baz.proxy.com".parse().unwrap();
+        let decode = |s: &str| EstablishQuery::from_domain(&s.parse().unwrap(), &host);
+        assert_eq!(decode("eraw.123.b64.64.32.sack.913379.beef.u.mux.1337.foo.proxy.com"),
+            Err(Error::IncorrectHost));
+        assert_eq!(decode("eraw.123.b64.64.32.sack.913379.beef.u.mux.baz.proxy.com"),
+            Err(Error::NotEnoughLabels));
+        assert_eq!(decode("eraw.123.b64.64.32.sack.913379.xyz.u.mux.1337.foo.baz.proxy.com"),
+            Err(Error::InvalidNumber));
+        assert_eq!(decode("eraw.123.b64.64.32.sack.913379.beef.x.mux.1337.foo.baz.proxy.com"),
+            Err(Error::InvalidData("bad user label: x".to_owned())));
+    }
+
+    #[test]
+    fn response_encodings() {
+        let responses = vec![
+            EstablishResponse::Success{id: 3, seq: 0xdeadbeef},
+            EstablishResponse::Failure("invalid proof".to_owned()),
+            EstablishResponse::IpSuccess{id: 7, seq: 12, lease: IpLease{
+                addr: "10.53.0.2".parse().unwrap(),
+                gateway: "10.53.0.1".parse().unwrap(),
+                prefix_len: 24
+            }}
+        ];
+        for response in responses {
+            let data = dns_encode(&response).unwrap();
+            assert_eq!(dns_decode::<EstablishResponse>(data).unwrap(), response);
+        }
     }
 }
@@ -0,0 +1,271 @@
+use std::cmp::min;
+use std::io;
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{Receiver, SyncSender, TryRecvError, TrySendError, sync_channel};
+use std::thread::spawn;
+
+use conn::{Chunker, Interrupt, SplitStream};
+
+/// The number of packets buffered in each direction.
+pub const PACKET_BUFFER: usize = 64;
+
+/// The largest packet that can be framed.
+pub const MAX_PACKET: usize = 0xffff;
+
+/// A `Chunker` that carries IP packets instead of a byte stream.
+///
+/// Each packet is framed with a 16-bit length, so packets may be split across
+/// chunks. Like a real network, packets are dropped if they cannot be
+/// delivered right away.
+pub struct PacketChunker {
+    incoming: Receiver<Vec<u8>>,
+    outgoing: SyncSender<Vec<u8>>,
+    interrupt: Option<Interrupt>,
+    recv_mtu: usize,
+    source_addr: Option<Ipv4Addr>,
+    recv_buffer: Vec<u8>,
+    send_buffer: Vec<u8>,
+    recv_eof: bool,
+    recv_eof_reported: bool
+}
+
+impl PacketChunker {
+    /// Create a chunker from packet channels.
+    ///
+    /// # Arguments
+    ///
+    /// * `incoming` - Local packets to send to the remote end.
+    /// * `outgoing` - Packets received from the remote end.
+    /// * `recv_mtu` - The maximum incoming chunk size.
+    pub fn new(
+        incoming: Receiver<Vec<u8>>,
+        outgoing: SyncSender<Vec<u8>>,
+        recv_mtu: usize
+    ) -> PacketChunker {
+        PacketChunker{
+            incoming: incoming,
+            outgoing: outgoing,
+            interrupt: None,
+            recv_mtu: recv_mtu,
+            source_addr: None,
+            recv_buffer: Vec::new(),
+            send_buffer: Vec::new(),
+            recv_eof: false,
+            recv_eof_reported: false
+        }
+    }
+
+    /// Create a chunker along with the other ends of its packet channels.
+    ///
+    /// Returns the chunker, a sender for local packets, and a receiver for
+    /// packets from the remote end.
+    pub fn pipe(recv_mtu: usize) -> (PacketChunker, SyncSender<Vec<u8>>, Receiver<Vec<u8>>) {
+        let (in_sender, in_receiver) = sync_channel(PACKET_BUFFER);
+        let (out_sender, out_receiver) = sync_channel(PACKET_BUFFER);
+        (PacketChunker::new(in_receiver, out_sender, recv_mtu), in_sender, out_receiver)
+    }
+
+    /// Create a chunker for a packet device, such as a tun device.
+    ///
+    /// Every read from the device must produce exactly one packet, and every
+    /// write to the device is exactly one packet.
+    pub fn from_device<S: SplitStream>(device: S, recv_mtu: usize) -> io::Result<PacketChunker> {
+        let (mut reader, mut writer, interrupt) = device.split()?;
+        let (mut chunker, local_packets, remote_packets) = PacketChunker::pipe(recv_mtu);
+        chunker.interrupt = Some(interrupt);
+        spawn(move || {
+            let mut buffer = vec![0u8; MAX_PACKET];
+            loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(size) => {
+                        if is_disconnected(local_packets.try_send(buffer[0..size].to_vec())) {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        spawn(move || {
+            for packet in remote_packets {
+                if writer.write(&packet).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(chunker)
+    }
+
+    /// Only pass on IPv4 packets from the remote end whose source address is
+    /// `addr`, so that a client cannot send packets as someone else.
+    pub fn set_source_addr(&mut self, addr: Ipv4Addr) {
+        self.source_addr = Some(addr);
+    }
+
+    fn fill_recv_buffer(&mut self) {
+        while self.recv_buffer.len() < self.recv_mtu && !self.recv_eof {
+            match self.incoming.try_recv() {
+                Ok(packet) => {
+                    if !packet.is_empty() && packet.len() <= MAX_PACKET {
+                        self.recv_buffer.push((packet.len() >> 8) as u8);
+                        self.recv_buffer.push(packet.len() as u8);
+                        self.recv_buffer.extend(packet);
+                    }
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => self.recv_eof = true
+            }
+        }
+    }
+}
+
+impl Chunker for PacketChunker {
+    fn can_send(&mut self) -> bool {
+        true
+    }
+
+    fn send(&mut self, chunk: Vec<u8>) {
+        self.send_buffer.extend(chunk);
+        while self.send_buffer.len() >= 2 {
+            let size = ((self.send_buffer[0] as usize) << 8) | (self.send_buffer[1] as usize);
+            if self.send_buffer.len() < size + 2 {
+                break;
+            }
+            let packet: Vec<u8> = self.send_buffer.drain(0..(size + 2)).skip(2).collect();
+            if self.source_addr.is_some() && source(&packet) != self.source_addr {
+                continue;
+            }
+            // Drop the packet if the device is not keeping up.
+            self.outgoing.try_send(packet).ok();
+        }
+    }
+
+    fn send_finished(&mut self) {
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.fill_recv_buffer();
+        if !self.recv_buffer.is_empty() {
+            let size = min(self.recv_buffer.len(), self.recv_mtu);
+            Some(self.recv_buffer.drain(0..size).collect())
+        } else if self.recv_eof && !self.recv_eof_reported {
+            self.recv_eof_reported = true;
+            Some(Vec::new())
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for PacketChunker {
+    fn drop(&mut self) {
+        if let Some(ref mut interrupt) = self.interrupt {
+            interrupt();
+        }
+    }
+}
+
+/// Get the source address of an IPv4 packet.
+fn source(packet: &[u8]) -> Option<Ipv4Addr> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        None
+    } else {
+        Some(Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]))
+    }
+}
+
+fn is_disconnected<T>(result: Result<(), TrySendError<T>>) -> bool {
+    matches!(result, Err(TrySendError::Disconnected(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use conn::{IoPair, WriteHalf, pipe};
+    use myo_proto::xfer::{WwrState, handle_packet_in, next_packet_out};
+
+    #[test]
+    fn packet_framing() {
+        let (mut client, client_in, _) = PacketChunker::pipe(7);
+        let (mut server, _, server_out) = PacketChunker::pipe(7);
+        let packets = vec![vec![1, 2, 3], vec![4; 20], vec![5]];
+        for packet in &packets {
+            client_in.send(packet.clone()).unwrap();
+        }
+        while let Some(chunk) = client.recv() {
+            assert!(chunk.len() <= 7);
+            server.send(chunk);
+        }
+        assert_eq!(server_out.try_iter().collect::<Vec<_>>(), packets);
+
+        drop(client_in);
+        assert_eq!(client.recv(), Some(Vec::new()));
+        assert_eq!(client.recv(), None);
+    }
+
+    #[test]
+    fn wwr_transfer() {
+        let (mut client, client_in, client_out) = PacketChunker::pipe(50);
+        let (mut server, server_in, server_out) = PacketChunker::pipe(50);
+        let mut client_state = WwrState::new(4, 4, 1337);
+        let mut server_state = WwrState::new(4, 4, 1337);
+        client_state.set_retransmit_timeout(Duration::from_millis(0));
+        server_state.set_retransmit_timeout(Duration::from_millis(0));
+
+        let to_server: Vec<Vec<u8>> = (0..30).map(|i| vec![i as u8; 1 + i * 7]).collect();
+        let to_client: Vec<Vec<u8>> = (0..30).map(|i| vec![i as u8; 200 - i * 3]).collect();
+        let mut received_server = Vec::new();
+        let mut received_client = Vec::new();
+        for i in 0..1000 {
+            if i < to_server.len() {
+                client_in.send(to_server[i].clone()).unwrap();
+                server_in.send(to_client[i].clone()).unwrap();
+            }
+            let (packet, _) = next_packet_out(&mut client_state, &mut client);
+            // Lose some of the packets to exercise retransmission.
+            if i % 3 != 0 {
+                handle_packet_in(packet, &mut server_state, &mut server).ok();
+            }
+            let (packet, _) = next_packet_out(&mut server_state, &mut server);
+            if i % 5 != 0 {
+                handle_packet_in(packet, &mut client_state, &mut client).ok();
+            }
+            received_server.extend(server_out.try_iter());
+            received_client.extend(client_out.try_iter());
+        }
+        assert_eq!(received_server, to_server);
+        assert_eq!(received_client, to_client);
+    }
+
+    #[test]
+    fn packet_device() {
+        let (mut device_writer, device_reader) = pipe();
+        let (chunker_writer, mut chunker_reader) = pipe();
+        let device = IoPair{reader: device_reader, writer: chunker_writer};
+        let mut chunker = PacketChunker::from_device(device, 100).unwrap();
+
+        device_writer.write_all(&[1, 2, 3]).unwrap();
+        device_writer.close_write();
+        let mut data = Vec::new();
+        loop {
+            match chunker.recv() {
+                Some(ref chunk) if chunk.is_empty() => break,
+                Some(chunk) => data.extend(chunk),
+                None => sleep(Duration::from_millis(10))
+            }
+        }
+        assert_eq!(data, vec![0, 3, 1, 2, 3]);
+
+        chunker.send(vec![0, 2, 9, 8, 0]);
+        chunker.send(vec![1, 7]);
+        let mut packet = vec![0u8; 10];
+        assert_eq!(chunker_reader.read(&mut packet).unwrap(), 2);
+        assert_eq!(&packet[0..2], &[9, 8]);
+        assert_eq!(chunker_reader.read(&mut packet).unwrap(), 1);
+        assert_eq!(packet[0], 7);
+    }
+}
@@ -0,0 +1,9 @@
+//! APIs for tunneling IP packets over a session.
+
+mod chunker;
+mod pool;
+mod router;
+
+pub use self::chunker::{MAX_PACKET, PACKET_BUFFER, PacketChunker};
+pub use self::pool::{AddressPool, IpLease};
+pub use self::router::{Lease, Router};
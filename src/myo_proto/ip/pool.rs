@@ -0,0 +1,157 @@
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use dns_coding;
+use dns_coding::{DecPacket, Decoder, EncPacket, Encoder};
+use myo_proto::Error;
+
+/// The addresses assigned to the two ends of an IP tunnel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpLease {
+    /// The address of the client.
+    pub addr: Ipv4Addr,
+
+    /// The address of the server, which routes the client's packets.
+    pub gateway: Ipv4Addr,
+
+    /// The prefix length of the tunnel's network.
+    pub prefix_len: u8
+}
+
+impl Encoder for IpLease {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), dns_coding::Error> {
+        u32::from(self.addr).dns_encode(packet)?;
+        u32::from(self.gateway).dns_encode(packet)?;
+        self.prefix_len.dns_encode(packet)
+    }
+}
+
+impl Decoder for IpLease {
+    type Error = dns_coding::Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<IpLease, dns_coding::Error> {
+        Ok(IpLease{
+            addr: From::from(u32::dns_decode(packet)?),
+            gateway: From::from(u32::dns_decode(packet)?),
+            prefix_len: u8::dns_decode(packet)?
+        })
+    }
+}
+
+/// A network from which tunnel addresses are handed out.
+///
+/// The first host address belongs to the server, and the rest are assigned
+/// to clients.
+#[derive(Clone, Debug)]
+pub struct AddressPool {
+    network: u32,
+    prefix_len: u8,
+    used: HashSet<u32>
+}
+
+impl AddressPool {
+    /// Create a pool for a network.
+    ///
+    /// The prefix length must leave room for at least two hosts.
+    pub fn new(network: Ipv4Addr, prefix_len: u8) -> Result<AddressPool, Error> {
+        if prefix_len > 30 {
+            return Err(Error::NetworkTooSmall(prefix_len));
+        }
+        let mask = if prefix_len == 0 { 0 } else { !0u32 << (32 - prefix_len as u32) };
+        Ok(AddressPool{
+            network: u32::from(network) & mask,
+            prefix_len: prefix_len,
+            used: HashSet::new()
+        })
+    }
+
+    /// Get the server's address.
+    pub fn gateway(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.network + 1)
+    }
+
+    /// Get the prefix length of the network.
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Assign an unused address to a client.
+    pub fn allocate(&mut self) -> Option<IpLease> {
+        let num_addrs = 1u64 << (32 - self.prefix_len as u64);
+        for offset in 2..(num_addrs - 1) {
+            let addr = self.network + offset as u32;
+            if self.used.insert(addr) {
+                return Some(IpLease{
+                    addr: Ipv4Addr::from(addr),
+                    gateway: self.gateway(),
+                    prefix_len: self.prefix_len
+                });
+            }
+        }
+        None
+    }
+
+    /// Make an address available again.
+    pub fn release(&mut self, addr: Ipv4Addr) {
+        self.used.remove(&u32::from(addr));
+    }
+}
+
+impl FromStr for AddressPool {
+    type Err = Error;
+
+    /// Parse a network in CIDR notation, such as "10.53.0.0/24".
+    fn from_str(s: &str) -> Result<AddressPool, Error> {
+        let mut parts = s.splitn(2, '/');
+        let network = parts.next().unwrap_or("").parse()
+            .map_err(|_| Error::InvalidNetwork(s.to_owned()))?;
+        let prefix_len = parts.next().and_then(|x| x.parse().ok())
+            .ok_or_else(|| Error::InvalidNetwork(s.to_owned()))?;
+        AddressPool::new(network, prefix_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns_coding::{dns_decode, dns_encode};
+
+    #[test]
+    fn allocate_addresses() {
+        let mut pool: AddressPool = "10.53.0.7/30".parse().unwrap();
+        assert_eq!(pool.gateway(), Ipv4Addr::new(10, 53, 0, 5));
+        let lease = pool.allocate().unwrap();
+        assert_eq!(lease, IpLease{
+            addr: Ipv4Addr::new(10, 53, 0, 6),
+            gateway: Ipv4Addr::new(10, 53, 0, 5),
+            prefix_len: 30
+        });
+        assert_eq!(pool.allocate(), None);
+        pool.release(lease.addr);
+        assert_eq!(pool.allocate(), Some(lease));
+    }
+
+    #[test]
+    fn bad_networks() {
+        assert_eq!("10.53.0.0/31".parse::<AddressPool>().unwrap_err(), Error::NetworkTooSmall(31));
+        assert_eq!("10.53.0.0".parse::<AddressPool>().unwrap_err(),
+            Error::InvalidNetwork("10.53.0.0".to_owned()));
+        assert_eq!("10.53.0/24".parse::<AddressPool>().unwrap_err(),
+            Error::InvalidNetwork("10.53.0/24".to_owned()));
+        assert_eq!("10.53.0.0/x".parse::<AddressPool>().unwrap_err(),
+            Error::InvalidNetwork("10.53.0.0/x".to_owned()));
+    }
+
+    #[test]
+    fn lease_encoding() {
+        let lease = IpLease{
+            addr: Ipv4Addr::new(10, 53, 0, 2),
+            gateway: Ipv4Addr::new(10, 53, 0, 1),
+            prefix_len: 24
+        };
+        let data = dns_encode(&lease).unwrap();
+        assert_eq!(data, vec![10, 53, 0, 2, 10, 53, 0, 1, 24]);
+        assert_eq!(dns_decode::<IpLease>(data).unwrap(), lease);
+    }
+}
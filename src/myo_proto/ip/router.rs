@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{SyncSender, sync_channel};
+use std::thread::spawn;
+
+use conn::SplitStream;
+use super::chunker::{MAX_PACKET, PACKET_BUFFER, PacketChunker};
+use super::pool::{AddressPool, IpLease};
+
+/// Connects the IP tunnels of many clients to a single packet device.
+///
+/// Packets from the device are delivered to the client whose address they
+/// are destined for, and packets from every client are written to the
+/// device.
+pub struct Router {
+    routes: Arc<Mutex<Routes>>,
+    to_device: SyncSender<Vec<u8>>
+}
+
+struct Routes {
+    pool: AddressPool,
+    clients: HashMap<Ipv4Addr, SyncSender<Vec<u8>>>
+}
+
+/// A client's claim on an address.
+///
+/// The address is released once the lease is dropped.
+pub struct Lease {
+    info: IpLease,
+    routes: Arc<Mutex<Routes>>
+}
+
+impl Router {
+    /// Start routing packets for a packet device, such as a tun device.
+    ///
+    /// Every read from the device must produce exactly one packet, and every
+    /// write to the device is exactly one packet.
+    pub fn new<S: SplitStream>(device: S, pool: AddressPool) -> io::Result<Router> {
+        let (mut reader, mut writer, _) = device.split()?;
+        let routes = Arc::new(Mutex::new(Routes{pool: pool, clients: HashMap::new()}));
+        let (to_device, from_clients) = sync_channel::<Vec<u8>>(PACKET_BUFFER);
+        let read_routes = routes.clone();
+        spawn(move || {
+            let mut buffer = vec![0u8; MAX_PACKET];
+            loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(size) => {
+                        let packet = &buffer[0..size];
+                        if let Some(dest) = destination(packet) {
+                            let routes = read_routes.lock().unwrap();
+                            if let Some(client) = routes.clients.get(&dest) {
+                                client.try_send(packet.to_vec()).ok();
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        spawn(move || {
+            for packet in from_clients {
+                if writer.write(&packet).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Router{routes: routes, to_device: to_device})
+    }
+
+    /// Assign an address to a new client.
+    ///
+    /// Returns the lease and a chunker for the client's tunnel, or None if the
+    /// address pool is exhausted. The chunker drops packets from the client
+    /// that do not come from the leased address.
+    pub fn attach(&self, recv_mtu: usize) -> Option<(Lease, PacketChunker)> {
+        let mut routes = self.routes.lock().unwrap();
+        let info = routes.pool.allocate()?;
+        let (sender, receiver) = sync_channel(PACKET_BUFFER);
+        routes.clients.insert(info.addr, sender);
+        let mut chunker = PacketChunker::new(receiver, self.to_device.clone(), recv_mtu);
+        chunker.set_source_addr(info.addr);
+        Some((Lease{info: info, routes: self.routes.clone()}, chunker))
+    }
+}
+
+impl Lease {
+    /// Get the addresses for the client's tunnel.
+    pub fn info(&self) -> &IpLease {
+        &self.info
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut routes = self.routes.lock().unwrap();
+        routes.clients.remove(&self.info.addr);
+        routes.pool.release(self.info.addr);
+    }
+}
+
+/// Get the destination address of an IPv4 packet.
+fn destination(packet: &[u8]) -> Option<Ipv4Addr> {
+    if packet.len() < 20 || packet[0] >> 4 != 4 {
+        None
+    } else {
+        Some(Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use conn::{Chunker, IoPair, pipe};
+
+    #[test]
+    fn route_packets() {
+        let (mut device_writer, device_reader) = pipe();
+        let (router_writer, mut router_reader) = pipe();
+        let device = IoPair{reader: device_reader, writer: router_writer};
+        let router = Router::new(device, "10.53.0.0/29".parse().unwrap()).unwrap();
+
+        let (lease1, mut client1) = router.attach(1000).unwrap();
+        let (lease2, mut client2) = router.attach(1000).unwrap();
+        assert_eq!(lease1.info().addr, Ipv4Addr::new(10, 53, 0, 2));
+        assert_eq!(lease2.info().addr, Ipv4Addr::new(10, 53, 0, 3));
+
+        let gateway = Ipv4Addr::new(10, 53, 0, 1);
+        let packet1 = ipv4_packet(gateway, Ipv4Addr::new(10, 53, 0, 2));
+        let packet2 = ipv4_packet(gateway, Ipv4Addr::new(10, 53, 0, 3));
+        device_writer.write_all(&packet2).unwrap();
+        device_writer.write_all(&packet1).unwrap();
+        assert_eq!(recv_packet(&mut client1), packet1);
+        assert_eq!(recv_packet(&mut client2), packet2);
+
+        // Packets that claim to be from another client are dropped.
+        let spoofed = ipv4_packet(Ipv4Addr::new(10, 53, 0, 3), Ipv4Addr::new(10, 53, 0, 1));
+        let outgoing = ipv4_packet(Ipv4Addr::new(10, 53, 0, 2), Ipv4Addr::new(10, 53, 0, 1));
+        for packet in &[&spoofed, &outgoing] {
+            let mut framed = vec![0, 20];
+            framed.extend(packet.iter());
+            client1.send(framed);
+        }
+        let mut buffer = vec![0u8; 100];
+        assert_eq!(router_reader.read(&mut buffer).unwrap(), 20);
+        assert_eq!(&buffer[0..20], &outgoing[..]);
+
+        drop(lease1);
+        let (lease3, _) = router.attach(1000).unwrap();
+        assert_eq!(lease3.info().addr, Ipv4Addr::new(10, 53, 0, 2));
+    }
+
+    fn ipv4_packet(source: Ipv4Addr, dest: Ipv4Addr) -> Vec<u8> {
+        let mut packet = vec![0x45; 20];
+        packet[12..16].copy_from_slice(&source.octets());
+        packet[16..20].copy_from_slice(&dest.octets());
+        packet
+    }
+
+    fn recv_packet(client: &mut PacketChunker) -> Vec<u8> {
+        for _ in 0..100 {
+            if let Some(chunk) = client.recv() {
+                assert_eq!(&chunk[0..2], &[0, 20]);
+                return chunk[2..].to_vec();
+            }
+            sleep(Duration::from_millis(10));
+        }
+        panic!("no packet was routed");
+    }
+}
@@ -1,8 +1,15 @@
 //! A set of APIs for both serving and using the myodine protocol.
 
+mod error;
+
 pub mod util;
 pub mod name_code;
 pub mod record_code;
+pub mod datagram;
 pub mod discovery;
 pub mod establish;
+pub mod ip;
+pub mod mux;
 pub mod xfer;
+
+pub use self::error::Error;
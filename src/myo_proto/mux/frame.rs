@@ -0,0 +1,160 @@
+use dns_coding;
+use dns_coding::{DecPacket, Decoder, EncPacket, Encoder};
+
+use myo_proto::Error;
+
+/// The number of bytes that a data frame adds to its payload.
+pub const DATA_OVERHEAD: usize = 5;
+
+/// A message about a single stream in a multiplexed session.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    /// Ask the remote end to connect a new stream to a host.
+    Open{id: u16, host: String, port: u16},
+
+    /// The remote end connected a stream that we opened.
+    Opened(u16),
+
+    /// Data for a stream.
+    Data(u16, Vec<u8>),
+
+    /// Permission to send more bytes on a stream.
+    Window(u16, u32),
+
+    /// The sender will not send any more data on a stream.
+    Close(u16),
+
+    /// The stream was aborted, along with a reason.
+    Reset(u16, String),
+
+    /// Ask the remote end to listen on a port and pass connections back.
+    Listen{id: u16, host: String, port: u16},
+
+    /// A connection arrived on one of the listeners that we were asked for.
+    Accept{id: u16, listener: u16}
+}
+
+impl Frame {
+    /// Get the ID of the stream that the frame is about.
+    pub fn stream_id(&self) -> u16 {
+        match *self {
+            Frame::Open{id, ..} | Frame::Listen{id, ..} | Frame::Accept{id, ..} => id,
+            Frame::Opened(id) | Frame::Data(id, _) | Frame::Window(id, _) | Frame::Close(id) |
+                Frame::Reset(id, _) => id
+        }
+    }
+}
+
+impl Encoder for Frame {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), dns_coding::Error> {
+        match *self {
+            Frame::Open{id, ref host, port} => {
+                encode_header(packet, 0, id)?;
+                port.dns_encode(packet)?;
+                encode_string(packet, host)
+            },
+            Frame::Opened(id) => encode_header(packet, 1, id),
+            Frame::Data(id, ref data) => {
+                encode_header(packet, 2, id)?;
+                packet.encode_with_length(|p| data.dns_encode(p))
+            },
+            Frame::Window(id, size) => {
+                encode_header(packet, 3, id)?;
+                size.dns_encode(packet)
+            },
+            Frame::Close(id) => encode_header(packet, 4, id),
+            Frame::Reset(id, ref reason) => {
+                encode_header(packet, 5, id)?;
+                encode_string(packet, reason)
+            },
+            Frame::Listen{id, ref host, port} => {
+                encode_header(packet, 6, id)?;
+                port.dns_encode(packet)?;
+                encode_string(packet, host)
+            },
+            Frame::Accept{id, listener} => {
+                encode_header(packet, 7, id)?;
+                listener.dns_encode(packet)
+            }
+        }
+    }
+}
+
+impl Decoder for Frame {
+    type Error = Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<Frame, Error> {
+        let frame_type = u8::dns_decode(packet)?;
+        let id = u16::dns_decode(packet)?;
+        Ok(match frame_type {
+            0 => {
+                let port = u16::dns_decode(packet)?;
+                Frame::Open{id: id, host: decode_string(packet)?, port: port}
+            },
+            1 => Frame::Opened(id),
+            2 => Frame::Data(id, decode_bytes(packet)?),
+            3 => Frame::Window(id, u32::dns_decode(packet)?),
+            4 => Frame::Close(id),
+            5 => Frame::Reset(id, decode_string(packet)?),
+            6 => {
+                let port = u16::dns_decode(packet)?;
+                Frame::Listen{id: id, host: decode_string(packet)?, port: port}
+            },
+            7 => Frame::Accept{id: id, listener: u16::dns_decode(packet)?},
+            x => return Err(Error::UnknownFrameType(x))
+        })
+    }
+}
+
+fn encode_header(packet: &mut EncPacket, frame_type: u8, id: u16) -> Result<(), dns_coding::Error> {
+    frame_type.dns_encode(packet)?;
+    id.dns_encode(packet)
+}
+
+fn encode_string(packet: &mut EncPacket, s: &str) -> Result<(), dns_coding::Error> {
+    packet.encode_with_length(|p| s.as_bytes().to_vec().dns_encode(p))
+}
+
+fn decode_bytes(packet: &mut DecPacket) -> Result<Vec<u8>, dns_coding::Error> {
+    packet.decode_with_length(|p, len| p.read_bytes(len))
+}
+
+fn decode_string(packet: &mut DecPacket) -> Result<String, dns_coding::Error> {
+    Ok(String::from_utf8_lossy(&decode_bytes(packet)?).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns_coding::{dns_decode, dns_encode};
+
+    #[test]
+    fn frame_encodings() {
+        let frames = vec![
+            Frame::Open{id: 3, host: "example.com".to_owned(), port: 443},
+            Frame::Opened(3),
+            Frame::Data(0xfffe, vec![1, 2, 3]),
+            Frame::Data(7, Vec::new()),
+            Frame::Window(3, 0x10000),
+            Frame::Close(1),
+            Frame::Reset(2, "connection refused".to_owned()),
+            Frame::Listen{id: 4, host: "localhost".to_owned(), port: 8080},
+            Frame::Accept{id: 5, listener: 4}
+        ];
+        for frame in frames {
+            let data = dns_encode(&frame).unwrap();
+            assert_eq!(dns_decode::<Frame>(data).unwrap(), frame);
+        }
+        assert_eq!(dns_encode(&Frame::Data(1, vec![9; 10])).unwrap().len(), DATA_OVERHEAD + 10);
+    }
+
+    #[test]
+    fn partial_frames() {
+        let data = dns_encode(&Frame::Open{id: 1, host: "foo".to_owned(), port: 80}).unwrap();
+        for i in 0..data.len() {
+            assert_eq!(dns_decode::<Frame>(data[0..i].to_vec()).unwrap_err(),
+                Error::Coding(dns_coding::Error::BufferUnderflow));
+        }
+        assert_eq!(dns_decode::<Frame>(vec![9, 0, 1]).unwrap_err(), Error::UnknownFrameType(9));
+    }
+}
@@ -0,0 +1,55 @@
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+/// How often the accept loop checks whether the listener was dropped.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A TCP listener that accepts connections in the background.
+///
+/// The port stays open until the listener is dropped.
+pub struct Listener {
+    closed: Arc<AtomicBool>
+}
+
+impl Listener {
+    /// Listen on a host and port.
+    ///
+    /// Every accepted connection is sent to `sender`, along with `id`.
+    pub fn bind(
+        host: &str,
+        port: u16,
+        id: u16,
+        sender: Sender<(u16, TcpStream)>
+    ) -> io::Result<Listener> {
+        let listener = TcpListener::bind((host, port))?;
+        listener.set_nonblocking(true)?;
+        let closed = Arc::new(AtomicBool::new(false));
+        let accept_closed = closed.clone();
+        spawn(move || {
+            while !accept_closed.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        if stream.set_nonblocking(false).is_ok() &&
+                            sender.send((id, stream)).is_err()
+                        {
+                            break;
+                        }
+                    },
+                    Err(_) => sleep(POLL_INTERVAL)
+                }
+            }
+        });
+        Ok(Listener{closed: closed})
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,8 @@
+//! APIs for carrying many streams over a single session.
+
+mod frame;
+mod listener;
+mod mux;
+
+pub use self::frame::Frame;
+pub use self::mux::{Mux, OpenCallback, OpenError, OpenFilter, STREAM_WINDOW, Side};
@@ -0,0 +1,889 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::fmt;
+use std::io;
+use std::mem::{replace, take};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread::spawn;
+use std::time::Duration;
+
+use conn::{Chunker, SplitStream, TcpChunker};
+use dns_coding;
+use dns_coding::{DecPacket, Decoder, dns_encode};
+use myo_proto::Error;
+use super::frame::{DATA_OVERHEAD, Frame};
+use super::listener::Listener;
+
+/// The number of bytes either end may send on a stream before the other end
+/// grants it more room.
+pub const STREAM_WINDOW: usize = 0x10000;
+
+/// The number of chunks buffered in each direction for a stream's socket.
+const STREAM_BUFFER: usize = 16;
+
+/// The maximum number of streams that may be waiting to connect at once.
+const MAX_CONNECTING: usize = 16;
+
+/// The reset reason sent when a stream could not be connected in time.
+const CONNECT_TIMEOUT: &str = "connect timed out";
+
+/// A function which is told whether the remote end managed to connect a
+/// stream, or why it failed to.
+pub type OpenCallback = Box<dyn FnOnce(Result<(), OpenError>) + Send>;
+
+/// The reason that a stream or listener could not be opened.
+#[derive(Clone, Debug, PartialEq)]
+pub enum OpenError {
+    /// The remote end could not connect the stream in time.
+    Timeout,
+
+    /// The stream or listener was refused or failed for some other reason.
+    Refused(String)
+}
+
+impl OpenError {
+    fn from_reason(reason: &str) -> OpenError {
+        if reason == CONNECT_TIMEOUT {
+            OpenError::Timeout
+        } else {
+            OpenError::Refused(reason.to_owned())
+        }
+    }
+}
+
+impl Display for OpenError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            OpenError::Timeout => write!(f, "{}", CONNECT_TIMEOUT),
+            OpenError::Refused(ref reason) => write!(f, "{}", reason)
+        }
+    }
+}
+
+/// A function which decides whether the remote end may open a stream to a
+/// host and port.
+pub type OpenFilter = Box<dyn Fn(&str, u16) -> bool + Send>;
+
+/// Which end of a session a multiplexer is on.
+///
+/// The client picks even stream IDs and the server picks odd ones, so that
+/// both ends can start streams without colliding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    Client,
+    Server
+}
+
+/// Many TCP streams carried over the byte stream of a single session.
+///
+/// A `Mux` takes the place of a `TcpChunker` in a multiplexed session. Every
+/// stream has its own flow control window, so a stream whose socket is slow
+/// to drain cannot stall the others.
+///
+/// Usually the client opens streams and the server connects them. The client
+/// may also ask the server to listen on a port, in which case the server
+/// starts a stream for every connection it accepts and the client connects
+/// it to a local target.
+pub struct Mux {
+    side: Side,
+    mtu: usize,
+    coalesce_delay: Duration,
+    connect_timeout: Duration,
+    listen_enabled: bool,
+    open_filter: Option<OpenFilter>,
+    streams: HashMap<u16, Stream>,
+    forwards: HashMap<u16, Forward>,
+    next_id: u16,
+    last_read: u16,
+    connecting: usize,
+    connect_sender: Sender<(u16, io::Result<TcpStream>)>,
+    connect_results: Receiver<(u16, io::Result<TcpStream>)>,
+    accept_sender: Sender<(u16, TcpStream)>,
+    accepts: Receiver<(u16, TcpStream)>,
+    in_buffer: Vec<u8>,
+    out_buffer: Vec<u8>
+}
+
+/// A port that one end of the session listens on for the other.
+enum Forward {
+    /// We listen on the port for the remote end, until the listener is
+    /// dropped.
+    Local{_listener: Listener},
+
+    /// The remote end listens on the port, and we connect the streams that
+    /// it accepts to a host and port.
+    Remote{host: String, port: u16, on_listen: Option<OpenCallback>}
+}
+
+struct Stream {
+    conn: Option<TcpChunker>,
+    on_open: Option<OpenCallback>,
+    pending: VecDeque<Vec<u8>>,
+    pending_size: usize,
+    send_credit: usize,
+    delivered: usize,
+    local_eof: bool,
+    remote_eof: bool,
+    eof_delivered: bool
+}
+
+impl Mux {
+    /// Create a multiplexer with no streams.
+    ///
+    /// # Arguments
+    ///
+    /// * `side` - The end of the session that the multiplexer is on. Only the
+    ///   server connects streams that the remote end opens.
+    /// * `mtu` - The maximum size of the chunks produced by recv().
+    /// * `coalesce_delay` - The coalescing delay for each stream's socket.
+    /// * `connect_timeout` - The timeout for connecting streams that the
+    ///   remote end opens or accepts.
+    pub fn new(side: Side, mtu: usize, coalesce_delay: Duration, connect_timeout: Duration) -> Mux {
+        let (connect_sender, connect_results) = channel();
+        let (accept_sender, accepts) = channel();
+        Mux{
+            side: side,
+            mtu: mtu,
+            coalesce_delay: coalesce_delay,
+            connect_timeout: connect_timeout,
+            listen_enabled: false,
+            open_filter: None,
+            streams: HashMap::new(),
+            forwards: HashMap::new(),
+            next_id: if side == Side::Client { 0 } else { 1 },
+            last_read: 0,
+            connecting: 0,
+            connect_sender: connect_sender,
+            connect_results: connect_results,
+            accept_sender: accept_sender,
+            accepts: accepts,
+            in_buffer: Vec::new(),
+            out_buffer: Vec::new()
+        }
+    }
+
+    /// Choose whether the client may ask us to listen on ports.
+    ///
+    /// This is off by default, and clients never listen for servers.
+    pub fn set_listen_enabled(&mut self, enabled: bool) {
+        self.listen_enabled = enabled;
+    }
+
+    /// Only connect the streams that the remote end opens if they pass a
+    /// filter. Other streams are reset.
+    pub fn set_open_filter(&mut self, filter: OpenFilter) {
+        self.open_filter = Some(filter);
+    }
+
+    /// Get the number of open streams.
+    pub fn num_streams(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Get the number of ports that either end is listening on for the
+    /// other.
+    pub fn num_listeners(&self) -> usize {
+        self.forwards.len()
+    }
+
+    /// Start a stream for a local connection, such as a `TcpStream`.
+    ///
+    /// The remote end connects the stream to the given host and port. If
+    /// `on_open` is provided, it is called with the outcome before any data
+    /// from the remote end is written to the connection.
+    ///
+    /// Returns the ID of the new stream.
+    pub fn open<S: SplitStream>(
+        &mut self,
+        stream: S,
+        host: &str,
+        port: u16,
+        on_open: Option<OpenCallback>
+    ) -> io::Result<u16> {
+        let conn = TcpChunker::new(stream, self.max_payload(), STREAM_BUFFER, STREAM_BUFFER,
+            self.coalesce_delay)?;
+        self.add_stream(conn, host, port, on_open)
+    }
+
+    fn add_stream(
+        &mut self,
+        conn: TcpChunker,
+        host: &str,
+        port: u16,
+        on_open: Option<OpenCallback>
+    ) -> io::Result<u16> {
+        if host.len() > 255 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "host name is too long"));
+        }
+        let id = self.unused_id()
+            .ok_or_else(|| io::Error::other("too many streams"))?;
+        let mut new_stream = Stream::new(Some(conn));
+        new_stream.on_open = on_open;
+        self.streams.insert(id, new_stream);
+        self.push_frame(Frame::Open{id: id, host: host.to_owned(), port: port});
+        Ok(id)
+    }
+
+    /// Ask the remote end to listen on a host and port.
+    ///
+    /// Every connection that the remote end accepts becomes a new stream,
+    /// which is connected to the target host and port. If `on_listen` is
+    /// provided, it is called once the remote end is listening or has failed
+    /// to.
+    ///
+    /// Returns the ID of the new listener.
+    pub fn listen(
+        &mut self,
+        host: &str,
+        port: u16,
+        target_host: &str,
+        target_port: u16,
+        on_listen: Option<OpenCallback>
+    ) -> io::Result<u16> {
+        if host.len() > 255 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "host name is too long"));
+        }
+        let id = self.unused_id()
+            .ok_or_else(|| io::Error::other("too many streams"))?;
+        self.forwards.insert(id, Forward::Remote{
+            host: target_host.to_owned(),
+            port: target_port,
+            on_listen: on_listen
+        });
+        self.push_frame(Frame::Listen{id: id, host: host.to_owned(), port: port});
+        Ok(id)
+    }
+
+    fn unused_id(&mut self) -> Option<u16> {
+        for _ in 0..0x8000 {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(2);
+            if !self.streams.contains_key(&id) && !self.forwards.contains_key(&id) {
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    /// Check that the remote end may use an ID for a new stream or listener.
+    fn check_remote_id(&self, id: u16) -> Result<(), String> {
+        if id.is_multiple_of(2) != (self.side == Side::Server) {
+            Err("stream ID has the wrong parity".to_owned())
+        } else if self.streams.contains_key(&id) || self.forwards.contains_key(&id) {
+            Err("stream ID is in use".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn max_payload(&self) -> usize {
+        if self.mtu > DATA_OVERHEAD {
+            self.mtu - DATA_OVERHEAD
+        } else {
+            1
+        }
+    }
+
+    fn push_frame(&mut self, frame: Frame) {
+        match dns_encode(&frame) {
+            Ok(data) => self.out_buffer.extend(data),
+            Err(err) => {
+                // The stream cannot go on without the frame, so it is reset
+                // with a reason that is sure to fit.
+                let id = frame.stream_id();
+                self.remove(id, &format!("encode error: {}", err));
+                if let Ok(data) = dns_encode(&Frame::Reset(id, "encode error".to_owned())) {
+                    self.out_buffer.extend(data);
+                }
+            }
+        }
+    }
+
+    fn reset(&mut self, id: u16, reason: String) {
+        self.remove(id, &reason);
+        self.push_frame(Frame::Reset(id, reason));
+    }
+
+    /// Forget about a stream or listener that was aborted.
+    fn remove(&mut self, id: u16, reason: &str) {
+        if let Some(mut stream) = self.streams.remove(&id) {
+            stream.report_open(Err(OpenError::from_reason(reason)));
+        }
+        if let Some(Forward::Remote{on_listen: Some(on_listen), ..}) = self.forwards.remove(&id) {
+            on_listen(Err(OpenError::from_reason(reason)));
+        }
+    }
+
+    /// Connect a stream that the remote end started.
+    ///
+    /// Each connection attempt has its own thread, so streams beyond the
+    /// limit of attempts in progress are reset.
+    fn start_connect(&mut self, id: u16, host: String, port: u16) {
+        if let Err(reason) = self.check_remote_id(id) {
+            self.push_frame(Frame::Reset(id, reason));
+            return;
+        }
+        if self.connecting >= MAX_CONNECTING {
+            self.push_frame(Frame::Reset(id, "too many pending connections".to_owned()));
+            return;
+        }
+        self.connecting += 1;
+        self.streams.insert(id, Stream::new(None));
+        let sender = self.connect_sender.clone();
+        let timeout = self.connect_timeout;
+        spawn(move || {
+            sender.send((id, connect(&host, port, timeout))).ok();
+        });
+    }
+
+    fn handle_frame(&mut self, frame: Frame) {
+        let id = frame.stream_id();
+        match frame {
+            Frame::Open{host, port, ..} => {
+                let allowed = self.open_filter.as_ref().map(|f| f(&host, port)).unwrap_or(true);
+                if self.side != Side::Server {
+                    self.push_frame(Frame::Reset(id, "cannot open streams here".to_owned()));
+                } else if !allowed {
+                    self.push_frame(Frame::Reset(id, "destination not allowed".to_owned()));
+                } else {
+                    self.start_connect(id, host, port);
+                }
+            },
+            Frame::Listen{host, port, ..} => {
+                let result = if self.side != Side::Server || !self.listen_enabled {
+                    Err("cannot listen here".to_owned())
+                } else {
+                    self.check_remote_id(id).and_then(|_| {
+                        Listener::bind(&host, port, id, self.accept_sender.clone())
+                            .map_err(|e| format!("listen error: {}", e))
+                    })
+                };
+                match result {
+                    Ok(listener) => {
+                        self.forwards.insert(id, Forward::Local{_listener: listener});
+                        self.push_frame(Frame::Opened(id));
+                    },
+                    Err(reason) => self.push_frame(Frame::Reset(id, reason))
+                }
+            },
+            Frame::Accept{listener, ..} => {
+                let target = match self.forwards.get(&listener) {
+                    Some(&Forward::Remote{ref host, port, ..}) => Some((host.clone(), port)),
+                    _ => None
+                };
+                match target {
+                    Some((host, port)) => self.start_connect(id, host, port),
+                    None => self.push_frame(Frame::Reset(id, "no such listener".to_owned()))
+                }
+            },
+            Frame::Opened(_) => {
+                if let Some(stream) = self.streams.get_mut(&id) {
+                    stream.report_open(Ok(()));
+                } else if let Some(&mut Forward::Remote{ref mut on_listen, ..}) =
+                    self.forwards.get_mut(&id)
+                {
+                    if let Some(on_listen) = on_listen.take() {
+                        on_listen(Ok(()));
+                    }
+                }
+            },
+            Frame::Data(_, data) => {
+                let overflow = if let Some(stream) = self.streams.get_mut(&id) {
+                    stream.pending_size += data.len();
+                    stream.pending.push_back(data);
+                    stream.remote_eof || stream.pending_size > STREAM_WINDOW
+                } else {
+                    false
+                };
+                if overflow {
+                    self.reset(id, "data past the end of the window".to_owned());
+                }
+            },
+            Frame::Window(_, size) => {
+                if let Some(stream) = self.streams.get_mut(&id) {
+                    stream.send_credit = stream.send_credit.saturating_add(size as usize);
+                }
+            },
+            Frame::Close(_) => {
+                if let Some(stream) = self.streams.get_mut(&id) {
+                    stream.remote_eof = true;
+                }
+            },
+            Frame::Reset(_, reason) => self.remove(id, &reason)
+        }
+    }
+
+    fn poll_connects(&mut self) {
+        while let Ok((id, result)) = self.connect_results.try_recv() {
+            self.connecting -= 1;
+            if !self.streams.contains_key(&id) {
+                continue;
+            }
+            let conn_res = result.and_then(|stream| {
+                TcpChunker::new(stream, self.max_payload(), STREAM_BUFFER, STREAM_BUFFER,
+                    self.coalesce_delay)
+            });
+            match conn_res {
+                Ok(conn) => {
+                    self.streams.get_mut(&id).unwrap().conn = Some(conn);
+                    self.push_frame(Frame::Opened(id));
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::TimedOut => {
+                    self.reset(id, CONNECT_TIMEOUT.to_owned())
+                },
+                Err(err) => self.reset(id, format!("connect error: {}", err))
+            }
+        }
+    }
+
+    fn poll_accepts(&mut self) {
+        while let Ok((listener, stream)) = self.accepts.try_recv() {
+            if !self.forwards.contains_key(&listener) {
+                continue;
+            }
+            let id = match self.unused_id() {
+                Some(id) => id,
+                None => continue
+            };
+            let conn_res = TcpChunker::new(stream, self.max_payload(), STREAM_BUFFER,
+                STREAM_BUFFER, self.coalesce_delay);
+            if let Ok(conn) = conn_res {
+                self.streams.insert(id, Stream::new(Some(conn)));
+                self.push_frame(Frame::Accept{id: id, listener: listener});
+            }
+        }
+    }
+
+    fn flush_streams(&mut self) {
+        let mut frames = Vec::new();
+        for (id, stream) in self.streams.iter_mut() {
+            if let Some(size) = stream.flush() {
+                frames.push(Frame::Window(*id, size as u32));
+            }
+        }
+        for frame in frames {
+            self.push_frame(frame);
+        }
+        self.remove_finished();
+    }
+
+    fn read_streams(&mut self) {
+        let mut ids: Vec<u16> = self.streams.keys().cloned().collect();
+        ids.sort();
+        let start = ids.iter().position(|x| *x > self.last_read).unwrap_or(0);
+        ids.rotate_left(start);
+        let max_payload = self.max_payload();
+        for id in ids {
+            if self.out_buffer.len() >= self.mtu {
+                break;
+            }
+            let frame = self.streams.get_mut(&id).unwrap().read(id, max_payload);
+            if let Some(frame) = frame {
+                self.last_read = id;
+                self.push_frame(frame);
+            }
+        }
+        self.remove_finished();
+    }
+
+    fn remove_finished(&mut self) {
+        self.streams.retain(|_, stream| !(stream.local_eof && stream.eof_delivered));
+    }
+}
+
+impl Chunker for Mux {
+    fn can_send(&mut self) -> bool {
+        // Flow control bounds the amount of buffered data, so the underlying
+        // session never needs to apply backpressure.
+        true
+    }
+
+    fn send(&mut self, chunk: Vec<u8>) {
+        self.in_buffer.extend(chunk);
+        let mut packet = DecPacket::new(take(&mut self.in_buffer));
+        let mut consumed = 0;
+        loop {
+            match Frame::dns_decode(&mut packet) {
+                Ok(frame) => {
+                    consumed = packet.current_offset();
+                    self.handle_frame(frame);
+                },
+                Err(Error::Coding(dns_coding::Error::BufferUnderflow)) => break,
+                Err(_) => {
+                    // There is no way to find the next frame boundary.
+                    let ids: Vec<u16> = self.streams.keys().chain(self.forwards.keys())
+                        .cloned().collect();
+                    for id in ids {
+                        self.reset(id, "invalid frame".to_owned());
+                    }
+                    consumed = packet.current_offset() + packet.remaining();
+                    break;
+                }
+            }
+        }
+        // Only the bytes of a partial frame at the end are kept.
+        self.in_buffer = packet.into_buffer();
+        self.in_buffer.drain(0..consumed);
+        self.flush_streams();
+    }
+
+    fn send_finished(&mut self) {
+        self.streams.clear();
+        self.forwards.clear();
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.poll_connects();
+        self.poll_accepts();
+        self.flush_streams();
+        self.read_streams();
+        if self.out_buffer.is_empty() {
+            None
+        } else if self.out_buffer.len() > self.mtu {
+            let rest = self.out_buffer.split_off(self.mtu);
+            Some(replace(&mut self.out_buffer, rest))
+        } else {
+            Some(take(&mut self.out_buffer))
+        }
+    }
+}
+
+impl Stream {
+    fn new(conn: Option<TcpChunker>) -> Stream {
+        Stream{
+            conn: conn,
+            on_open: None,
+            pending: VecDeque::new(),
+            pending_size: 0,
+            send_credit: STREAM_WINDOW,
+            delivered: 0,
+            local_eof: false,
+            remote_eof: false,
+            eof_delivered: false
+        }
+    }
+
+    fn report_open(&mut self, result: Result<(), OpenError>) {
+        if let Some(on_open) = self.on_open.take() {
+            on_open(result);
+        }
+    }
+
+    /// Write as much pending data to the socket as possible.
+    ///
+    /// Returns the number of bytes to grant the remote end, if it is time to
+    /// send a window update.
+    fn flush(&mut self) -> Option<usize> {
+        if let Some(ref mut conn) = self.conn {
+            while !self.pending.is_empty() && conn.can_send() {
+                let data = self.pending.pop_front().unwrap();
+                self.pending_size -= data.len();
+                self.delivered += data.len();
+                conn.send(data);
+            }
+            if self.pending.is_empty() && self.remote_eof && !self.eof_delivered {
+                conn.send_finished();
+                self.eof_delivered = true;
+            }
+        }
+        if self.delivered >= STREAM_WINDOW / 4 && !self.eof_delivered {
+            Some(replace(&mut self.delivered, 0))
+        } else {
+            None
+        }
+    }
+
+    /// Read the next frame from the socket, if the window allows it.
+    fn read(&mut self, id: u16, max_payload: usize) -> Option<Frame> {
+        if self.local_eof || self.send_credit < max_payload {
+            return None;
+        }
+        let data = self.conn.as_mut().and_then(|x| x.recv())?;
+        if data.is_empty() {
+            self.local_eof = true;
+            Some(Frame::Close(id))
+        } else {
+            self.send_credit -= data.len();
+            Some(Frame::Data(id, data))
+        }
+    }
+}
+
+fn connect(host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no addresses for host");
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err
+        }
+    }
+    Err(last_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener};
+    use std::thread::sleep;
+
+    #[test]
+    fn echo_streams() {
+        let port = echo_server();
+        let mut client = new_mux(Side::Client);
+        let mut server = new_mux(Side::Server);
+        let mut locals = Vec::new();
+        let (open_sender, open_results) = channel();
+        for i in 0..3 {
+            let (local, remote) = tcp_pair();
+            let sender = open_sender.clone();
+            client.open(remote, "127.0.0.1", port, Some(Box::new(move |res| {
+                sender.send(res).unwrap();
+            }))).unwrap();
+            locals.push((local, vec![i as u8; 50000 * (i + 1)]));
+        }
+        let mut threads = Vec::new();
+        for (mut local, data) in locals {
+            threads.push(spawn(move || {
+                let mut reader = local.try_clone().unwrap();
+                let expected = data.clone();
+                let reader_thread = spawn(move || {
+                    let mut result = Vec::new();
+                    reader.read_to_end(&mut result).unwrap();
+                    result
+                });
+                local.write_all(&data).unwrap();
+                local.shutdown(Shutdown::Write).unwrap();
+                assert!(reader_thread.join().unwrap() == expected);
+            }));
+        }
+
+        for _ in 0..2000 {
+            pump(&mut client, &mut server);
+            pump(&mut server, &mut client);
+            if client.num_streams() == 0 && server.num_streams() == 0 {
+                break;
+            }
+            sleep(Duration::from_millis(5));
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(client.num_streams(), 0);
+        assert_eq!(server.num_streams(), 0);
+        assert_eq!(open_results.try_iter().collect::<Vec<_>>(), vec![Ok(()); 3]);
+    }
+
+    #[test]
+    fn open_refused() {
+        let mut client = new_mux(Side::Client);
+        let mut server = new_mux(Side::Server);
+        let (mut local, remote) = tcp_pair();
+        let (sender, results) = channel();
+        server.open(remote, "127.0.0.1", 1, Some(Box::new(move |res| {
+            sender.send(res).unwrap();
+        }))).unwrap();
+        pump(&mut server, &mut client);
+        pump(&mut client, &mut server);
+        assert_eq!(server.num_streams(), 0);
+        assert_eq!(results.try_recv().unwrap(), refused("cannot open streams here"));
+        let mut data = Vec::new();
+        local.read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), 0);
+    }
+
+    #[test]
+    fn open_filter() {
+        let mut client = new_mux(Side::Client);
+        let mut server = new_mux(Side::Server);
+        server.set_open_filter(Box::new(|host, port| host == "127.0.0.1" && port != 1));
+        let (sender, results) = channel();
+        for &(host, port) in &[("localhost", 2), ("127.0.0.1", 1)] {
+            let sender = sender.clone();
+            let (_, remote) = tcp_pair();
+            client.open(remote, host, port, Some(Box::new(move |res| {
+                sender.send(res).unwrap();
+            }))).unwrap();
+        }
+        pump(&mut client, &mut server);
+        pump(&mut server, &mut client);
+        assert_eq!(server.num_streams(), 0);
+        assert_eq!(results.try_iter().collect::<Vec<_>>(),
+            vec![refused("destination not allowed"); 2]);
+    }
+
+    #[test]
+    fn reverse_streams() {
+        let target_port = echo_server();
+        let listen_port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut client = new_mux(Side::Client);
+        let mut server = new_mux(Side::Server);
+        let (sender, results) = channel();
+        for _ in 0..2 {
+            let sender = sender.clone();
+            client.listen("127.0.0.1", listen_port, "127.0.0.1", target_port,
+                Some(Box::new(move |res| sender.send(res).unwrap()))).unwrap();
+        }
+
+        // Listening is refused until the server enables it.
+        pump(&mut client, &mut server);
+        pump(&mut server, &mut client);
+        assert_eq!(results.try_recv().unwrap(), refused("cannot listen here"));
+        assert_eq!(results.try_recv().unwrap(), refused("cannot listen here"));
+        assert_eq!(client.num_listeners(), 0);
+        server.set_listen_enabled(true);
+        client.listen("127.0.0.1", listen_port, "127.0.0.1", target_port,
+            Some(Box::new(move |res| sender.send(res).unwrap()))).unwrap();
+        pump(&mut client, &mut server);
+        pump(&mut server, &mut client);
+        assert_eq!(results.try_recv().unwrap(), Ok(()));
+        assert_eq!(server.num_listeners(), 1);
+
+        let mut conn = TcpStream::connect(("127.0.0.1", listen_port)).unwrap();
+        let thread = spawn(move || {
+            conn.write_all(&[7; 20000]).unwrap();
+            conn.shutdown(Shutdown::Write).unwrap();
+            let mut result = Vec::new();
+            conn.read_to_end(&mut result).unwrap();
+            result
+        });
+        let mut accepted = false;
+        for _ in 0..2000 {
+            pump(&mut server, &mut client);
+            pump(&mut client, &mut server);
+            accepted = accepted || server.num_streams() > 0;
+            if accepted && client.num_streams() == 0 && server.num_streams() == 0 {
+                break;
+            }
+            sleep(Duration::from_millis(5));
+        }
+        assert_eq!(thread.join().unwrap(), vec![7; 20000]);
+        assert_eq!(client.num_listeners(), 1);
+    }
+
+    #[test]
+    fn split_frames() {
+        let mut client = new_mux(Side::Client);
+        let mut server = new_mux(Side::Server);
+        let (_, remote) = tcp_pair();
+        client.open(remote, "127.0.0.1", 1, None).unwrap();
+        let mut data = Vec::new();
+        while let Some(chunk) = client.recv() {
+            data.extend(chunk);
+        }
+        for b in data {
+            server.send(vec![b]);
+        }
+        assert_eq!(server.num_streams(), 1);
+        assert_eq!(server.in_buffer.len(), 0);
+    }
+
+    #[test]
+    fn pending_connection_limit() {
+        let port = echo_server();
+        let mut client = new_mux(Side::Client);
+        let mut server = new_mux(Side::Server);
+        let (sender, results) = channel();
+        let mut locals = Vec::new();
+        for _ in 0..(MAX_CONNECTING + 1) {
+            let sender = sender.clone();
+            let (local, remote) = tcp_pair();
+            locals.push(local);
+            client.open(remote, "127.0.0.1", port, Some(Box::new(move |res| {
+                sender.send(res).unwrap();
+            }))).unwrap();
+        }
+
+        // Every open arrives before any connection attempt is finished.
+        pump(&mut client, &mut server);
+        let mut outcomes = Vec::new();
+        for _ in 0..2000 {
+            pump(&mut server, &mut client);
+            outcomes.extend(results.try_iter());
+            if outcomes.len() == MAX_CONNECTING + 1 {
+                break;
+            }
+            sleep(Duration::from_millis(5));
+        }
+        let full = refused("too many pending connections");
+        assert_eq!(outcomes.iter().filter(|x| **x == Ok(())).count(), MAX_CONNECTING);
+        assert_eq!(outcomes.iter().filter(|x| **x == full).count(), 1);
+        assert_eq!(server.connecting, 0);
+    }
+
+    #[test]
+    fn unencodable_frames() {
+        let mut client = new_mux(Side::Client);
+        let (_, remote) = tcp_pair();
+        let (sender, results) = channel();
+        let id = client.open(remote, "127.0.0.1", 1, Some(Box::new(move |res| {
+            sender.send(res).unwrap();
+        }))).unwrap();
+        client.out_buffer.clear();
+        client.push_frame(Frame::Data(id, vec![0; 0x10000]));
+        assert_eq!(client.num_streams(), 0);
+        match results.try_recv().unwrap() {
+            Err(OpenError::Refused(reason)) => assert!(reason.starts_with("encode error")),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        let frame = Frame::dns_decode(&mut DecPacket::new(client.out_buffer.clone())).unwrap();
+        assert_eq!(frame, Frame::Reset(id, "encode error".to_owned()));
+    }
+
+    #[test]
+    fn open_timeout() {
+        let mut client = new_mux(Side::Client);
+        let (sender, results) = channel();
+        for &reason in &[CONNECT_TIMEOUT, "connect error: refused"] {
+            let sender = sender.clone();
+            let (_, remote) = tcp_pair();
+            let id = client.open(remote, "127.0.0.1", 1, Some(Box::new(move |res| {
+                sender.send(res).unwrap();
+            }))).unwrap();
+            client.send(dns_encode(&Frame::Reset(id, reason.to_owned())).unwrap());
+        }
+        assert_eq!(results.try_iter().collect::<Vec<_>>(), vec![
+            Err(OpenError::Timeout),
+            refused("connect error: refused")
+        ]);
+    }
+
+    fn new_mux(side: Side) -> Mux {
+        Mux::new(side, 100, Duration::from_millis(0), Duration::from_secs(5))
+    }
+
+    fn echo_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                spawn(move || {
+                    let mut data = Vec::new();
+                    conn.read_to_end(&mut data).unwrap();
+                    conn.write_all(&data).unwrap();
+                });
+            }
+        });
+        port
+    }
+
+    fn pump(source: &mut Mux, dest: &mut Mux) {
+        while let Some(chunk) = source.recv() {
+            assert!(chunk.len() <= 100);
+            dest.send(chunk);
+        }
+    }
+
+    fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let local = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (remote, _) = listener.accept().unwrap();
+        (local, remote)
+    }
+
+    fn refused(reason: &str) -> Result<(), OpenError> {
+        Err(OpenError::Refused(reason.to_owned()))
+    }
+}
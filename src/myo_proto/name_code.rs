@@ -1,24 +1,28 @@
 use std::fmt::Write;
+use std::sync::Arc;
 
 use dns_proto::Domain;
 
+use super::Error;
 use super::util::{domain_ends_with, domain_part_lowercase};
 
 /// Lookup the NameCode for the given identifier.
-pub fn get_name_code(name: &str) -> Option<Box<NameCode>> {
+///
+/// Codes are stateless, so one instance may be shared between sessions.
+pub fn get_name_code(name: &str) -> Option<Arc<dyn NameCode>> {
     match name {
-        "b16" => Some(Box::new(HexNameCode{})),
+        "b16" => Some(Arc::new(HexNameCode{})),
         _ => None
     }
 }
 
 /// A method of encoding raw data in DNS names.
-pub trait NameCode {
+pub trait NameCode: Send + Sync {
     /// Encode the raw data as domain name labels.
-    fn encode_parts(&self, data: &[u8]) -> Result<Vec<String>, String>;
+    fn encode_parts(&self, data: &[u8]) -> Result<Vec<String>, Error>;
 
     /// Decode the raw data from domain name labels.
-    fn decode_parts(&self, parts: &[String]) -> Result<Vec<u8>, String>;
+    fn decode_parts(&self, parts: &[String]) -> Result<Vec<u8>, Error>;
 
     /// Encode the data into the full `Domain` for a transfer query.
     ///
@@ -34,12 +38,12 @@ pub trait NameCode {
         sess_id: u16,
         data: &[u8],
         host: &Domain
-    ) -> Result<Domain, String> {
+    ) -> Result<Domain, Error> {
         let mut parts = Vec::new();
         parts.push(format!("{}{}", api_flag, sess_id));
         parts.extend(self.encode_parts(data)?);
         parts.extend(host.parts().to_vec());
-        Domain::from_parts(parts)
+        Ok(Domain::from_parts(parts)?)
     }
 
     /// Decode the data from a transfer query's domain name.
@@ -52,18 +56,18 @@ pub trait NameCode {
     /// # Returns
     ///
     /// A tuple of the form (api_code, session_id, data).
-    fn decode_domain(&self, name: &Domain, host: &Domain) -> Result<(char, u16, Vec<u8>), String> {
+    fn decode_domain(&self, name: &Domain, host: &Domain) -> Result<(char, u16, Vec<u8>), Error> {
         if !domain_ends_with(name, host) {
-            Err("incorrect host domain".to_owned())
+            Err(Error::IncorrectHost)
         } else if name.parts().len() < host.parts().len() + 2 {
-            Err("not enough data".to_owned())
+            Err(Error::NotEnoughLabels)
         } else {
             let mut all_parts = name.parts().to_vec();
             let sess_part = all_parts.remove(0);
             let api_code = domain_part_lowercase(&sess_part).chars().next().unwrap();
             let sess_id = sess_part.chars().skip(1).collect::<String>().parse();
             if sess_id.is_err() {
-                return Err("invalid session ID".to_owned());
+                return Err(Error::InvalidSessionId);
             }
             Ok((api_code, sess_id.unwrap(),
                 self.decode_parts(&all_parts[0..(all_parts.len() - host.parts().len())])?))
@@ -75,7 +79,7 @@ pub trait NameCode {
 pub struct HexNameCode;
 
 impl NameCode for HexNameCode {
-    fn encode_parts(&self, data: &[u8]) -> Result<Vec<String>, String> {
+    fn encode_parts(&self, data: &[u8]) -> Result<Vec<String>, Error> {
         let mut encoded = String::new();
         for ch in data {
             write!(encoded, "{:02x}", *ch).unwrap();
@@ -83,12 +87,12 @@ impl NameCode for HexNameCode {
         Ok(split_labels(encoded))
     }
 
-    fn decode_parts(&self, parts: &[String]) -> Result<Vec<u8>, String> {
+    fn decode_parts(&self, parts: &[String]) -> Result<Vec<u8>, Error> {
         let mut hex_data = String::new();
         hex_data.extend(parts.iter().map(|x| x as &str));
         let bytes = hex_data.as_bytes();
         if bytes.len() % 2 != 0 {
-            return Err("invalid data length".to_owned());
+            return Err(Error::InvalidData("odd number of hex digits".to_owned()));
         }
         let mut data = Vec::new();
         for i in 0..(bytes.len() / 2) {
@@ -96,7 +100,7 @@ impl NameCode for HexNameCode {
             if let Ok(res) = u8::from_str_radix(&format!("{}{}", c1, c2), 16) {
                 data.push(res);
             } else {
-                return Err(format!("invalid hex byte: {}{}", c1, c2));
+                return Err(Error::InvalidData(format!("invalid hex byte: {}{}", c1, c2)));
             }
         }
         Ok(data)
@@ -1,12 +1,18 @@
+use std::sync::Arc;
+
 use dns_coding::{DecPacket, Decoder, EncPacket, Encoder};
 use dns_proto::{RecordBody, RecordType};
 
+use super::Error;
+
 /// Lookup the RecordCode for the given record type and code identifier.
-pub fn get_record_code(record_type: RecordType, name: &str) -> Option<Box<RecordCode>> {
+///
+/// Codes are stateless, so one instance may be shared between sessions.
+pub fn get_record_code(record_type: RecordType, name: &str) -> Option<Arc<dyn RecordCode>> {
     match record_type {
         RecordType::TXT => {
             if name == "raw" {
-                Some(Box::new(RawTxtCode{}))
+                Some(Arc::new(RawTxtCode{}))
             } else {
                 None
             }
@@ -16,19 +22,19 @@ pub fn get_record_code(record_type: RecordType, name: &str) -> Option<Box<Record
 }
 
 /// A method of encoding raw data in DNS records.
-pub trait RecordCode {
+pub trait RecordCode: Send + Sync {
     /// Encode the data into a record.
-    fn encode_body(&self, data: &[u8]) -> Result<RecordBody, String>;
+    fn encode_body(&self, data: &[u8]) -> Result<RecordBody, Error>;
 
     /// Decode the data from a record.
-    fn decode_body(&self, body: &RecordBody) -> Result<Vec<u8>, String>;
+    fn decode_body(&self, body: &RecordBody) -> Result<Vec<u8>, Error>;
 }
 
 /// A RecordCode that puts raw data into TXT records.
 pub struct RawTxtCode;
 
 impl RecordCode for RawTxtCode {
-    fn encode_body(&self, data: &[u8]) -> Result<RecordBody, String> {
+    fn encode_body(&self, data: &[u8]) -> Result<RecordBody, Error> {
         let mut result = EncPacket::new();
         let mut next_buf = Vec::new();
         for x in data.iter() {
@@ -46,7 +52,7 @@ impl RecordCode for RawTxtCode {
         Ok(RecordBody::Unknown(result.data().clone()))
     }
 
-    fn decode_body(&self, body: &RecordBody) -> Result<Vec<u8>, String> {
+    fn decode_body(&self, body: &RecordBody) -> Result<Vec<u8>, Error> {
         if let &RecordBody::Unknown(ref data) = body {
             let mut packet = DecPacket::new(data.clone());
             let mut result = Vec::new();
@@ -56,7 +62,7 @@ impl RecordCode for RawTxtCode {
             }
             Ok(result)
         } else {
-            Err("unexpected record type".to_owned())
+            Err(Error::UnexpectedRecordType)
         }
     }
 }
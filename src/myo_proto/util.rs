@@ -38,6 +38,11 @@ pub fn domain_part_equal(x: &str, y: &str) -> bool {
     return domain_part_lowercase(x) == domain_part_lowercase(y);
 }
 
+/// Convert a domain name to lowercase, for use as a key.
+pub fn domain_lowercase(domain: &Domain) -> String {
+    domain.parts().iter().map(|x| domain_part_lowercase(x)).collect::<Vec<_>>().join(".")
+}
+
 /// Convert a domain label to lowercase.
 pub fn domain_part_lowercase(x: &str) -> String {
     let mut res = String::new();
@@ -0,0 +1,177 @@
+use std::iter::FromIterator;
+
+/// A fixed-length sequence of bits, packed into machine words.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BitMask {
+    words: Vec<u64>,
+    len: usize
+}
+
+impl BitMask {
+    /// Create a mask of `len` bits, all of which are unset.
+    pub fn new(len: usize) -> BitMask {
+        BitMask{words: vec![0; len.div_ceil(64)], len: len}
+    }
+
+    /// Get the number of bits in the mask.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if the mask has no bits at all.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the bit at the given index.
+    ///
+    /// Panics if the index is out of bounds.
+    pub fn get(&self, index: usize) -> bool {
+        assert!(index < self.len);
+        self.words[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    /// Set the bit at the given index.
+    ///
+    /// Panics if the index is out of bounds.
+    pub fn set(&mut self, index: usize, value: bool) {
+        assert!(index < self.len);
+        if value {
+            self.words[index / 64] |= 1 << (index % 64);
+        } else {
+            self.words[index / 64] &= !(1 << (index % 64));
+        }
+    }
+
+    /// Check if any bit is set.
+    pub fn any(&self) -> bool {
+        self.words.iter().any(|x| *x != 0)
+    }
+
+    /// Get the indices of all the set bits, in ascending order.
+    ///
+    /// This skips over unset words, so it is fast for sparse masks.
+    pub fn ones(&self) -> Ones<'_> {
+        Ones{mask: self, word_idx: 0, word: self.words.first().cloned().unwrap_or(0)}
+    }
+
+    /// Get the indices `i` such that bit `i` is set in this mask and bit
+    /// `i + offset` is set in `other`, in ascending order.
+    ///
+    /// This works a word at a time, so it is fast even for dense masks.
+    pub fn common_ones(&self, other: &BitMask, offset: usize) -> Vec<usize> {
+        let mut result = Vec::new();
+        for i in 0..self.words.len() {
+            let mut word = self.words[i] & other.shifted_word(i, offset);
+            while word != 0 {
+                result.push(i * 64 + word.trailing_zeros() as usize);
+                word &= word - 1;
+            }
+        }
+        result
+    }
+
+    /// Drop the first `amount` bits, moving every other bit towards the start
+    /// of the mask and filling the end of the mask with unset bits.
+    pub fn shift_down(&mut self, amount: usize) {
+        for i in 0..self.words.len() {
+            self.words[i] = self.shifted_word(i, amount);
+        }
+    }
+
+    fn shifted_word(&self, index: usize, amount: usize) -> u64 {
+        let (word_shift, bit_shift) = (amount / 64, amount % 64);
+        let lower = self.words.get(index + word_shift).cloned().unwrap_or(0);
+        if bit_shift == 0 {
+            lower
+        } else {
+            let upper = self.words.get(index + word_shift + 1).cloned().unwrap_or(0);
+            (lower >> bit_shift) | (upper << (64 - bit_shift))
+        }
+    }
+}
+
+impl FromIterator<bool> for BitMask {
+    fn from_iter<I: IntoIterator<Item = bool>>(iter: I) -> BitMask {
+        let bits: Vec<bool> = iter.into_iter().collect();
+        let mut result = BitMask::new(bits.len());
+        for (i, b) in bits.into_iter().enumerate() {
+            if b {
+                result.set(i, true);
+            }
+        }
+        result
+    }
+}
+
+impl From<Vec<bool>> for BitMask {
+    fn from(bits: Vec<bool>) -> BitMask {
+        bits.into_iter().collect()
+    }
+}
+
+/// An iterator over the set bits in a `BitMask`.
+pub struct Ones<'a> {
+    mask: &'a BitMask,
+    word_idx: usize,
+    word: u64
+}
+
+impl<'a> Iterator for Ones<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while self.word == 0 {
+            self.word_idx += 1;
+            if self.word_idx >= self.mask.words.len() {
+                return None;
+            }
+            self.word = self.mask.words[self.word_idx];
+        }
+        let bit = self.word.trailing_zeros() as usize;
+        self.word &= self.word - 1;
+        Some(self.word_idx * 64 + bit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_iterate() {
+        let mut mask = BitMask::new(200);
+        assert!(!mask.any());
+        for i in &[0, 5, 63, 64, 130, 199] {
+            mask.set(*i, true);
+        }
+        assert!(mask.any());
+        assert!(mask.get(63) && mask.get(64) && !mask.get(65));
+        assert_eq!(mask.ones().collect::<Vec<_>>(), vec![0, 5, 63, 64, 130, 199]);
+        mask.set(64, false);
+        assert_eq!(mask.ones().collect::<Vec<_>>(), vec![0, 5, 63, 130, 199]);
+    }
+
+    #[test]
+    fn common_ones() {
+        let x: BitMask = (0..150).map(|i| i % 2 == 0).collect();
+        let y: BitMask = (0..100).map(|i| i % 3 == 0).collect();
+        assert_eq!(x.common_ones(&y, 1), (0..150).filter(|i| i % 2 == 0 && i < &99 &&
+            (i + 1) % 3 == 0).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn shift_down() {
+        for &len in &[1, 63, 64, 65, 200] {
+            for amount in 0..(len + 2) {
+                let bits: Vec<bool> = (0..len).map(|i| i % 3 == 0 || i % 7 == 1).collect();
+                let mut mask: BitMask = bits.clone().into();
+                mask.shift_down(amount);
+                let expected: BitMask = (0..len)
+                    .map(|i| i + amount < len && bits[i + amount])
+                    .collect();
+                assert_eq!(mask, expected);
+            }
+        }
+    }
+}
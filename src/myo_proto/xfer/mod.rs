@@ -1,11 +1,14 @@
 //! APIs for implementing the myodine data transfer protocol.
 
+mod bitmask;
 mod types;
+mod window;
 mod wwr;
 mod messages;
 mod session;
 
-pub use self::types::{Ack, Chunk, Packet};
-pub use self::wwr::WwrState;
+pub use self::bitmask::{BitMask, Ones};
+pub use self::types::{Ack, AckEncoding, Chunk, Packet};
+pub use self::wwr::{WwrError, WwrState};
 pub use self::messages::xfer_query_session_id;
 pub use self::session::{handle_packet_in, next_packet_out};
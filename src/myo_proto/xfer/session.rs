@@ -1,17 +1,24 @@
-use conn::TcpChunker;
-use super::{Packet, WwrState};
+use conn::Chunker;
+use super::{Packet, WwrError, WwrState};
 
-/// Feed an incoming packet into a WWR state machine and a TCP socket.
+/// Feed an incoming packet into a WWR state machine and a connection.
 ///
-/// Automatically deals with backpressure from the TCP socket.
+/// Automatically deals with backpressure from the connection.
 ///
-/// Returns the number of bytes written to the connection.
-pub fn handle_packet_in(packet: Packet, state: &mut WwrState, conn: &mut TcpChunker) -> usize {
-    state.handle_ack(&packet.ack);
+/// Returns the number of bytes written to the connection, or an error if the
+/// packet was invalid. The acknowledgement is handled before the chunk, so a
+/// valid acknowledgement still takes effect if the chunk is rejected, but an
+/// invalid acknowledgement stops the chunk from being handled at all.
+pub fn handle_packet_in(
+    packet: Packet,
+    state: &mut WwrState,
+    conn: &mut dyn Chunker
+) -> Result<usize, WwrError> {
+    state.handle_ack(&packet.ack)?;
     if conn.can_send() && packet.chunk.is_some() {
         let mut buffer = Vec::new();
         let mut finished = false;
-        for chunk in state.handle_chunk(packet.chunk.unwrap()) {
+        for chunk in state.handle_chunk(packet.chunk.unwrap())? {
             if chunk.data.len() == 0 {
                 finished = true;
                 // Data past EOF is meaningless.
@@ -27,18 +34,18 @@ pub fn handle_packet_in(packet: Packet, state: &mut WwrState, conn: &mut TcpChun
         if finished {
             conn.send_finished();
         }
-        len
+        Ok(len)
     } else {
-        0
+        Ok(0)
     }
 }
 
-/// Feed data from a TCP connection into a WWR state machine.
+/// Feed data from a connection into a WWR state machine.
 ///
 /// Produces the next packet to send on behalf of the WWR state.
 ///
 /// Returns the number of bytes read from the connection.
-pub fn next_packet_out(state: &mut WwrState, conn: &mut TcpChunker) -> (Packet, usize) {
+pub fn next_packet_out(state: &mut WwrState, conn: &mut dyn Chunker) -> (Packet, usize) {
     let mut bytes = 0;
     while state.send_buffer_space() > 0 {
         if let Some(data) = conn.recv() {
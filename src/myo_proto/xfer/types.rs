@@ -2,13 +2,17 @@ extern crate rand;
 use self::rand::thread_rng;
 use self::rand::distributions::{Range, IndependentSample};
 
+use dns_coding;
 use dns_coding::{DecPacket, Decoder, EncPacket, Encoder};
 
+use myo_proto::Error;
+use super::BitMask;
+
 /// An acknowledgement of the chunks that have been seen in a window.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Ack {
     pub window_start: u32,
-    pub window_mask: Vec<bool>
+    pub window_mask: BitMask
 }
 
 /// A sequenced chunk of data.
@@ -25,55 +29,132 @@ pub struct Packet {
     pub chunk: Option<Chunk>
 }
 
+/// A format for acknowledgements, which is chosen during establishment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AckEncoding {
+    /// One bit for every chunk in the window.
+    Mask,
+
+    /// A list of (offset, length) ranges of chunks that have been received.
+    ///
+    /// This is much smaller than a mask for large, mostly empty windows.
+    Ranges
+}
+
+impl AckEncoding {
+    /// Lookup the AckEncoding for the given identifier.
+    pub fn from_name(name: &str) -> Option<AckEncoding> {
+        match name {
+            "mask" => Some(AckEncoding::Mask),
+            "sack" => Some(AckEncoding::Ranges),
+            _ => None
+        }
+    }
+
+    /// Get the identifier for the encoding.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AckEncoding::Mask => "mask",
+            AckEncoding::Ranges => "sack"
+        }
+    }
+}
+
 impl Ack {
     /// Decode an acknowledgement from the remote end.
     ///
     /// Requires the our outgoing window size in order to know how large the
     /// acknowledgement bit-mask is.
-    pub fn decode(packet: &mut DecPacket, window_size: u16) -> Result<Ack, String> {
+    pub fn decode(
+        packet: &mut DecPacket,
+        window_size: u16,
+        encoding: AckEncoding
+    ) -> Result<Ack, Error> {
+        if window_size == 0 {
+            return Err(Error::ZeroWindow);
+        }
         let window_start = Decoder::dns_decode(packet)?;
         let num_bits = (window_size as usize) - 1;
-        let num_bytes = if num_bits % 8 != 0 {
-            num_bits / 8 + 1
-        } else {
-            num_bits / 8
-        };
-        let mut bits = Vec::new();
-        for byte in packet.read_bytes(num_bytes)? {
-            for j in 0..8 {
-                if bits.len() < num_bits {
-                    bits.push(byte & (1 << (7 - j)) != 0);
+        let mut bits = BitMask::new(num_bits);
+        match encoding {
+            AckEncoding::Mask => {
+                let num_bytes = if num_bits % 8 != 0 {
+                    num_bits / 8 + 1
+                } else {
+                    num_bits / 8
+                };
+                for (i, byte) in packet.read_bytes(num_bytes)?.into_iter().enumerate() {
+                    for j in 0..8 {
+                        if i * 8 + j < num_bits && byte & (1 << (7 - j)) != 0 {
+                            bits.set(i * 8 + j, true);
+                        }
+                    }
+                }
+            },
+            AckEncoding::Ranges => {
+                // Ranges must be sorted and disjoint, so that decoding never
+                // touches more bits than the window has.
+                let mut prev_end = 0;
+                for _ in 0..u8::dns_decode(packet)? {
+                    let start = u16::dns_decode(packet)? as usize;
+                    let len = u16::dns_decode(packet)? as usize;
+                    if start + len > num_bits {
+                        return Err(Error::AckRangeOutOfBounds);
+                    } else if start < prev_end {
+                        return Err(Error::AckRangeOutOfOrder);
+                    }
+                    prev_end = start + len;
+                    for i in start..(start + len) {
+                        bits.set(i, true);
+                    }
                 }
             }
         }
         Ok(Ack{window_start: window_start, window_mask: bits})
     }
-}
 
-impl Encoder for Ack {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    /// Encode an acknowledgement for the remote end.
+    ///
+    /// With `AckEncoding::Ranges`, at most 255 ranges are sent. Any chunks
+    /// past the last range are simply not acknowledged yet.
+    pub fn encode(&self, packet: &mut EncPacket, encoding: AckEncoding) -> Result<(), dns_coding::Error> {
         self.window_start.dns_encode(packet)?;
-        let mut cur_byte = 0u8;
-        for (i, b) in (&self.window_mask).into_iter().enumerate() {
-            cur_byte = cur_byte << 1;
-            if *b {
-                cur_byte |= 1;
-            }
-            if i % 8 == 7 {
-                cur_byte.dns_encode(packet)?;
-                cur_byte = 0;
+        match encoding {
+            AckEncoding::Mask => {
+                let mut bytes = vec![0u8; (self.window_mask.len() + 7) / 8];
+                for i in self.window_mask.ones() {
+                    bytes[i / 8] |= 1 << (7 - (i % 8));
+                }
+                bytes.dns_encode(packet)
+            },
+            AckEncoding::Ranges => {
+                let mut ranges: Vec<(usize, usize)> = Vec::new();
+                for i in self.window_mask.ones() {
+                    if let Some(last) = ranges.last_mut() {
+                        if last.0 + last.1 == i {
+                            last.1 += 1;
+                            continue;
+                        }
+                    }
+                    if ranges.len() == 255 {
+                        break;
+                    }
+                    ranges.push((i, 1));
+                }
+                (ranges.len() as u8).dns_encode(packet)?;
+                for (start, len) in ranges {
+                    encode_all!(packet, start as u16, len as u16)?;
+                }
+                Ok(())
             }
         }
-        if self.window_mask.len() % 8 != 0 {
-            cur_byte <<= 8 - (self.window_mask.len() % 8);
-            cur_byte.dns_encode(packet)?;
-        }
-        Ok(())
     }
 }
 
 impl Decoder for Chunk {
-    fn dns_decode(packet: &mut DecPacket) -> Result<Chunk, String> {
+    type Error = dns_coding::Error;
+
+    fn dns_decode(packet: &mut DecPacket) -> Result<Chunk, dns_coding::Error> {
         let seq = Decoder::dns_decode(packet)?;
         let remaining = packet.remaining();
         let data = packet.read_bytes(remaining)?;
@@ -82,7 +163,7 @@ impl Decoder for Chunk {
 }
 
 impl Encoder for Chunk {
-    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), String> {
+    fn dns_encode(&self, packet: &mut EncPacket) -> Result<(), dns_coding::Error> {
         self.seq.dns_encode(packet)?;
         self.data.dns_encode(packet)
     }
@@ -93,9 +174,9 @@ impl Packet {
     ///
     /// Returns a tuple (api_code, data), where api_code is used to specify the
     /// kind of transfer packet, and data is to be encoded in the domain name.
-    pub fn encode_query(&self) -> Result<(char, Vec<u8>), String> {
+    pub fn encode_query(&self, ack_encoding: AckEncoding) -> Result<(char, Vec<u8>), dns_coding::Error> {
         let mut enc_packet = EncPacket::new();
-        self.ack.dns_encode(&mut enc_packet)?;
+        self.ack.encode(&mut enc_packet, ack_encoding)?;
         let api_code = if let &Some(ref chunk) = &self.chunk {
             chunk.dns_encode(&mut enc_packet)?;
             't'
@@ -114,13 +195,19 @@ impl Packet {
     ///
     /// * `data` - The raw data that was encoded in the domain name.
     /// * `window_size` - This end's outgoing window size.
+    /// * `ack_encoding` - The negotiated acknowledgement format.
     /// * `api_code` - The API code accompanying this query.
-    pub fn decode_query(data: &[u8], window_size: u16, api_code: char) -> Result<Packet, String> {
+    pub fn decode_query(
+        data: &[u8],
+        window_size: u16,
+        ack_encoding: AckEncoding,
+        api_code: char
+    ) -> Result<Packet, Error> {
         let mut packet = DecPacket::new(data.to_vec());
         if api_code != 't' && api_code != 'p' {
-            return Err(format!("unknown API code: {}", api_code));
+            return Err(Error::UnknownApiCode(api_code));
         }
-        let ack = Ack::decode(&mut packet, window_size)?;
+        let ack = Ack::decode(&mut packet, window_size, ack_encoding)?;
         Ok(Packet{
             ack: ack,
             chunk: if api_code == 't' {
@@ -132,9 +219,9 @@ impl Packet {
     }
 
     /// Encode the `Packet` for transmission in a DNS response.
-    pub fn encode_response(&self) -> Result<Vec<u8>, String> {
+    pub fn encode_response(&self, ack_encoding: AckEncoding) -> Result<Vec<u8>, dns_coding::Error> {
         let mut packet = EncPacket::new();
-        self.ack.dns_encode(&mut packet)?;
+        self.ack.encode(&mut packet, ack_encoding)?;
         if let &Some(ref chunk) = &self.chunk {
             chunk.dns_encode(&mut packet)?;
         }
@@ -147,9 +234,14 @@ impl Packet {
     ///
     /// * `data` - The raw data from the response.
     /// * `window_size` - This end's outgoing window size.
-    pub fn decode_response(data: &[u8], window_size: u16) -> Result<Packet, String> {
+    /// * `ack_encoding` - The negotiated acknowledgement format.
+    pub fn decode_response(
+        data: &[u8],
+        window_size: u16,
+        ack_encoding: AckEncoding
+    ) -> Result<Packet, Error> {
         let mut packet = DecPacket::new(data.to_vec());
-        let ack = Ack::decode(&mut packet, window_size)?;
+        let ack = Ack::decode(&mut packet, window_size, ack_encoding)?;
         Ok(Packet{
             ack: ack,
             chunk: if packet.remaining() > 0 {
@@ -160,3 +252,70 @@ impl Packet {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ack_encodings() {
+        let masks: Vec<BitMask> = vec![
+            BitMask::new(255),
+            (0..255).map(|i| i % 3 == 0 || i > 200).collect(),
+            vec![true, false, true, true, false, false, true].into()
+        ];
+        for mask in masks {
+            let ack = Ack{window_start: 0xfffffffe, window_mask: mask};
+            for encoding in &[AckEncoding::Mask, AckEncoding::Ranges] {
+                let mut enc_packet = EncPacket::new();
+                ack.encode(&mut enc_packet, *encoding).unwrap();
+                let mut dec_packet = DecPacket::new(enc_packet.data().clone());
+                let window_size = (ack.window_mask.len() + 1) as u16;
+                assert_eq!(Ack::decode(&mut dec_packet, window_size, *encoding).unwrap(), ack);
+                assert_eq!(dec_packet.remaining(), 0);
+            }
+        }
+    }
+
+    #[test]
+    fn sparse_ranges() {
+        let mut mask = BitMask::new(1023);
+        mask.set(17, true);
+        let ack = Ack{window_start: 3, window_mask: mask};
+        let mut enc_packet = EncPacket::new();
+        ack.encode(&mut enc_packet, AckEncoding::Ranges).unwrap();
+        assert_eq!(enc_packet.data(), &vec![0, 0, 0, 3, 1, 0, 17, 0, 1]);
+    }
+
+    #[test]
+    fn too_many_ranges() {
+        let mask: BitMask = (0..1023).map(|i| i % 2 == 0).collect();
+        let ack = Ack{window_start: 0, window_mask: mask};
+        let mut enc_packet = EncPacket::new();
+        ack.encode(&mut enc_packet, AckEncoding::Ranges).unwrap();
+        let mut dec_packet = DecPacket::new(enc_packet.data().clone());
+        let decoded = Ack::decode(&mut dec_packet, 1024, AckEncoding::Ranges).unwrap();
+        assert_eq!(decoded.window_mask.ones().collect::<Vec<_>>(),
+            (0..255).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn out_of_bounds_range() {
+        let data = vec![0, 0, 0, 0, 1, 0, 2, 0, 2];
+        assert!(Ack::decode(&mut DecPacket::new(data.clone()), 5, AckEncoding::Ranges).is_ok());
+        assert!(Ack::decode(&mut DecPacket::new(data), 4, AckEncoding::Ranges).is_err());
+    }
+
+    #[test]
+    fn unordered_ranges() {
+        let touching = vec![0, 0, 0, 0, 2, 0, 0, 0, 2, 0, 2, 0, 1];
+        assert_eq!(Ack::decode(&mut DecPacket::new(touching), 5, AckEncoding::Ranges).unwrap()
+            .window_mask.ones().collect::<Vec<_>>(), vec![0, 1, 2]);
+        let overlapping = vec![0, 0, 0, 0, 2, 0, 0, 0, 2, 0, 1, 0, 1];
+        assert_eq!(Ack::decode(&mut DecPacket::new(overlapping), 5, AckEncoding::Ranges),
+            Err(Error::AckRangeOutOfOrder));
+        let backwards = vec![0, 0, 0, 0, 2, 0, 3, 0, 1, 0, 0, 0, 1];
+        assert_eq!(Ack::decode(&mut DecPacket::new(backwards), 5, AckEncoding::Ranges),
+            Err(Error::AckRangeOutOfOrder));
+    }
+}
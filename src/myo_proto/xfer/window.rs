@@ -0,0 +1,129 @@
+use std::num::Wrapping;
+
+/// A ring buffer of slots indexed by sequence number.
+///
+/// The window covers the sequence numbers `[start, start + size)`, and every
+/// lookup is O(1).
+pub struct Window<T> {
+    start: u32,
+    head: usize,
+    count: usize,
+    slots: Vec<Option<T>>
+}
+
+impl<T> Window<T> {
+    /// Create an empty window with `size` slots, starting at `start`.
+    pub fn new(size: u16, start: u32) -> Window<T> {
+        Window{
+            start: start,
+            head: 0,
+            count: 0,
+            slots: (0..size).map(|_| None).collect()
+        }
+    }
+
+    /// Get the first sequence number in the window.
+    pub fn start(&self) -> u32 {
+        self.start
+    }
+
+    /// Get the number of occupied slots.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Get the offset of a sequence number from the start of the window, if
+    /// the sequence number is in the window.
+    pub fn offset(&self, seq: u32) -> Option<usize> {
+        let offset = (Wrapping(seq) - Wrapping(self.start)).0 as usize;
+        if offset < self.slots.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    /// Get the value for a sequence number.
+    pub fn get(&self, seq: u32) -> Option<&T> {
+        let idx = self.slot_index(seq)?;
+        self.slots[idx].as_ref()
+    }
+
+    /// Get a mutable reference to the value for a sequence number.
+    pub fn get_mut(&mut self, seq: u32) -> Option<&mut T> {
+        let idx = self.slot_index(seq)?;
+        self.slots[idx].as_mut()
+    }
+
+    /// Store a value for a sequence number.
+    ///
+    /// Returns false if the sequence number is outside of the window or if
+    /// its slot is already occupied.
+    pub fn insert(&mut self, seq: u32, value: T) -> bool {
+        if let Some(idx) = self.slot_index(seq) {
+            if self.slots[idx].is_none() {
+                self.slots[idx] = Some(value);
+                self.count += 1;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Remove the value for a sequence number.
+    pub fn remove(&mut self, seq: u32) -> Option<T> {
+        let idx = self.slot_index(seq)?;
+        let res = self.slots[idx].take();
+        if res.is_some() {
+            self.count -= 1;
+        }
+        res
+    }
+
+    /// Move the window forward by one sequence number.
+    ///
+    /// Returns the value that was in the first slot, if there was one.
+    pub fn advance(&mut self) -> Option<T> {
+        if self.slots.is_empty() {
+            self.start = (Wrapping(self.start) + Wrapping(1)).0;
+            return None;
+        }
+        let res = self.slots[self.head].take();
+        if res.is_some() {
+            self.count -= 1;
+        }
+        self.head = (self.head + 1) % self.slots.len();
+        self.start = (Wrapping(self.start) + Wrapping(1)).0;
+        res
+    }
+
+    fn slot_index(&self, seq: u32) -> Option<usize> {
+        self.offset(seq).map(|x| (self.head + x) % self.slots.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapping_window() {
+        let mut window = Window::new(3, 0xfffffffe);
+        assert!(window.insert(0xffffffff, 1));
+        assert!(window.insert(0, 2));
+        assert!(!window.insert(0, 3));
+        assert!(!window.insert(1, 3));
+        assert_eq!(window.len(), 2);
+
+        assert_eq!(window.advance(), None);
+        assert_eq!(window.get(0), Some(&2));
+        assert!(window.insert(1, 3));
+        assert_eq!(window.advance(), Some(1));
+        assert_eq!(window.advance(), Some(2));
+        assert_eq!(window.start(), 1);
+        assert_eq!(window.remove(1), Some(3));
+        assert_eq!(window.len(), 0);
+        assert_eq!(window.offset(3), Some(2));
+        assert_eq!(window.offset(0), None);
+    }
+}
@@ -1,20 +1,93 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fmt;
 use std::num::Wrapping;
+use std::time::{Duration, Instant};
+
+use super::{Ack, BitMask, Chunk};
+use super::window::Window;
+
+/// The default amount of time to wait before resending an unacknowledged
+/// chunk.
+pub const DEFAULT_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// The retransmit timeout for a chunk doubles with every send, up to this many
+// doublings.
+const MAX_BACKOFF_SHIFT: u32 = 3;
+
+/// A reason why an incoming acknowledgement or chunk was rejected.
+///
+/// Stale packets, such as retransmissions of chunks that were already
+/// received, are not errors. These errors indicate a misbehaving or malicious
+/// remote end.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WwrError {
+    /// The acknowledgement mask does not match our outgoing window size.
+    AckMaskLength{expected: usize, actual: usize},
+
+    /// The acknowledgement covers chunks which we have not sent yet.
+    AckPastEnd{window_start: u32, next_seq: u32},
+
+    /// The chunk is ahead of the incoming window.
+    ChunkPastWindow{seq: u32, window_start: u32}
+}
+
+impl Display for WwrError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            WwrError::AckMaskLength{expected, actual} => {
+                write!(f, "ack mask has {} bits (expected {})", actual, expected)
+            },
+            WwrError::AckPastEnd{window_start, next_seq} => {
+                write!(f, "ack window start {} is past next sequence number {}", window_start,
+                    next_seq)
+            },
+            WwrError::ChunkPastWindow{seq, window_start} => {
+                write!(f, "chunk {} is past the window starting at {}", seq, window_start)
+            }
+        }
+    }
+}
 
-use super::{Ack, Chunk};
+impl Error for WwrError {}
 
 /// A finite state machine representing an endpoint's view of a WWR session.
+///
+/// Both windows are stored in ring buffers, so the cost of handling a chunk
+/// does not grow with the window size, and handling an acknowledgement only
+/// touches the chunks that it acknowledges.
 pub struct WwrState {
-    in_win_size: u16,
-    in_win_start: u32,
-    in_received: Vec<Chunk>,
+    in_received: Window<Chunk>,
+    in_mask: BitMask,
     in_eof: bool,
 
     out_win_size: u16,
     out_next_seq: u32,
-    out_win_start: u32,
-    out_pending: Vec<Chunk>,
-    out_round_robin: usize,
-    out_eof: bool
+    out_unsent: u32,
+    out_pending: Window<PendingChunk>,
+    out_unacked: BitMask,
+    out_in_flight: Vec<VecDeque<InFlight>>,
+    out_eof: bool,
+    retransmit_timeout: Duration
+}
+
+/// An outgoing chunk which has not been acknowledged yet.
+struct PendingChunk {
+    chunk: Chunk,
+    last_sent: Option<Instant>,
+    send_count: u32
+}
+
+/// A record of a chunk being sent.
+///
+/// There is one queue of these for every amount of backoff, so each queue is
+/// ordered by both send time and retransmission time. A record is stale once
+/// its chunk is acknowledged or sent again.
+struct InFlight {
+    sent: Instant,
+    seq: u32,
+    send_count: u32
 }
 
 impl WwrState {
@@ -27,60 +100,93 @@ impl WwrState {
     /// * `seq_start` - The initial sequence number for both directions.
     pub fn new(in_win_size: u16, out_win_size: u16, seq_start: u32) -> WwrState {
         WwrState{
-            in_win_size: in_win_size,
-            in_win_start: seq_start,
-            in_received: Vec::new(),
+            in_received: Window::new(in_win_size, seq_start),
+            in_mask: BitMask::new(in_win_size.saturating_sub(1) as usize),
             in_eof: false,
 
             out_win_size: out_win_size,
             out_next_seq: seq_start,
-            out_win_start: seq_start,
-            out_pending: Vec::new(),
-            out_round_robin: 0,
-            out_eof: false
+            out_unsent: seq_start,
+            out_pending: Window::new(out_win_size, seq_start),
+            out_unacked: BitMask::new(out_win_size as usize),
+            out_in_flight: (0..(MAX_BACKOFF_SHIFT + 1)).map(|_| VecDeque::new()).collect(),
+            out_eof: false,
+            retransmit_timeout: DEFAULT_RETRANSMIT_TIMEOUT
         }
     }
 
+    /// Set the amount of time after which a chunk that was sent but not
+    /// acknowledged is assumed to be lost.
+    ///
+    /// Chunks that are resent repeatedly wait longer between retransmissions.
+    pub fn set_retransmit_timeout(&mut self, timeout: Duration) {
+        self.retransmit_timeout = timeout;
+    }
+
     /// Check if both the incoming and outgoing streams have EOF'd.
     pub fn is_done(&self) -> bool {
         self.in_eof && self.out_eof && self.out_pending.len() == 0
     }
 
+    /// Check if every outgoing chunk has been acknowledged.
+    pub fn all_acked(&self) -> bool {
+        self.out_pending.len() == 0
+    }
+
     /// Get the current acknowledgement packet.
     pub fn next_send_ack(&self) -> Ack {
-        let mut bit_mask = Vec::new();
-        for _ in 0..(self.in_win_size - 1) {
-            bit_mask.push(false);
-        }
-        for chunk in &self.in_received {
-            let offset = (Wrapping(chunk.seq) - Wrapping(self.in_win_start)).0 - 1;
-            assert!(offset < self.in_win_size as u32);
-            bit_mask[offset as usize] = true;
-        }
-        Ack{window_start: self.in_win_start, window_mask: bit_mask}
+        Ack{window_start: self.in_received.start(), window_mask: self.in_mask.clone()}
     }
 
     /// Get a chunk to send in the next packet.
     ///
-    /// This should only be called once per packet, since it cycles through
-    /// the unacknowledged chunks in order to prevent starvation.
+    /// This should only be called once per packet, since it records the chunk
+    /// as being in flight.
+    ///
+    /// Chunks are prioritized as follows:
+    ///
+    /// 1. The oldest unacknowledged chunk, if it has never been sent or it has
+    ///    timed out, since it is blocking the remote end.
+    /// 2. Chunks that have never been sent, in order.
+    /// 3. Timed out chunks, least recently sent first.
     ///
-    /// If there are no chunks to send, this returns None.
+    /// If there are no chunks to send, or if every chunk is probably still in
+    /// flight, this returns None.
     pub fn next_send_chunk(&mut self) -> Option<Chunk> {
-        if self.out_pending.is_empty() {
-            return None;
-        }
-        if self.out_round_robin >= self.out_pending.len() {
-            self.out_round_robin = 0;
+        self.next_send_chunk_at(Instant::now())
+    }
+
+    /// Like `next_send_chunk`, but with an explicit current time.
+    ///
+    /// Times should never go backwards between calls.
+    pub fn next_send_chunk_at(&mut self, now: Instant) -> Option<Chunk> {
+        let seq = self.head_to_send(now)
+            .or_else(|| self.next_unsent())
+            .or_else(|| self.next_expired(now))?;
+        let send_count = {
+            let pending = self.out_pending.get_mut(seq).unwrap();
+            pending.last_sent = Some(now);
+            pending.send_count += 1;
+            pending.send_count
+        };
+        self.out_in_flight[backoff_shift(send_count) as usize].push_back(InFlight{
+            sent: now,
+            seq: seq,
+            send_count: send_count
+        });
+        let num_records = self.out_in_flight.iter().map(|x| x.len()).sum::<usize>();
+        if num_records > 2 * (self.out_win_size as usize) + 16 {
+            let pending = &self.out_pending;
+            for queue in &mut self.out_in_flight {
+                queue.retain(|x| is_current(pending, x));
+            }
         }
-        let chunk = self.out_pending[self.out_round_robin].clone();
-        self.out_round_robin += 1;
-        Some(chunk)
+        Some(self.out_pending.get(seq).unwrap().chunk.clone())
     }
 
     /// Get the number of chunks that can be pushed by `push_send_buffer`.
     pub fn send_buffer_space(&self) -> usize {
-        let win_used = (Wrapping(self.out_next_seq) - Wrapping(self.out_win_start)).0;
+        let win_used = (Wrapping(self.out_next_seq) - Wrapping(self.out_pending.start())).0;
         assert!((win_used as usize) <= (self.out_win_size as usize));
         (self.out_win_size as usize) - (win_used as usize)
     }
@@ -94,8 +200,13 @@ impl WwrState {
         assert!(!self.out_eof);
         assert!(self.send_buffer_space() > 0);
         let chunk = Chunk{seq: self.out_next_seq, data: data};
+        self.out_unacked.set(self.out_pending.offset(chunk.seq).unwrap(), true);
+        self.out_pending.insert(chunk.seq, PendingChunk{
+            chunk: chunk,
+            last_sent: None,
+            send_count: 0
+        });
         self.out_next_seq = (Wrapping(self.out_next_seq) + Wrapping(1)).0;
-        self.out_pending.push(chunk);
     }
 
     /// Push an EOF to the end of the outgoing data stream.
@@ -111,32 +222,50 @@ impl WwrState {
     }
 
     /// Handle an acknowledgement from the remote end.
-    pub fn handle_ack(&mut self, ack: &Ack) {
-        assert_eq!(ack.window_mask.len(), (self.out_win_size - 1) as usize);
-
-        if ack.window_start == self.out_next_seq {
-            self.out_pending.clear();
-            self.out_win_start = self.out_next_seq;
-            return;
+    ///
+    /// Stale acknowledgements are ignored. Acknowledgements which could not
+    /// have come from a well-behaved remote end produce an error, and do not
+    /// affect the state.
+    pub fn handle_ack(&mut self, ack: &Ack) -> Result<(), WwrError> {
+        let expected_len = self.out_win_size.saturating_sub(1) as usize;
+        if ack.window_mask.len() != expected_len {
+            return Err(WwrError::AckMaskLength{
+                expected: expected_len,
+                actual: ack.window_mask.len()
+            });
         }
-        let residual = (Wrapping(ack.window_start) - Wrapping(self.out_win_start)).0;
-        if residual > (Wrapping(self.out_next_seq) - Wrapping(self.out_win_start)).0 {
+
+        let win_start = self.out_pending.start();
+        let residual = (Wrapping(ack.window_start) - Wrapping(win_start)).0;
+        if residual > (Wrapping(self.out_next_seq) - Wrapping(win_start)).0 {
             // This is a stale ACK or an out-of-bounds ACK.
             // If we didn't check for out-of-bounds ACKs, the sender could move our
-            // out_win_start past our out_next_seq.
-            return;
+            // window start past our out_next_seq.
+            if is_ahead(ack.window_start, self.out_next_seq) {
+                return Err(WwrError::AckPastEnd{
+                    window_start: ack.window_start,
+                    next_seq: self.out_next_seq
+                });
+            }
+            return Ok(());
         }
-        for i in 0..residual {
-            let residual_seq = (Wrapping(self.out_win_start) + Wrapping(i)).0;
-            self.remove_out_seq(residual_seq);
+        for _ in 0..residual {
+            self.out_pending.advance();
         }
-        self.out_win_start = ack.window_start;
-        for (i, b) in (&ack.window_mask).into_iter().enumerate() {
-            if *b {
-                self.remove_out_seq((Wrapping(ack.window_start) + Wrapping(i as u32) +
-                    Wrapping(1)).0);
-            }
+        self.out_unacked.shift_down(residual as usize);
+
+        // Only look at bits for chunks which are still pending, so that
+        // repeated acknowledgements of the same chunks are cheap.
+        for i in ack.window_mask.common_ones(&self.out_unacked, 1) {
+            self.out_unacked.set(i + 1, false);
+            self.out_pending.remove((Wrapping(ack.window_start) + Wrapping(i as u32) +
+                Wrapping(1)).0);
+        }
+        let unsent_offset = (Wrapping(self.out_unsent) - Wrapping(ack.window_start)).0;
+        if unsent_offset > (Wrapping(self.out_next_seq) - Wrapping(ack.window_start)).0 {
+            self.out_unsent = ack.window_start;
         }
+        Ok(())
     }
 
     /// Handle an incoming chunk from the remote end.
@@ -147,61 +276,118 @@ impl WwrState {
     ///
     /// If an empty chunk is included in the result, it is the last chunk and
     /// signals an EOF.
-    pub fn handle_chunk(&mut self, chunk: Chunk) -> Vec<Chunk> {
+    ///
+    /// Stale and duplicate chunks are ignored. Chunks past the end of the
+    /// window produce an error.
+    pub fn handle_chunk(&mut self, chunk: Chunk) -> Result<Vec<Chunk>, WwrError> {
         if self.in_eof {
-            return Vec::new();
+            return Ok(Vec::new());
         }
 
-        let chunk_offset = (Wrapping(chunk.seq) - Wrapping(self.in_win_start)).0;
-        if chunk_offset >= self.in_win_size as u32 {
-            // Stale chunk or some kind of premature chunk.
-            return Vec::new();
+        let chunk_offset = match self.in_received.offset(chunk.seq) {
+            Some(offset) => offset,
+            None => {
+                let window_start = self.in_received.start();
+                return if is_ahead(chunk.seq, window_start) {
+                    Err(WwrError::ChunkPastWindow{seq: chunk.seq, window_start: window_start})
+                } else {
+                    Ok(Vec::new())
+                };
+            }
+        };
+        if !self.in_received.insert(chunk.seq, chunk) {
+            return Ok(Vec::new());
         }
-        if (&self.in_received).into_iter().any(|x| x.seq == chunk.seq) {
-            return Vec::new();
+        if chunk_offset > 0 {
+            self.in_mask.set(chunk_offset - 1, true);
+            return Ok(Vec::new());
         }
-        self.in_received.push(chunk);
 
         let mut result = Vec::new();
-        let mut got_chunk = true;
-        while got_chunk {
-            got_chunk = false;
-            for i in 0..self.in_received.len() {
-                if self.in_received[i].seq == self.in_win_start {
-                    let chunk = self.in_received.swap_remove(i);
-                    let is_eof = chunk.data.len() == 0;
-                    result.push(chunk);
-                    self.in_win_start = (Wrapping(self.in_win_start) + Wrapping(1)).0;
-                    got_chunk = true;
-                    if is_eof {
-                        self.in_eof = true;
-                        return result;
-                    }
-                    break;
+        while self.in_received.get(self.in_received.start()).is_some() {
+            let chunk = self.in_received.advance().unwrap();
+            let is_eof = chunk.data.len() == 0;
+            result.push(chunk);
+            if is_eof {
+                self.in_eof = true;
+                break;
+            }
+        }
+        self.in_mask.shift_down(result.len());
+        Ok(result)
+    }
+
+    fn head_to_send(&self, now: Instant) -> Option<u32> {
+        let seq = self.out_pending.start();
+        let pending = self.out_pending.get(seq)?;
+        if let Some(last_sent) = pending.last_sent {
+            let timeout = self.retransmit_timeout * (1 << backoff_shift(pending.send_count));
+            if now.duration_since(last_sent) < timeout {
+                return None;
+            }
+        }
+        Some(seq)
+    }
+
+    fn next_unsent(&mut self) -> Option<u32> {
+        while self.out_unsent != self.out_next_seq {
+            if let Some(pending) = self.out_pending.get(self.out_unsent) {
+                if pending.last_sent.is_none() {
+                    return Some(self.out_unsent);
                 }
             }
+            self.out_unsent = (Wrapping(self.out_unsent) + Wrapping(1)).0;
         }
-        result
+        None
     }
 
-    fn remove_out_seq(&mut self, seq: u32) {
-        for i in (0..self.out_pending.len()).into_iter().rev() {
-            if self.out_pending[i].seq == seq {
-                self.out_pending.remove(i);
-                if self.out_round_robin > i {
-                    self.out_round_robin -= 1;
+    fn next_expired(&mut self, now: Instant) -> Option<u32> {
+        // The front of each queue is its least recently sent chunk, so the
+        // oldest of the fronts that have timed out is the one to resend.
+        let (pending, retransmit_timeout) = (&self.out_pending, self.retransmit_timeout);
+        let mut oldest: Option<(Instant, usize)> = None;
+        for (shift, queue) in self.out_in_flight.iter_mut().enumerate() {
+            while queue.front().map(|x| !is_current(pending, x)).unwrap_or(false) {
+                queue.pop_front();
+            }
+            if let Some(record) = queue.front() {
+                let timeout = retransmit_timeout * (1 << shift);
+                let is_older = oldest.map(|(sent, _)| record.sent < sent).unwrap_or(true);
+                if now.duration_since(record.sent) >= timeout && is_older {
+                    oldest = Some((record.sent, shift));
                 }
-                return;
             }
         }
+        let (_, shift) = oldest?;
+        self.out_in_flight[shift].pop_front().map(|x| x.seq)
     }
 }
 
+// Get the number of times that a chunk's retransmit timeout has doubled.
+fn backoff_shift(send_count: u32) -> u32 {
+    (send_count - 1).min(MAX_BACKOFF_SHIFT)
+}
+
+// Check if a record is for the latest send of a chunk that is still pending.
+fn is_current(pending: &Window<PendingChunk>, record: &InFlight) -> bool {
+    pending.get(record.seq).map(|x| x.send_count == record.send_count).unwrap_or(false)
+}
+
+// Check if `seq` comes after `reference`, using serial number arithmetic.
+fn is_ahead(seq: u32, reference: u32) -> bool {
+    let diff = (Wrapping(seq) - Wrapping(reference)).0;
+    diff != 0 && diff < 0x80000000
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::super::{AckEncoding, Packet};
     use std::iter::repeat;
 
+    extern crate rand;
+    use self::rand::{Rng, thread_rng};
+
     #[test]
     fn symmetric_single_window() {
         let (mut end1, mut end2) = (trivial_endpoint(), trivial_endpoint());
@@ -241,40 +427,40 @@ mod tests {
         assert_eq!(state.send_buffer_space(), 0);
 
         // ACK past the end of the window.
-        state.handle_ack(&Ack{
+        assert_eq!(state.handle_ack(&Ack{
             window_start: 4,
-            window_mask: vec![true, true, true, true]
-        });
+            window_mask: vec![true, true, true, true].into()
+        }), Err(WwrError::AckPastEnd{window_start: 4, next_seq: 3}));
         assert_eq!(state.send_buffer_space(), 0);
 
         // This packet is ignored, because it indicates an invalid state.
         // The window is ahead of -3, meaning that we've seen a more recent
         // acknowledgement, so this stale ACK should not contain any bits that our
         // our newer ACK did not.
-        state.handle_ack(&Ack{
+        assert!(state.handle_ack(&Ack{
             window_start: 0xfffffffd,
-            window_mask: vec![true, true, true, true]
-        });
+            window_mask: vec![true, true, true, true].into()
+        }).is_ok());
         assert_eq!(state.send_buffer_space(), 0);
 
         // This is also invalid, since we are ACK'ing packets past the window.
         // However, the trailing ACKs are ignored in this case.
-        state.handle_ack(&Ack{
+        assert!(state.handle_ack(&Ack{
             window_start: 0,
-            window_mask: vec![false, true, true, true]
-        });
+            window_mask: vec![false, true, true, true].into()
+        }).is_ok());
         assert_eq!(state.send_buffer_space(), 2);
 
         // Packets within the maximum possible window, but not the current window.
-        state.handle_ack(&Ack{
+        assert!(state.handle_ack(&Ack{
             window_start: 5,
-            window_mask: vec![true, true, true, true]
-        });
+            window_mask: vec![true, true, true, true].into()
+        }).is_err());
         assert_eq!(state.send_buffer_space(), 2);
-        state.handle_ack(&Ack{
+        assert!(state.handle_ack(&Ack{
             window_start: 4,
-            window_mask: vec![false, false, false, false]
-        });
+            window_mask: vec![false, false, false, false].into()
+        }).is_err());
         assert_eq!(state.send_buffer_space(), 2);
 
         // Fill up the window.
@@ -288,11 +474,226 @@ mod tests {
         // Now this ACK is in bounds.
         state.handle_ack(&Ack{
             window_start: 5,
-            window_mask: vec![true, true, true, true]
-        });
+            window_mask: vec![true, true, true, true].into()
+        }).unwrap();
         assert_eq!(state.send_buffer_space(), 5);
     }
 
+    #[test]
+    fn no_resend_in_flight() {
+        let mut state = WwrState::new(4, 4, 0);
+        state.set_retransmit_timeout(Duration::from_secs(1000));
+        for i in 0..3 {
+            state.push_send_buffer(vec![i]);
+        }
+        for i in 0..3 {
+            assert_eq!(state.next_send_chunk().unwrap().seq, i);
+        }
+        assert!(state.next_send_chunk().is_none());
+
+        // New chunks can still be sent while old ones are in flight.
+        state.push_send_buffer(vec![3]);
+        assert_eq!(state.next_send_chunk().unwrap().seq, 3);
+        assert!(state.next_send_chunk().is_none());
+    }
+
+    #[test]
+    fn resend_oldest_first() {
+        let mut state = WwrState::new(4, 4, 0);
+        let start = Instant::now();
+        state.set_retransmit_timeout(Duration::from_secs(0));
+        for i in 0..3 {
+            state.push_send_buffer(vec![i]);
+        }
+
+        // The head of the window is always favored once it times out.
+        assert_eq!(state.next_send_chunk_at(start).unwrap().seq, 0);
+        assert_eq!(state.next_send_chunk_at(start).unwrap().seq, 0);
+
+        state.set_retransmit_timeout(Duration::from_millis(200));
+        assert_eq!(state.next_send_chunk_at(start).unwrap().seq, 1);
+        assert_eq!(state.next_send_chunk_at(start).unwrap().seq, 2);
+        assert!(state.next_send_chunk_at(at(start, 199)).is_none());
+
+        // The head has been sent more often, so it backs off for longer.
+        assert_eq!(state.next_send_chunk_at(at(start, 200)).unwrap().seq, 1);
+        assert_eq!(state.next_send_chunk_at(at(start, 200)).unwrap().seq, 2);
+        assert!(state.next_send_chunk_at(at(start, 399)).is_none());
+        assert_eq!(state.next_send_chunk_at(at(start, 400)).unwrap().seq, 0);
+
+        // Acknowledging the head makes the next chunk the new head.
+        state.handle_ack(&Ack{window_start: 1, window_mask: vec![false, false, false].into()})
+            .unwrap();
+        assert!(state.next_send_chunk_at(at(start, 599)).is_none());
+        assert_eq!(state.next_send_chunk_at(at(start, 600)).unwrap().seq, 1);
+    }
+
+    #[test]
+    fn resend_least_recently_sent() {
+        let mut state = WwrState::new(4, 4, 0);
+        let start = Instant::now();
+        state.set_retransmit_timeout(Duration::from_secs(0));
+        for i in 0..2 {
+            state.push_send_buffer(vec![i]);
+        }
+        // Back the head off as far as it goes, so that it stays in flight.
+        for _ in 0..4 {
+            assert_eq!(state.next_send_chunk_at(start).unwrap().seq, 0);
+        }
+        state.set_retransmit_timeout(Duration::from_millis(100));
+        assert_eq!(state.next_send_chunk_at(start).unwrap().seq, 1);
+        assert_eq!(state.next_send_chunk_at(at(start, 100)).unwrap().seq, 1);
+        state.push_send_buffer(vec![2]);
+        assert_eq!(state.next_send_chunk_at(at(start, 120)).unwrap().seq, 2);
+
+        // Chunk 2 timed out first, but chunk 1 has waited longer.
+        assert!(state.next_send_chunk_at(at(start, 219)).is_none());
+        assert_eq!(state.next_send_chunk_at(at(start, 300)).unwrap().seq, 1);
+        assert_eq!(state.next_send_chunk_at(at(start, 300)).unwrap().seq, 2);
+        assert!(state.next_send_chunk_at(at(start, 300)).is_none());
+    }
+
+    #[test]
+    fn resend_head_first() {
+        let mut state = WwrState::new(4, 4, 0);
+        let start = Instant::now();
+        state.set_retransmit_timeout(Duration::from_millis(200));
+        for i in 0..3 {
+            state.push_send_buffer(vec![i]);
+        }
+        for i in 0..3 {
+            assert_eq!(state.next_send_chunk_at(start).unwrap().seq, i);
+        }
+        assert!(state.next_send_chunk_at(start).is_none());
+
+        // Only resend the head, leaving the other chunks timed out.
+        assert_eq!(state.next_send_chunk_at(at(start, 200)).unwrap().seq, 0);
+
+        // The head times out after the other chunks, since it backs off, but
+        // it is still resent first.
+        assert_eq!(state.next_send_chunk_at(at(start, 600)).unwrap().seq, 0);
+        assert_eq!(state.next_send_chunk_at(at(start, 600)).unwrap().seq, 1);
+        assert_eq!(state.next_send_chunk_at(at(start, 600)).unwrap().seq, 2);
+        assert!(state.next_send_chunk_at(at(start, 600)).is_none());
+
+        // Acknowledging the head makes the next chunk the new head.
+        state.handle_ack(&Ack{window_start: 2, window_mask: vec![false, false, false].into()})
+            .unwrap();
+        assert!(state.next_send_chunk_at(at(start, 600)).is_none());
+        assert!(state.next_send_chunk_at(at(start, 999)).is_none());
+        assert_eq!(state.next_send_chunk_at(at(start, 1000)).unwrap().seq, 2);
+        assert!(state.next_send_chunk_at(at(start, 1000)).is_none());
+    }
+
+    #[test]
+    fn max_window() {
+        let (mut sender, mut receiver) = (WwrState::new(1, 65535, 7), WwrState::new(65535, 1, 7));
+        while sender.send_buffer_space() > 0 {
+            sender.push_send_buffer(vec![1]);
+        }
+        let mut chunks = Vec::new();
+        while let Some(chunk) = sender.next_send_chunk() {
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks.len(), 65535);
+        let first = chunks.remove(0);
+        for chunk in chunks.into_iter().rev() {
+            assert_eq!(receiver.handle_chunk(chunk).unwrap().len(), 0);
+        }
+        let ack = receiver.next_send_ack();
+        assert_eq!(ack.window_mask.ones().count(), 65534);
+        sender.handle_ack(&ack).unwrap();
+        assert_eq!(sender.send_buffer_space(), 0);
+
+        assert_eq!(receiver.handle_chunk(first).unwrap().len(), 65535);
+        sender.handle_ack(&receiver.next_send_ack()).unwrap();
+        assert_eq!(sender.send_buffer_space(), 65535);
+        assert!(!receiver.next_send_ack().window_mask.any());
+    }
+
+    #[test]
+    fn invalid_packets() {
+        let mut state = WwrState::new(4, 4, 100);
+        state.push_send_buffer(vec![1]);
+        assert_eq!(state.handle_ack(&Ack{window_start: 100, window_mask: vec![false].into()}),
+            Err(WwrError::AckMaskLength{expected: 3, actual: 1}));
+        assert_eq!(state.handle_chunk(Chunk{seq: 104, data: vec![1]}),
+            Err(WwrError::ChunkPastWindow{seq: 104, window_start: 100}));
+        assert_eq!(state.handle_chunk(Chunk{seq: 99, data: vec![1]}), Ok(Vec::new()));
+        assert_eq!(state.next_send_ack().window_start, 100);
+        assert_eq!(state.send_buffer_space(), 3);
+    }
+
+    #[test]
+    fn fuzz_raw_packets() {
+        let mut rng = thread_rng();
+        for _ in 0..1000 {
+            let (mut state, win_size, _) = random_state(&mut rng);
+            for _ in 0..20 {
+                let data: Vec<u8> = (0..rng.gen_range(0, 48)).map(|_| rng.gen()).collect();
+                let encoding = *rng.choose(&[AckEncoding::Mask, AckEncoding::Ranges]).unwrap();
+                let api_code = *rng.choose(&['t', 'p', 'x']).unwrap();
+                if let Ok(packet) = Packet::decode_query(&data, win_size, encoding, api_code) {
+                    feed_packet(&mut state, packet);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fuzz_near_window() {
+        let mut rng = thread_rng();
+        for _ in 0..1000 {
+            let (mut state, win_size, seq_start) = random_state(&mut rng);
+            for _ in 0..50 {
+                let mask_len = if rng.gen_weighted_bool(10) {
+                    rng.gen_range(0, 20)
+                } else {
+                    win_size.saturating_sub(1) as usize
+                };
+                let offset = Wrapping(rng.gen_range(-20i32, 20) as u32);
+                let packet = Packet{
+                    ack: Ack{
+                        window_start: (Wrapping(seq_start) + offset).0,
+                        window_mask: (0..mask_len).map(|_| rng.gen()).collect()
+                    },
+                    chunk: Some(Chunk{
+                        seq: (Wrapping(seq_start) + offset + Wrapping(rng.gen_range(0, 20))).0,
+                        data: (0..rng.gen_range(0, 3)).map(|_| rng.gen()).collect()
+                    })
+                };
+                feed_packet(&mut state, packet);
+            }
+        }
+    }
+
+    fn random_state<R: Rng>(rng: &mut R) -> (WwrState, u16, u32) {
+        let win_size = rng.gen_range(0, 20);
+        let seq_start = rng.gen();
+        let mut state = WwrState::new(rng.gen_range(0, 20), win_size, seq_start);
+        for _ in 0..rng.gen_range(0, win_size + 1) {
+            state.push_send_buffer(vec![rng.gen()]);
+        }
+        (state, win_size, seq_start)
+    }
+
+    fn feed_packet(state: &mut WwrState, packet: Packet) {
+        state.handle_ack(&packet.ack).ok();
+        if let Some(chunk) = packet.chunk {
+            state.handle_chunk(chunk).ok();
+        }
+        state.next_send_ack();
+        state.next_send_chunk();
+        while state.send_buffer_space() > 0 {
+            state.push_send_buffer(vec![1]);
+        }
+        state.is_done();
+    }
+
+    fn at(start: Instant, millis: u64) -> Instant {
+        start + Duration::from_millis(millis)
+    }
+
     fn trivial_endpoint() -> WwrState {
         WwrState::new(1, 1, 0)
     }
@@ -311,27 +712,27 @@ mod tests {
         let ack1 = end1.next_send_ack();
         let chunk1 = end1.next_send_chunk().unwrap();
         assert_eq!(chunk1.data, vec![1, 2, 3, 4]);
-        end2.handle_ack(&ack1);
-        assert_eq!(end2.handle_chunk(chunk1.clone()), vec![chunk1]);
+        end2.handle_ack(&ack1).unwrap();
+        assert_eq!(end2.handle_chunk(chunk1.clone()).unwrap(), vec![chunk1]);
 
         let ack2 = end2.next_send_ack();
         let chunk2 = end2.next_send_chunk().unwrap();
         assert_eq!(chunk2.data, vec![4, 3, 2, 1, 0]);
-        end1.handle_ack(&ack2);
-        assert_eq!(end1.handle_chunk(chunk2.clone()), vec![chunk2]);
+        end1.handle_ack(&ack2).unwrap();
+        assert_eq!(end1.handle_chunk(chunk2.clone()).unwrap(), vec![chunk2]);
 
         // Simulate the second request.
         let ack3 = end1.next_send_ack();
         let chunk3 = end1.next_send_chunk();
         assert!(chunk3.is_none());
-        end2.handle_ack(&ack3);
+        end2.handle_ack(&ack3).unwrap();
 
         let ack4 = end2.next_send_ack();
         let chunk4 = end2.next_send_chunk();
         assert!(chunk4.is_none());
-        end1.handle_ack(&ack4);
+        end1.handle_ack(&ack4).unwrap();
 
-        // Make sure the round-robin system isn't horribly broken.
+        // Make sure the scheduler isn't horribly broken.
         for _ in 0..3 {
             assert!(end1.next_send_chunk().is_none());
             assert!(end2.next_send_chunk().is_none());
@@ -346,7 +747,7 @@ mod tests {
             seq: endpoint.next_send_ack().window_start,
             data: Vec::new()
         };
-        assert_eq!(endpoint.handle_chunk(empty_chunk.clone()), vec![empty_chunk]);
+        assert_eq!(endpoint.handle_chunk(empty_chunk.clone()).unwrap(), vec![empty_chunk]);
 
         // We've gotten an EOF, but still haven't sent an EOF.
         assert!(!endpoint.is_done());
@@ -362,7 +763,7 @@ mod tests {
             window_start: (Wrapping(next_chunk.seq) + Wrapping(1)).0,
             window_mask: repeat(false).take((endpoint.out_win_size - 1) as usize).collect()
         };
-        endpoint.handle_ack(&ack);
+        endpoint.handle_ack(&ack).unwrap();
         assert!(endpoint.is_done());
     }
 
@@ -371,14 +772,14 @@ mod tests {
             seq: (Wrapping(endpoint.next_send_ack().window_start) + Wrapping(1)).0,
             data: Vec::new()
         };
-        assert_eq!(endpoint.handle_chunk(empty_chunk.clone()).len(), 0);
+        assert_eq!(endpoint.handle_chunk(empty_chunk.clone()).unwrap().len(), 0);
         assert!(!endpoint.is_done());
 
         let data_chunk = Chunk{
             seq: endpoint.next_send_ack().window_start,
             data: vec![1, 2, 3]
         };
-        assert_eq!(endpoint.handle_chunk(data_chunk.clone()), vec![data_chunk, empty_chunk]);
+        assert_eq!(endpoint.handle_chunk(data_chunk.clone()).unwrap(), vec![data_chunk, empty_chunk]);
 
         // We've gotten an EOF, but still haven't sent an EOF.
         assert!(!endpoint.is_done());
@@ -388,7 +789,7 @@ mod tests {
         assert_eq!(out_chunk.data, vec![1, 2, 5, 4]);
 
         endpoint.push_eof();
-        // This is a subtle test for round-robin.
+        // Unsent chunks take priority over chunks that are in flight.
         let eof_chunk = endpoint.next_send_chunk().unwrap();
         assert_eq!(eof_chunk.data.len(), 0);
 
@@ -401,14 +802,14 @@ mod tests {
                 .chain(repeat(false).take((endpoint.out_win_size - 2) as usize))
                 .collect()
         };
-        endpoint.handle_ack(&ack);
+        endpoint.handle_ack(&ack).unwrap();
         assert!(!endpoint.is_done());
 
         let ack = Ack{
             window_start: (Wrapping(out_chunk.seq) + Wrapping(1)).0,
             window_mask: repeat(false).take((endpoint.out_win_size - 1) as usize).collect()
         };
-        endpoint.handle_ack(&ack);
+        endpoint.handle_ack(&ack).unwrap();
         assert!(endpoint.is_done());
     }
 
@@ -422,9 +823,9 @@ mod tests {
                 data: vec![((i + 17) & 0xff) as u8]
             };
             final_chunks.insert(0, chunk.clone());
-            let chunks = endpoint.handle_chunk(chunk);
+            let chunks = endpoint.handle_chunk(chunk).unwrap();
             if i != 0 {
-                window_mask[(i - 1) as usize] = true;
+                window_mask.set((i - 1) as usize, true);
                 assert_eq!(chunks.len(), 0);
                 assert_eq!(endpoint.next_send_ack(), Ack{
                     window_start: start_seq,
@@ -0,0 +1,140 @@
+//! Command-line flags with a configuration file to fall back on.
+//!
+//! Both binaries include this module by path, so that the library itself
+//! does not depend on clap.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::env;
+use std::vec;
+
+use clap::ArgMatches;
+use myodine::config::{Config, Error, PASSWORD_VAR, read_password};
+
+/// Command-line flags, with a configuration file to fall back on.
+///
+/// This has the same accessors as `ArgMatches`. A flag given on the command
+/// line replaces all of its values from the configuration file.
+pub struct Options<'a> {
+    matches: ArgMatches<'a>,
+    config: Config,
+    used: RefCell<HashSet<String>>
+}
+
+impl<'a> Options<'a> {
+    pub fn new(matches: ArgMatches<'a>, config: Config) -> Options<'a> {
+        Options{matches: matches, config: config, used: RefCell::new(HashSet::new())}
+    }
+
+    /// Get the value of a flag.
+    pub fn value_of(&self, name: &str) -> Option<&str> {
+        self.mark_used(name);
+        self.matches.value_of(name).or_else(|| self.config.get(name))
+    }
+
+    /// Get the value of a flag that must be given somewhere.
+    pub fn required(&self, name: &str) -> Result<&str, Error> {
+        self.value_of(name).ok_or_else(|| Error::MissingOption(name.to_owned()))
+    }
+
+    /// Get every value of a repeatable flag.
+    pub fn values_of(&self, name: &str) -> Option<vec::IntoIter<&str>> {
+        self.mark_used(name);
+        if let Some(values) = self.matches.values_of(name) {
+            return Some(values.collect::<Vec<_>>().into_iter());
+        }
+        let values = self.config.get_all(name);
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.into_iter())
+        }
+    }
+
+    /// Check if a flag that takes no value is set.
+    pub fn is_present(&self, name: &str) -> Result<bool, Error> {
+        self.mark_used(name);
+        if self.matches.is_present(name) {
+            return Ok(true);
+        }
+        match self.config.get(name) {
+            None | Some("false") => Ok(false),
+            Some("true") => Ok(true),
+            Some(x) => Err(Error::BadValue{name: name.to_owned(), value: x.to_owned()})
+        }
+    }
+
+    /// Get the password.
+    ///
+    /// It comes from the `password` or `password-file` flags, or from the
+    /// environment variable in `PASSWORD_VAR`. The command line takes
+    /// precedence over the environment, which takes precedence over the
+    /// configuration file.
+    pub fn password(&self) -> Result<String, Error> {
+        self.mark_used("password");
+        self.mark_used("password-file");
+        if let Some(password) = self.matches.value_of("password") {
+            Ok(password.to_owned())
+        } else if let Some(path) = self.matches.value_of("password-file") {
+            read_password(path)
+        } else if let Ok(password) = env::var(PASSWORD_VAR) {
+            Ok(password)
+        } else if let Some(password) = self.config.get("password") {
+            Ok(password.to_owned())
+        } else if let Some(path) = self.config.get("password-file") {
+            read_password(path)
+        } else {
+            Ok(String::new())
+        }
+    }
+
+    /// Make sure that every option in the configuration file was looked up,
+    /// to catch misspelled names.
+    pub fn check_unused(&self) -> Result<(), Error> {
+        let used = self.used.borrow();
+        match self.config.names().into_iter().find(|x| !used.contains(*x)) {
+            Some(name) => Err(Error::UnknownOption(name.to_owned())),
+            None => Ok(())
+        }
+    }
+
+    fn mark_used(&self, name: &str) {
+        self.used.borrow_mut().insert(name.to_owned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{App, Arg};
+
+    #[test]
+    fn options_precedence() {
+        let config: Config = "port = 1\nns = a\nns = b\nsocks = true\nhttp = maybe\nextra = 3"
+            .parse().unwrap();
+        let app = App::new("test")
+            .arg(Arg::with_name("port").long("port").takes_value(true))
+            .arg(Arg::with_name("ns").long("ns").takes_value(true).multiple(true)
+                .number_of_values(1))
+            .arg(Arg::with_name("socks").long("socks"))
+            .arg(Arg::with_name("http").long("http"));
+
+        let options = Options::new(app.clone().get_matches_from(vec!["test"]), config.clone());
+        assert_eq!(options.value_of("port"), Some("1"));
+        assert_eq!(options.values_of("ns").unwrap().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert!(options.is_present("socks").unwrap());
+        assert!(options.is_present("http").is_err());
+        assert_eq!(options.check_unused().unwrap_err().to_string(),
+            "unknown option in config file: extra");
+
+        let args = vec!["test", "--port", "2", "--ns", "c", "--http"];
+        let options = Options::new(app.get_matches_from(args), config);
+        assert_eq!(options.value_of("port"), Some("2"));
+        assert_eq!(options.values_of("ns").unwrap().collect::<Vec<_>>(), vec!["c"]);
+        assert!(options.is_present("http").unwrap());
+        assert_eq!(options.value_of("missing"), None);
+        assert_eq!(options.required("missing").unwrap_err().to_string(),
+            "missing missing argument");
+        assert!(options.values_of("missing").is_none());
+    }
+}
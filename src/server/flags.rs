@@ -1,8 +1,18 @@
+use std::env;
+use std::ffi::OsString;
 use std::time::Duration;
 
 use clap::{App, Arg};
 
-use myodine::dns_proto::Domain;
+use myodine::config::Config;
+use myodine::dns_proto::{Domain, SOADetails};
+use myodine::myo_proto::ip::AddressPool;
+use myodine::myo_proto::util::domain_ends_with;
+
+use forwarder::Network;
+use limits::Limit;
+use options::Options;
+use zone::{GlueAddr, same_domain};
 
 pub struct Flags {
     pub listen_addr: String,
@@ -10,11 +20,38 @@ pub struct Flags {
     pub host: Domain,
     pub conn_timeout: Duration,
     pub session_timeout: Duration,
-    pub proof_window: u64
+    pub proof_window: u64,
+    pub coalesce_delay: Duration,
+    pub response_ttl: u32,
+    pub replay_window: Duration,
+    pub name_servers: Vec<Domain>,
+    pub ns_addrs: Vec<GlueAddr>,
+    pub soa: SOADetails,
+    pub records_file: Option<String>,
+    pub users_file: Option<String>,
+    pub upstream: Option<String>,
+    pub upstream_allow: Vec<Network>,
+    pub upstream_lanes: usize,
+    pub upstream_timeout: Duration,
+    pub ip_net: Option<AddressPool>,
+    pub tun_name: String,
+    pub allow_listen: bool,
+    pub session_limit: Limit,
+    pub user_limit: Limit,
+    pub global_limit: Limit
 }
 
 impl Flags {
+    /// Parse the flags from the command line.
     pub fn parse() -> Result<Flags, String> {
+        Flags::parse_from(env::args_os())
+    }
+
+    /// Parse the flags from a list of arguments, starting with the program
+    /// name.
+    pub fn parse_from<I, T>(args: I) -> Result<Flags, String>
+        where I: IntoIterator<Item = T>, T: Into<OsString> + Clone
+    {
         let matches = App::new("myodine-server")
             .arg(Arg::with_name("addr")
                 .short("a")
@@ -28,6 +65,17 @@ impl Flags {
                 .value_name("VALUE")
                 .help("Set the server password")
                 .takes_value(true))
+            .arg(Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .help("Read default flag values from a config file")
+                .takes_value(true))
+            .arg(Arg::with_name("password-file")
+                .long("password-file")
+                .value_name("FILE")
+                .conflicts_with("password")
+                .help("Read the server password from a file")
+                .takes_value(true))
             .arg(Arg::with_name("proof-win")
                 .short("w")
                 .long("proof-win")
@@ -46,11 +94,154 @@ impl Flags {
                 .value_name("INT")
                 .help("Set the session timeout")
                 .takes_value(true))
+            .arg(Arg::with_name("coalesce-time")
+                .long("coalesce-time")
+                .value_name("INT")
+                .help("Set the time to wait for small writes to coalesce, in milliseconds")
+                .takes_value(true))
+            .arg(Arg::with_name("ns")
+                .long("ns")
+                .value_name("DOMAIN")
+                .help("Add a name server for the zone (default: ns.<host>)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(Arg::with_name("ns-addr")
+                .long("ns-addr")
+                .value_name("[NAME=]IP")
+                .help("Add a glue address for one or all name servers inside the zone")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(Arg::with_name("soa-rname")
+                .long("soa-rname")
+                .value_name("DOMAIN")
+                .help("Set the SOA responsible mailbox (default: hostmaster.<host>)")
+                .takes_value(true))
+            .arg(Arg::with_name("soa-serial")
+                .long("soa-serial")
+                .value_name("INT")
+                .help("Set the SOA serial number")
+                .takes_value(true))
+            .arg(Arg::with_name("soa-refresh")
+                .long("soa-refresh")
+                .value_name("INT")
+                .help("Set the SOA refresh interval, in seconds")
+                .takes_value(true))
+            .arg(Arg::with_name("soa-retry")
+                .long("soa-retry")
+                .value_name("INT")
+                .help("Set the SOA retry interval, in seconds")
+                .takes_value(true))
+            .arg(Arg::with_name("replay-win")
+                .long("replay-win")
+                .value_name("INT")
+                .help("Set how long to replay responses to retransmitted queries, in milliseconds")
+                .takes_value(true))
+            .arg(Arg::with_name("ttl")
+                .long("ttl")
+                .value_name("INT")
+                .help("Set the TTL for discovery and establishment responses, in seconds")
+                .takes_value(true))
+            .arg(Arg::with_name("soa-expire")
+                .long("soa-expire")
+                .value_name("INT")
+                .help("Set the SOA expire time, in seconds")
+                .takes_value(true))
+            .arg(Arg::with_name("soa-minimum")
+                .long("soa-minimum")
+                .value_name("INT")
+                .help("Set the SOA minimum (negative caching) TTL, in seconds")
+                .takes_value(true))
+            .arg(Arg::with_name("records")
+                .long("records")
+                .value_name("FILE")
+                .help("Serve static DNS records from a zone file")
+                .takes_value(true))
+            .arg(Arg::with_name("users")
+                .long("users")
+                .value_name("FILE")
+                .help("Give each user their own secret instead of the shared password")
+                .takes_value(true))
+            .arg(Arg::with_name("upstream")
+                .long("upstream")
+                .value_name("ADDR:PORT")
+                .help("Forward queries outside of the host domain to a resolver")
+                .takes_value(true))
+            .arg(Arg::with_name("upstream-allow")
+                .long("upstream-allow")
+                .value_name("CIDR")
+                .help("Let clients in a network use the upstream resolver (default: loopback only)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+            .arg(Arg::with_name("upstream-lanes")
+                .long("upstream-lanes")
+                .value_name("INT")
+                .help("Set the maximum number of forwarded queries in flight")
+                .takes_value(true))
+            .arg(Arg::with_name("upstream-timeout")
+                .long("upstream-timeout")
+                .value_name("INT")
+                .help("Set the timeout for forwarded queries, in milliseconds")
+                .takes_value(true))
+            .arg(Arg::with_name("ip-net")
+                .long("ip-net")
+                .value_name("CIDR")
+                .help("Allow IP tunnels, assigning client addresses from a network")
+                .takes_value(true))
+            .arg(Arg::with_name("tun")
+                .long("tun")
+                .value_name("NAME")
+                .help("Set the name of the tun device for IP tunnels (default: myodine0)")
+                .takes_value(true))
+            .arg(Arg::with_name("allow-listen")
+                .long("allow-listen")
+                .help("Let clients listen on ports of this machine for remote forwarding"))
+            .arg(Arg::with_name("session-query-rate")
+                .long("session-query-rate")
+                .value_name("INT")
+                .help("Limit the transfer queries per second for each session")
+                .takes_value(true))
+            .arg(Arg::with_name("session-byte-rate")
+                .long("session-byte-rate")
+                .value_name("INT")
+                .help("Limit the bytes of data per second for each session")
+                .takes_value(true))
+            .arg(Arg::with_name("user-query-rate")
+                .long("user-query-rate")
+                .value_name("INT")
+                .help("Limit the transfer queries per second for all of a user's sessions")
+                .takes_value(true))
+            .arg(Arg::with_name("user-byte-rate")
+                .long("user-byte-rate")
+                .value_name("INT")
+                .help("Limit the bytes of data per second for all of a user's sessions")
+                .takes_value(true))
+            .arg(Arg::with_name("query-rate")
+                .long("query-rate")
+                .value_name("INT")
+                .help("Limit the transfer queries per second for all sessions together")
+                .takes_value(true))
+            .arg(Arg::with_name("byte-rate")
+                .long("byte-rate")
+                .value_name("INT")
+                .help("Limit the bytes of data per second for all sessions together")
+                .takes_value(true))
             .arg(Arg::with_name("host")
                 .help("Set the root domain name of the proxy")
-                .required(true)
                 .index(1))
-            .get_matches();
+            .after_help("The password may also be set with the MYODINE_PASSWORD environment \
+                variable.\nA users file has one `NAME SECRET [HOST:PORT ...] [query-rate=N] \
+                [byte-rate=N]` line per user.\nWhen a rate limit is hit, sessions get \
+                responses without data until the rate drops.\nA config file has one \
+                `name = value` line per flag, using long flag names.")
+            .get_matches_from(args);
+        let config = match matches.value_of("config") {
+            Some(path) => Config::load(path).map_err(|e| format!("{}: {}", path, e))?,
+            None => Config::new()
+        };
+        let matches = Options::new(matches, config);
 
         macro_rules! parse_arg {
             ( $name:expr, $default:expr ) => {
@@ -59,13 +250,94 @@ impl Flags {
             }
         }
 
-        Ok(Flags{
+        macro_rules! parse_optional {
+            ( $name:expr ) => {
+                match matches.value_of($name) {
+                    Some(x) => x.parse().map(Some)
+                        .map_err(|e| format!("bad {} argument: {}", $name, e)),
+                    None => Ok(None)
+                }
+            }
+        }
+
+        macro_rules! parse_list {
+            ( $name:expr ) => {
+                matches.values_of($name).map(|x| x.collect()).unwrap_or(Vec::new()).into_iter()
+                    .map(|x: &str| x.parse().map_err(|e| format!("bad {} argument: {}", $name, e)))
+                    .collect::<Result<Vec<_>, String>>()
+            }
+        }
+
+        let host: Domain = matches.required("host").map_err(|e| e.to_string())?.parse()
+            .map_err(|e| format!("bad host argument: {}", e))?;
+        let mut name_servers: Vec<Domain> = parse_list!("ns")?;
+        if name_servers.is_empty() {
+            name_servers.push(format!("ns.{}", host).parse()
+                .map_err(|e| format!("bad host argument: {}", e))?);
+        }
+        let ns_addrs: Vec<GlueAddr> = parse_list!("ns-addr")?;
+        let mut upstream_allow: Vec<Network> = parse_list!("upstream-allow")?;
+        if upstream_allow.is_empty() {
+            upstream_allow.push("127.0.0.0/8".parse().unwrap());
+            upstream_allow.push("::1/128".parse().unwrap());
+        }
+        for name in ns_addrs.iter().filter_map(|x| x.name.as_ref()) {
+            let is_glue_name = name_servers.iter()
+                .any(|ns| domain_ends_with(ns, &host) && same_domain(ns, name));
+            if !is_glue_name {
+                return Err(format!("bad ns-addr argument: {} is not a name server inside the \
+                    zone", name));
+            }
+        }
+        let default_rname = format!("hostmaster.{}", host);
+
+        let flags = Flags{
             listen_addr: matches.value_of("addr").unwrap_or("0.0.0.0:53").to_owned(),
-            password: matches.value_of("password").unwrap_or("").to_owned(),
-            host: parse_arg!("host", "")?,
+            password: matches.password().map_err(|e| e.to_string())?,
+            soa: SOADetails{
+                master_name: name_servers[0].clone(),
+                responsible_name: parse_arg!("soa-rname", &default_rname)?,
+                serial: parse_arg!("soa-serial", "1")?,
+                refresh: parse_arg!("soa-refresh", "3600")?,
+                retry: parse_arg!("soa-retry", "600")?,
+                expire: parse_arg!("soa-expire", "86400")?,
+                minimum: parse_arg!("soa-minimum", "0")?
+            },
+            name_servers: name_servers,
+            ns_addrs: ns_addrs,
+            records_file: matches.value_of("records").map(String::from),
+            users_file: matches.value_of("users").map(String::from),
+            upstream: matches.value_of("upstream").map(String::from),
+            upstream_allow: upstream_allow,
+            upstream_lanes: parse_arg!("upstream-lanes", "32")?,
+            upstream_timeout: Duration::from_millis(parse_arg!("upstream-timeout", "2000")?),
+            ip_net: match matches.value_of("ip-net") {
+                Some(net) => Some(net.parse().map_err(|e| format!("bad ip-net argument: {}", e))?),
+                None => None
+            },
+            tun_name: matches.value_of("tun").unwrap_or("myodine0").to_owned(),
+            allow_listen: matches.is_present("allow-listen").map_err(|e| e.to_string())?,
+            session_limit: Limit{
+                queries: parse_optional!("session-query-rate")?,
+                bytes: parse_optional!("session-byte-rate")?
+            },
+            user_limit: Limit{
+                queries: parse_optional!("user-query-rate")?,
+                bytes: parse_optional!("user-byte-rate")?
+            },
+            global_limit: Limit{
+                queries: parse_optional!("query-rate")?,
+                bytes: parse_optional!("byte-rate")?
+            },
+            host: host,
             conn_timeout: Duration::from_secs(parse_arg!("conn-timeout", "5")?),
             session_timeout: Duration::from_secs(parse_arg!("sess-timeout", "60")?),
-            proof_window: parse_arg!("proof-win", "120")?
-        })
+            proof_window: parse_arg!("proof-win", "120")?,
+            coalesce_delay: Duration::from_millis(parse_arg!("coalesce-time", "0")?),
+            response_ttl: parse_arg!("ttl", "0")?,
+            replay_window: Duration::from_millis(parse_arg!("replay-win", "5000")?)
+        };
+        matches.check_unused().map_err(|e| e.to_string())?;
+        Ok(flags)
     }
 }
@@ -0,0 +1,486 @@
+use std::io;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use myodine::conn::dial_udp;
+use myodine::dns_coding::{dns_decode, dns_encode};
+use myodine::dns_proto::{Domain, Header, ResponseCode};
+use myodine::myo_proto::util::domain_part_equal;
+use rand::{Rng, thread_rng};
+
+/// The largest upstream answer that can be relayed.
+const MAX_ANSWER: usize = 65535;
+
+/// The number of queries a lane sends from one socket before dialing again.
+const QUERIES_PER_SOCKET: usize = 16;
+
+/// Relays queries to an upstream resolver.
+///
+/// Queries and answers are relayed byte for byte, apart from the message ID,
+/// so names and records that myodine itself cannot decode still work.
+///
+/// Each lane carries one query at a time over its own socket. Answers are
+/// sent straight back to the original client from the lane's thread, so
+/// forwarding never blocks the main server loop.
+pub struct Forwarder {
+    allowed: Vec<Network>,
+    lanes: Vec<Sender<PendingQuery>>,
+    busy: Arc<Mutex<Vec<bool>>>,
+    socket: UdpSocket
+}
+
+struct PendingQuery {
+    query: Vec<u8>,
+    client: SocketAddr
+}
+
+/// A range of client addresses in CIDR notation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u8
+}
+
+impl Forwarder {
+    /// Start forwarding to an upstream resolver.
+    ///
+    /// # Arguments
+    ///
+    /// * `upstream` - An "IP:port" pair for the resolver.
+    /// * `allowed` - The networks of the clients that may use the resolver.
+    /// * `lanes` - The maximum number of queries in flight.
+    /// * `timeout` - The time to wait for an upstream answer.
+    /// * `socket` - The socket on which to send answers to clients.
+    pub fn new(
+        upstream: &str,
+        allowed: Vec<Network>,
+        lanes: usize,
+        timeout: Duration,
+        socket: UdpSocket
+    ) -> io::Result<Forwarder> {
+        let busy = Arc::new(Mutex::new(vec![false; lanes]));
+        let mut senders = Vec::new();
+        for lane in 0..lanes {
+            let (sender, receiver) = channel();
+            senders.push(sender);
+            let upstream = upstream.to_owned();
+            let busy = busy.clone();
+            let socket = socket.try_clone()?;
+            spawn(move || {
+                run_lane(lane, &upstream, timeout, receiver, busy, socket);
+            });
+        }
+        Ok(Forwarder{allowed: allowed, lanes: senders, busy: busy, socket: socket})
+    }
+
+    /// Forward a raw query on behalf of a client.
+    ///
+    /// Clients outside of the allowed networks are refused straight away.
+    ///
+    /// Returns false if too many queries are already in flight.
+    pub fn forward(&self, query: &[u8], client: SocketAddr) -> bool {
+        if !self.allowed.iter().any(|x| x.contains(&client.ip())) {
+            if let Some(reply) = error_reply(query, ResponseCode::Refused) {
+                send_reply(&self.socket, &reply, &client);
+            }
+            return true;
+        }
+        let mut busy = self.busy.lock().unwrap();
+        match busy.iter().position(|x| !*x) {
+            Some(lane) => {
+                busy[lane] = true;
+                self.lanes[lane].send(PendingQuery{query: query.to_vec(), client: client}).ok();
+                true
+            },
+            None => false
+        }
+    }
+}
+
+impl Network {
+    /// Check if an address is inside the network.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, *addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            },
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            },
+            _ => false
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    /// Parse a network in CIDR notation, such as "10.0.0.0/8" or "fd00::/8".
+    fn from_str(s: &str) -> Result<Network, String> {
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap_or("").parse()
+            .map_err(|e| format!("bad network address: {}", e))?;
+        let prefix_len = parts.next().ok_or(format!("missing prefix length: {}", s))?.parse()
+            .map_err(|e| format!("bad prefix length: {}", e))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err(format!("bad prefix length: {}", prefix_len));
+        }
+        Ok(Network{addr: addr, prefix_len: prefix_len})
+    }
+}
+
+/// Check if a raw packet is a query for a name outside of `host`.
+///
+/// The name is read from the raw packet, so that queries for names which
+/// myodine cannot decode, like `_sip._udp.example.com`, are still found.
+pub fn is_foreign_query(packet: &[u8], host: &Domain) -> bool {
+    if packet.len() < 12 || packet[2] & 0x80 != 0 {
+        return false;
+    }
+    match read_question(packet) {
+        Some((labels, _)) => {
+            let offset = labels.len().saturating_sub(host.parts().len());
+            labels.len() < host.parts().len() || labels[offset..].iter().zip(host.parts())
+                .any(|(x, y)| !domain_part_equal(&String::from_utf8_lossy(x), y))
+        },
+        None => false
+    }
+}
+
+/// Read the labels of the only question in a raw query, along with the
+/// offset just past the question.
+fn read_question(packet: &[u8]) -> Option<(Vec<&[u8]>, usize)> {
+    if packet.len() < 12 || packet[4..6] != [0, 1] {
+        return None;
+    }
+    let mut labels = Vec::new();
+    let mut offset = 12;
+    loop {
+        let size = *packet.get(offset)? as usize;
+        offset += 1;
+        if size == 0 {
+            break;
+        } else if size & 0xc0 != 0 || offset + size > packet.len() {
+            return None;
+        }
+        labels.push(&packet[offset..(offset + size)]);
+        offset += size;
+    }
+    // Skip the type and class.
+    if offset + 4 > packet.len() {
+        return None;
+    }
+    Some((labels, offset + 4))
+}
+
+/// Create a response to a raw query with the question and no records.
+///
+/// Returns None if the query does not have a single readable question.
+fn error_reply(query: &[u8], code: ResponseCode) -> Option<Vec<u8>> {
+    let (_, question_end) = read_question(query)?;
+    let header = dns_decode::<Header>(query[0..12].to_vec()).ok()?;
+    let mut reply = dns_encode(&Header{
+        is_response: true,
+        authoritative: false,
+        truncated: false,
+        recursion_available: false,
+        response_code: code,
+        question_count: 1,
+        answer_count: 0,
+        authority_count: 0,
+        additional_count: 0,
+        ..header
+    }).ok()?;
+    reply.extend_from_slice(&query[12..question_end]);
+    Some(reply)
+}
+
+fn prefix_matches(net: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+    let (bytes, bits) = ((prefix_len / 8) as usize, prefix_len % 8);
+    if net[0..bytes] != addr[0..bytes] {
+        return false;
+    }
+    bits == 0 || (net[bytes] ^ addr[bytes]) >> (8 - bits) == 0
+}
+
+fn run_lane(
+    lane: usize,
+    upstream: &str,
+    timeout: Duration,
+    queries: Receiver<PendingQuery>,
+    busy: Arc<Mutex<Vec<bool>>>,
+    socket: UdpSocket
+) {
+    // The connection is dropped after any socket error and dialed again for
+    // the next query, so that a lane never stays dead. It is also dialed
+    // again every so often, so that together with random IDs the source port
+    // is hard for an off-path attacker to guess.
+    let mut rng = thread_rng();
+    let mut conn = None;
+    let mut uses = 0;
+    for PendingQuery{mut query, client} in queries {
+        if uses == QUERIES_PER_SOCKET {
+            conn = None;
+        }
+        if conn.is_none() {
+            uses = 0;
+        }
+        uses += 1;
+        let id: u16 = rng.gen();
+        let client_id = [query[0], query[1]];
+        query[0] = (id >> 8) as u8;
+        query[1] = id as u8;
+        let reply = match exchange(&mut conn, upstream, &query, timeout) {
+            Ok(Some(mut answer)) => {
+                answer[0] = client_id[0];
+                answer[1] = client_id[1];
+                Some(answer)
+            },
+            Ok(None) => {
+                query[0] = client_id[0];
+                query[1] = client_id[1];
+                error_reply(&query, ResponseCode::ServerFailure)
+            },
+            Err(err) => {
+                eprintln!("upstream lane {}: {}", lane, err);
+                conn = None;
+                query[0] = client_id[0];
+                query[1] = client_id[1];
+                error_reply(&query, ResponseCode::ServerFailure)
+            }
+        };
+        if let Some(reply) = reply {
+            send_reply(&socket, &reply, &client);
+        }
+        busy.lock().unwrap()[lane] = false;
+    }
+}
+
+/// Send a query upstream and wait for the answer with the same ID.
+///
+/// Returns None if no answer arrives in time.
+fn exchange(
+    conn: &mut Option<UdpSocket>,
+    upstream: &str,
+    query: &[u8],
+    timeout: Duration
+) -> io::Result<Option<Vec<u8>>> {
+    if conn.is_none() {
+        *conn = Some(dial_udp(upstream)?);
+    }
+    let socket = conn.as_ref().unwrap();
+    socket.send(query)?;
+    let deadline = Instant::now() + timeout;
+    let mut buffer = vec![0u8; MAX_ANSWER];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        match socket.recv(&mut buffer) {
+            Ok(size) => {
+                if size >= 12 && buffer[0..2] == query[0..2] && buffer[2] & 0x80 != 0 {
+                    return Ok(Some(buffer[0..size].to_vec()));
+                }
+            },
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock ||
+                err.kind() == ErrorKind::TimedOut => return Ok(None),
+            Err(err) => return Err(err)
+        }
+    }
+}
+
+fn send_reply(socket: &UdpSocket, reply: &[u8], client: &SocketAddr) {
+    if socket.send_to(reply, client).is_err() {
+        eprintln!("send to {} failed", client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+    use flags::Flags;
+
+    #[test]
+    fn networks() {
+        let net: Network = "10.1.2.0/23".parse().unwrap();
+        assert!(net.contains(&"10.1.3.255".parse().unwrap()));
+        assert!(!net.contains(&"10.1.4.0".parse().unwrap()));
+        assert!(!net.contains(&"::ffff:10.1.2.1".parse().unwrap()));
+        let net: Network = "fd00::/8".parse().unwrap();
+        assert!(net.contains(&"fdab::1".parse().unwrap()));
+        assert!(!net.contains(&"fe80::1".parse().unwrap()));
+        let all: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&"192.0.2.1".parse().unwrap()));
+        let one: Network = "192.0.2.1/32".parse().unwrap();
+        assert!(one.contains(&"192.0.2.1".parse().unwrap()));
+        assert!(!one.contains(&"192.0.2.2".parse().unwrap()));
+        assert!("10.0.0.0".parse::<Network>().is_err());
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("nope/8".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn foreign_queries() {
+        let host = "tun.test".parse().unwrap();
+        assert!(is_foreign_query(&raw_query(7, "example.com"), &host));
+        assert!(is_foreign_query(&raw_query(7, "_sip._udp.example.com"), &host));
+        assert!(is_foreign_query(&raw_query(7, "test"), &host));
+        assert!(is_foreign_query(&raw_query(7, "xtun.test"), &host));
+        assert!(!is_foreign_query(&raw_query(7, "tun.test"), &host));
+        assert!(!is_foreign_query(&raw_query(7, "_x.TUN.test"), &host));
+
+        let mut response = raw_query(7, "example.com");
+        response[2] |= 0x80;
+        assert!(!is_foreign_query(&response, &host));
+        let query = raw_query(7, "example.com");
+        assert!(!is_foreign_query(&query[0..(query.len() - 1)], &host));
+    }
+
+    #[test]
+    fn raw_forwarding() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let forwarder = test_forwarder(&upstream.local_addr().unwrap().to_string(),
+            vec!["127.0.0.0/8".parse().unwrap()]);
+        let client = test_client();
+
+        // An underscore label and an EDNS record both make it through.
+        let mut query = raw_query(0xbeef, "_sip._udp.example.com");
+        query[11] = 1;
+        query.extend_from_slice(&[0, 0, 41, 4, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(forwarder.forward(&query, client.local_addr().unwrap()));
+
+        let mut buffer = [0u8; 512];
+        let (size, resolver) = upstream.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[2..size], &query[2..]);
+        let mut answer = buffer[0..size].to_vec();
+        answer[2] |= 0x80;
+        answer.extend_from_slice(&[0xc0, 0x0c]);
+        upstream.send_to(&answer, resolver).unwrap();
+
+        let size = client.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[0..2], &[0xbe, 0xef]);
+        assert_eq!(&buffer[2..size], &answer[2..]);
+    }
+
+    #[test]
+    fn refuse_other_clients() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let forwarder = test_forwarder(&upstream.local_addr().unwrap().to_string(),
+            vec!["10.0.0.0/8".parse().unwrap()]);
+        let client = test_client();
+        let mut query = raw_query(0x1234, "example.com");
+        query[11] = 1;
+        query.extend_from_slice(&[0, 0, 41, 4, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(forwarder.forward(&query, client.local_addr().unwrap()));
+        let header = recv_header(&client);
+        assert_eq!(header.identifier, 0x1234);
+        assert!(header.is_response);
+        assert_eq!(header.response_code, ResponseCode::Refused);
+        assert_eq!(header.additional_count, 0);
+    }
+
+    #[test]
+    fn revive_lanes() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        drop(upstream);
+        let forwarder = test_forwarder(&upstream_addr.to_string(),
+            vec!["127.0.0.1/32".parse().unwrap()]);
+        let client = test_client();
+
+        // Nothing is listening, so the only lane fails.
+        assert!(forwarder.forward(&raw_query(1, "example.com"), client.local_addr().unwrap()));
+        assert_eq!(recv_header(&client).response_code, ResponseCode::ServerFailure);
+
+        let upstream = UdpSocket::bind(upstream_addr).unwrap();
+        while !forwarder.forward(&raw_query(2, "example.com"), client.local_addr().unwrap()) {
+            sleep(Duration::from_millis(10));
+        }
+        let mut buffer = [0u8; 512];
+        let (size, resolver) = upstream.recv_from(&mut buffer).unwrap();
+        buffer[2] |= 0x80;
+        upstream.send_to(&buffer[0..size], resolver).unwrap();
+        let header = recv_header(&client);
+        assert_eq!(header.identifier, 2);
+        assert_eq!(header.response_code, ResponseCode::NoError);
+    }
+
+    #[test]
+    fn source_ports() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let forwarder = test_forwarder(&upstream.local_addr().unwrap().to_string(),
+            vec!["127.0.0.1/32".parse().unwrap()]);
+        let client = test_client();
+
+        // Every query gets a fresh ID, and the lane moves to a new socket
+        // once it has sent enough queries from the old one.
+        let mut ids = Vec::new();
+        let mut resolvers = Vec::new();
+        for i in 0..(QUERIES_PER_SOCKET + 1) {
+            while !forwarder.forward(&raw_query(7, "example.com"), client.local_addr().unwrap()) {
+                sleep(Duration::from_millis(1));
+            }
+            let mut buffer = [0u8; 512];
+            let (size, resolver) = upstream.recv_from(&mut buffer).unwrap();
+            ids.push((buffer[0], buffer[1]));
+            resolvers.push(resolver);
+            buffer[2] |= 0x80;
+            upstream.send_to(&buffer[0..size], resolver).unwrap();
+            assert_eq!(recv_header(&client).identifier, 7, "query {}", i);
+        }
+        ids.sort();
+        ids.dedup();
+        assert!(ids.len() > 1);
+        assert!(resolvers[0..QUERIES_PER_SOCKET].iter().all(|x| *x == resolvers[0]));
+        assert!(resolvers[QUERIES_PER_SOCKET] != resolvers[0]);
+    }
+
+    #[test]
+    fn default_allow_list() {
+        let flags = Flags::parse_from(vec!["myodine-server", "tun.test",
+            "--upstream", "127.0.0.1:53"]).unwrap();
+        let allowed = |addr: &str| flags.upstream_allow.iter()
+            .any(|x| x.contains(&addr.parse().unwrap()));
+        assert!(allowed("127.0.0.1"));
+        assert!(allowed("127.3.2.1"));
+        assert!(allowed("::1"));
+        assert!(!allowed("192.0.2.1"));
+        assert!(!allowed("::2"));
+    }
+
+    fn test_forwarder(upstream: &str, allowed: Vec<Network>) -> Forwarder {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        Forwarder::new(upstream, allowed, 1, Duration::from_secs(2), socket).unwrap()
+    }
+
+    fn test_client() -> UdpSocket {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client
+    }
+
+    fn recv_header(client: &UdpSocket) -> Header {
+        let mut buffer = [0u8; 512];
+        let size = client.recv(&mut buffer).unwrap();
+        dns_decode(buffer[0..size].to_vec()).unwrap()
+    }
+
+    fn raw_query(id: u16, name: &str) -> Vec<u8> {
+        let mut result = vec![(id >> 8) as u8, id as u8, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            result.push(label.len() as u8);
+            result.extend_from_slice(label.as_bytes());
+        }
+        result.extend_from_slice(&[0, 0, 1, 0, 1]);
+        result
+    }
+}
@@ -0,0 +1,151 @@
+use std::time::Instant;
+
+/// Rates that a group of transfer queries may not exceed.
+///
+/// A rate of None means there is no limit.
+#[derive(Clone, Copy, Default)]
+pub struct Limit {
+    /// Full responses per second.
+    pub queries: Option<u64>,
+
+    /// Bytes of data per second, counting both directions.
+    pub bytes: Option<u64>
+}
+
+impl Limit {
+    /// Fill in the rates that this limit leaves out from another limit.
+    pub fn or(&self, other: Limit) -> Limit {
+        Limit{queries: self.queries.or(other.queries), bytes: self.bytes.or(other.bytes)}
+    }
+}
+
+/// Token buckets which enforce a limit.
+///
+/// Each bucket holds up to one second's worth of tokens. A query takes one
+/// token and each byte of data takes one token. Data is only counted once
+/// it has been transferred, so the byte bucket may go into debt, in which
+/// case it stays limited until the debt is paid off.
+pub struct Limiter {
+    queries: Option<TokenBucket>,
+    bytes: Option<TokenBucket>
+}
+
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_fill: Instant
+}
+
+impl Limiter {
+    pub fn new(limit: Limit) -> Limiter {
+        Limiter{
+            queries: limit.queries.map(TokenBucket::new),
+            bytes: limit.bytes.map(TokenBucket::new)
+        }
+    }
+
+    /// Check if another full response would exceed the limit.
+    pub fn is_limited(&mut self) -> bool {
+        self.queries.as_mut().map(|x| x.available() < 1.0).unwrap_or(false) ||
+            self.bytes.as_mut().map(|x| x.available() <= 0.0).unwrap_or(false)
+    }
+
+    /// Count a full response, which transferred some bytes of data.
+    pub fn record(&mut self, bytes: usize) {
+        if let Some(ref mut bucket) = self.queries {
+            bucket.take(1.0);
+        }
+        if let Some(ref mut bucket) = self.bytes {
+            bucket.take(bytes as f64);
+        }
+    }
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> TokenBucket {
+        TokenBucket{rate: rate as f64, tokens: rate as f64, last_fill: Instant::now()}
+    }
+
+    fn available(&mut self) -> f64 {
+        let now = Instant::now();
+        let elapsed = now - self.last_fill;
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.tokens = (self.tokens + secs * self.rate).min(self.rate);
+        self.last_fill = now;
+        self.tokens
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn burst_capacity() {
+        let mut limiter = Limiter::new(Limit{queries: Some(5), bytes: None});
+        for _ in 0..5 {
+            assert!(!limiter.is_limited());
+            limiter.record(1000000);
+        }
+        assert!(limiter.is_limited());
+
+        let mut unlimited = Limiter::new(Limit::default());
+        for _ in 0..1000 {
+            unlimited.record(1000000);
+        }
+        assert!(!unlimited.is_limited());
+    }
+
+    #[test]
+    fn refill() {
+        let mut limiter = Limiter::new(Limit{queries: Some(10), bytes: None});
+        for _ in 0..10 {
+            limiter.record(0);
+        }
+        assert!(limiter.is_limited());
+        rewind(&mut limiter.queries, Duration::from_millis(150));
+        assert!(!limiter.is_limited());
+        limiter.record(0);
+        assert!(limiter.is_limited());
+
+        // Idle time never fills a bucket past one second's worth.
+        rewind(&mut limiter.queries, Duration::from_secs(60));
+        assert_eq!(limiter.queries.as_mut().unwrap().available(), 10.0);
+    }
+
+    #[test]
+    fn byte_debt() {
+        let mut limiter = Limiter::new(Limit{queries: None, bytes: Some(1000)});
+        limiter.record(999);
+        assert!(!limiter.is_limited());
+        limiter.record(1501);
+        assert!(limiter.is_limited());
+
+        // The debt of 1500 bytes takes one and a half seconds to pay off.
+        rewind(&mut limiter.bytes, Duration::from_millis(1400));
+        assert!(limiter.is_limited());
+        rewind(&mut limiter.bytes, Duration::from_millis(200));
+        assert!(!limiter.is_limited());
+    }
+
+    #[test]
+    fn fallback_limits() {
+        let user = Limit{queries: Some(3), bytes: None};
+        let default = Limit{queries: Some(10), bytes: Some(2000)};
+        let result = user.or(default);
+        assert_eq!((result.queries, result.bytes), (Some(3), Some(2000)));
+        let result = Limit::default().or(user);
+        assert_eq!((result.queries, result.bytes), (Some(3), None));
+    }
+
+    /// Pretend that a bucket was last filled some time earlier.
+    fn rewind(bucket: &mut Option<TokenBucket>, time: Duration) {
+        let bucket = bucket.as_mut().unwrap();
+        bucket.last_fill -= time;
+    }
+}
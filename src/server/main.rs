@@ -1,18 +1,32 @@
 extern crate clap;
 extern crate myodine;
+extern crate rand;
 
 mod flags;
+mod forwarder;
+mod limits;
+mod records;
 mod session;
 mod server;
+mod users;
+mod zone;
+#[path = "../options.rs"]
+mod options;
 
 use std::net::UdpSocket;
 use std::process::exit;
 
+#[cfg(target_os = "linux")]
+use myodine::conn::TunDevice;
 use myodine::dns_coding::{dns_decode, dns_encode};
-use myodine::dns_proto::Message;
+use myodine::dns_proto::{Header, Message, ResponseCode};
+use myodine::myo_proto::ip::{AddressPool, Router};
 
 use flags::Flags;
-use server::Server;
+use forwarder::{Forwarder, is_foreign_query};
+use records::read_records;
+use server::{Server, error_response};
+use users::read_users;
 
 fn main() {
     if let Err(msg) = main_or_err() {
@@ -29,7 +43,28 @@ fn main_or_err() -> Result<(), String> {
     socket.set_read_timeout(Some(flags.session_timeout / 2))
         .map_err(|e| format!("socket error: {}", e))?;
 
-    let mut server = Server::new(flags);
+    let records = match flags.records_file {
+        Some(ref path) => read_records(path, &flags.host)?,
+        None => Vec::new()
+    };
+    let users = match flags.users_file {
+        Some(ref path) => Some(read_users(path)?),
+        None => None
+    };
+    let forwarder = match flags.upstream {
+        Some(ref addr) => {
+            let out_socket = socket.try_clone().map_err(|e| format!("socket error: {}", e))?;
+            Some(Forwarder::new(addr, flags.upstream_allow.clone(), flags.upstream_lanes,
+                flags.upstream_timeout, out_socket).map_err(|e| format!("socket error: {}", e))?)
+        },
+        None => None
+    };
+    let router = match flags.ip_net {
+        Some(ref pool) => Some(open_router(&flags.tun_name, pool.clone())?),
+        None => None
+    };
+    let host = flags.host.clone();
+    let mut server = Server::new(flags, records, users, router);
     loop {
         server.garbage_collect();
         let mut buf = [0; 2048];
@@ -39,22 +74,72 @@ fn main_or_err() -> Result<(), String> {
             continue;
         }
         let (size, sender_addr) = result.unwrap();
-        if let Ok(mut message) = dns_decode::<Message>(buf[0..size].to_vec()) {
-            if message.additional.len() > 0 {
-                message.additional.clear();
-                message.header.additional_count = 0;
+        if let Some(ref forwarder) = forwarder {
+            // Forwarded queries keep their additional records, such as EDNS.
+            if is_foreign_query(&buf[0..size], &host) {
+                if !forwarder.forward(&buf[0..size], sender_addr) {
+                    eprintln!("too many forwarded queries; dropping query from {}", sender_addr);
+                }
+                continue;
             }
-            match server.handle_message(message) {
-                Ok(response) => match dns_encode(&response) {
-                    Ok(out_buf) => {
-                        if socket.send_to(&out_buf, &sender_addr).is_err() {
-                            eprintln!("send to {} failed", sender_addr);
-                        }
-                    },
-                    Err(err) => eprintln!("error encoding response to {}: {}", sender_addr, err)
+        }
+        let response = match dns_decode::<Message>(buf[0..size].to_vec()) {
+            Ok(mut message) => {
+                if !message.additional.is_empty() {
+                    message.additional.clear();
+                    message.header.additional_count = 0;
+                }
+                server.handle_message(message)
+            },
+            Err(err) => {
+                eprintln!("error decoding query from {}: {}", sender_addr, err);
+                if let Some(response) = format_error(&buf[0..size]) {
+                    response
+                } else {
+                    continue;
                 }
-                Err(err) => eprintln!("error processing query from {}: {}", sender_addr, err)
             }
+        };
+        match dns_encode(&response) {
+            Ok(out_buf) => {
+                if socket.send_to(&out_buf, &sender_addr).is_err() {
+                    eprintln!("send to {} failed", sender_addr);
+                }
+            },
+            Err(err) => eprintln!("error encoding response to {}: {}", sender_addr, err)
         }
     }
 }
+
+/// Set up a tun device for IP tunnels.
+#[cfg(target_os = "linux")]
+fn open_router(name: &str, pool: AddressPool) -> Result<Router, String> {
+    let device = TunDevice::open(name).map_err(|e| format!("open tun device: {}", e))?;
+    device.configure(pool.gateway(), pool.prefix_len())
+        .map_err(|e| format!("configure {}: {}", device.name(), e))?;
+    println!("routing IP tunnels through {} ({}/{})", device.name(), pool.gateway(),
+        pool.prefix_len());
+    Router::new(device, pool).map_err(|e| format!("tun device error: {}", e))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_router(_: &str, _: AddressPool) -> Result<Router, String> {
+    Err("tun devices are only supported on Linux".to_owned())
+}
+
+/// Create a FORMERR response to a message that could not be decoded.
+///
+/// Returns None if there is not even a query header to respond to.
+fn format_error(data: &[u8]) -> Option<Message> {
+    let header = dns_decode::<Header>(data.to_vec()).ok()?;
+    if header.is_response {
+        return None;
+    }
+    Some(error_response(Message{
+        header: Header{question_count: 0, ..header},
+        questions: Vec::new(),
+        answers: Vec::new(),
+        authorities: Vec::new(),
+        additional: Vec::new()
+    }, ResponseCode::FormatError))
+}
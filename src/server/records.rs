@@ -0,0 +1,246 @@
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+
+use myodine::dns_coding;
+use myodine::dns_coding::{EncPacket, Encoder};
+use myodine::dns_proto::{Domain, Record, RecordBody, RecordClass, RecordHeader, RecordType};
+use myodine::myo_proto::util::domain_ends_with;
+
+/// The TTL for static records which do not specify one.
+const DEFAULT_TTL: u32 = 300;
+
+/// Read static records from a zone file.
+///
+/// Each line has the form `NAME [TTL] TYPE DATA`. Names are relative to
+/// `host` unless they end with a `.`, and `@` stands for `host` itself.
+/// Every record must be inside the `host` zone. Comments start with `;`.
+///
+/// Supported types and data formats:
+///
+/// * `A` - an IPv4 address.
+/// * `AAAA` - an IPv6 address.
+/// * `CNAME` and `NS` - a domain name.
+/// * `MX` - a preference followed by a domain name.
+/// * `TXT` - one or more strings, which may be quoted.
+pub fn read_records(path: &str, host: &Domain) -> Result<Vec<Record>, String> {
+    let mut contents = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| format!("read {}: {}", path, e))?;
+    parse_records(path, &contents, host)
+}
+
+fn parse_records(path: &str, contents: &str, host: &Domain) -> Result<Vec<Record>, String> {
+    let mut result = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let fields = split_fields(line).map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+        if !fields.is_empty() {
+            result.push(parse_record(&fields, host)
+                .map_err(|e| format!("{}:{}: {}", path, i + 1, e))?);
+        }
+    }
+    Ok(result)
+}
+
+fn parse_record(fields: &[String], host: &Domain) -> Result<Record, String> {
+    let mut fields = fields.iter();
+    let name = fields.next().unwrap();
+    let domain = parse_name(name, host)?;
+    if !domain_ends_with(&domain, host) {
+        return Err(format!("name outside the zone: {}", name));
+    }
+    let mut type_field = fields.next().ok_or("missing record type".to_owned())?;
+    let mut ttl = DEFAULT_TTL;
+    if let Ok(x) = type_field.parse() {
+        ttl = x;
+        type_field = fields.next().ok_or("missing record type".to_owned())?;
+    }
+    let data: Vec<&String> = fields.collect();
+    let (record_type, body) = match type_field.to_uppercase().as_str() {
+        "A" => (RecordType::A, RecordBody::A(parse_single(&data)?)),
+        "AAAA" => (RecordType::AAAA, RecordBody::AAAA(parse_single(&data)?)),
+        "CNAME" => {
+            (RecordType::CNAME, RecordBody::Domain(parse_name(&parse_single::<String>(&data)?,
+                host)?))
+        },
+        "NS" => {
+            (RecordType::NS, RecordBody::Domain(parse_name(&parse_single::<String>(&data)?,
+                host)?))
+        },
+        "MX" => {
+            if data.len() != 2 {
+                return Err("expected preference and exchange".to_owned());
+            }
+            let preference: u16 = data[0].parse().map_err(|_| "bad MX preference".to_owned())?;
+            let exchange = parse_name(data[1], host)?;
+            (RecordType::MX, RecordBody::Unknown(encode_data(|p| {
+                preference.dns_encode(p)?;
+                exchange.dns_encode(p)
+            })?))
+        },
+        "TXT" => {
+            if data.is_empty() {
+                return Err("expected TXT data".to_owned());
+            }
+            (RecordType::TXT, RecordBody::Unknown(encode_data(|p| {
+                for s in &data {
+                    if s.is_empty() {
+                        0u8.dns_encode(p)?;
+                    }
+                    for piece in s.as_bytes().chunks(255) {
+                        (piece.len() as u8).dns_encode(p)?;
+                        piece.to_vec().dns_encode(p)?;
+                    }
+                }
+                Ok(())
+            })?))
+        },
+        x => return Err(format!("unsupported record type: {}", x))
+    };
+    Ok(Record{
+        header: RecordHeader{
+            domain: domain,
+            record_type: record_type,
+            record_class: RecordClass::IN,
+            ttl: ttl
+        },
+        body: body
+    })
+}
+
+fn parse_name(name: &str, host: &Domain) -> Result<Domain, String> {
+    let result = if name == "@" {
+        Ok(host.clone())
+    } else if name.ends_with(".") {
+        name[0..(name.len() - 1)].parse()
+    } else {
+        format!("{}.{}", name, host).parse()
+    };
+    result.map_err(|e| format!("bad name {}: {}", name, e))
+}
+
+fn parse_single<T: FromStr>(data: &[&String]) -> Result<T, String> {
+    if data.len() != 1 {
+        return Err("expected exactly one value".to_owned());
+    }
+    data[0].parse().map_err(|_| format!("bad value: {}", data[0]))
+}
+
+fn encode_data<F>(f: F) -> Result<Vec<u8>, String>
+    where F: FnOnce(&mut EncPacket) -> Result<(), dns_coding::Error>
+{
+    // Names are not compressed, since the record data is opaque once encoded.
+    let mut packet = EncPacket::new();
+    f(&mut packet).map_err(|e| format!("{}", e))?;
+    Ok(packet.data().clone())
+}
+
+/// Split a line into whitespace-separated fields, handling quoted strings
+/// and comments.
+fn split_fields(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            chars.next();
+        }
+        match chars.next() {
+            None | Some(';') => return Ok(fields),
+            Some('"') => {
+                let mut field = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err("unterminated string".to_owned()),
+                        Some('"') => break,
+                        Some('\\') => field.push(chars.next().ok_or("bad escape".to_owned())?),
+                        Some(c) => field.push(c)
+                    }
+                }
+                fields.push(field);
+            },
+            Some(c) => {
+                let mut field = c.to_string();
+                while chars.peek().map(|c| !c.is_whitespace() && *c != ';').unwrap_or(false) {
+                    field.push(chars.next().unwrap());
+                }
+                fields.push(field);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields() {
+        assert_eq!(split_fields("  www  300 A\t192.0.2.1 ; comment").unwrap(),
+            vec!["www", "300", "A", "192.0.2.1"]);
+        assert_eq!(split_fields("@ TXT \"two words\" \"\" \"a \\\"quote\\\"\";x").unwrap(),
+            vec!["@", "TXT", "two words", "", "a \"quote\""]);
+        assert_eq!(split_fields("foo;bar").unwrap(), vec!["foo"]);
+        assert_eq!(split_fields("; only a comment").unwrap(), Vec::<String>::new());
+        assert!(split_fields("@ TXT \"open").is_err());
+        assert!(split_fields("@ TXT \"escape\\").is_err());
+    }
+
+    #[test]
+    fn record_data() {
+        let host = "tun.test".parse().unwrap();
+        let records = parse_records("zone", concat!(
+            "@ 60 MX 10 mail\n",
+            "www A 192.0.2.1\n",
+            "Mail.TUN.test. AAAA 2001:db8::1\n",
+            "txt TXT \"\" ab\n"
+        ), &host).unwrap();
+        assert_eq!(records.len(), 4);
+
+        assert_eq!(records[0].header.domain, host);
+        assert_eq!(records[0].header.record_type, RecordType::MX);
+        assert_eq!(records[0].header.ttl, 60);
+        let mut mx = vec![0, 10, 4];
+        mx.extend(b"mail\x03tun\x04test\x00");
+        assert_eq!(records[0].body, RecordBody::Unknown(mx));
+
+        assert_eq!(records[1].header.domain, "www.tun.test".parse().unwrap());
+        assert_eq!(records[1].header.ttl, DEFAULT_TTL);
+        assert_eq!(records[1].body, RecordBody::A("192.0.2.1".parse().unwrap()));
+        assert_eq!(records[2].body, RecordBody::AAAA("2001:db8::1".parse().unwrap()));
+
+        // The empty string is a string of its own, not just nothing.
+        assert_eq!(records[3].body, RecordBody::Unknown(vec![0, 2, b'a', b'b']));
+        let empty = parse_records("zone", "@ TXT \"\"", &host).unwrap();
+        assert_eq!(empty[0].body, RecordBody::Unknown(vec![0]));
+
+        let long = parse_records("zone", &format!("@ TXT {}", "x".repeat(300)), &host).unwrap();
+        match long[0].body {
+            RecordBody::Unknown(ref data) => {
+                assert_eq!(data.len(), 302);
+                assert_eq!(data[0], 255);
+                assert_eq!(data[256], 45);
+            },
+            ref x => panic!("unexpected record body: {:?}", x)
+        }
+    }
+
+    #[test]
+    fn error_lines() {
+        let host = "tun.test".parse().unwrap();
+        let error = |contents: &str| parse_records("zone", contents, &host).unwrap_err();
+        assert_eq!(error("; header\n\nwww A 192.0.2.1\nwww AA 192.0.2.1"),
+            "zone:4: unsupported record type: AA");
+        assert_eq!(error("www"), "zone:1: missing record type");
+        assert_eq!(error("www 60"), "zone:1: missing record type");
+        assert_eq!(error("www A 192.0.2.1 192.0.2.2"), "zone:1: expected exactly one value");
+        assert_eq!(error("www A nope"), "zone:1: bad value: nope");
+        assert_eq!(error("@ MX mail"), "zone:1: expected preference and exchange");
+        assert_eq!(error("@ MX x mail"), "zone:1: bad MX preference");
+        assert_eq!(error("@ TXT"), "zone:1: expected TXT data");
+        assert_eq!(error("\n@ TXT \"open"), "zone:2: unterminated string");
+        assert_eq!(error("www.example.com. A 192.0.2.1"),
+            "zone:1: name outside the zone: www.example.com.");
+        assert_eq!(error("@ A 192.0.2.1\ntest. A 192.0.2.1"),
+            "zone:2: name outside the zone: test.");
+    }
+}
@@ -1,31 +1,74 @@
+use std::collections::HashMap;
 use std::iter::Iterator;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
+use myodine::myo_proto::Error;
 use myodine::myo_proto::discovery;
 use myodine::myo_proto::establish;
+use myodine::myo_proto::ip::Router;
+use myodine::myo_proto::util::{domain_ends_with, domain_lowercase};
 use myodine::myo_proto::xfer;
-use myodine::dns_proto::{Message, ResponseCode};
+use myodine::dns_proto::{Message, Record, ResponseCode};
 
 use flags::Flags;
+use limits::Limiter;
 use session::Session;
+use users::UserTable;
+use zone::Zone;
+
+/// The maximum number of establishment responses to remember.
+const ESTABLISHMENT_CACHE_SIZE: usize = 256;
 
 /// A stateful server.
 pub struct Server {
     flags: Flags,
-    sessions: Vec<Session>
+    zone: Zone,
+    sessions: Vec<Session>,
+    establishments: HashMap<String, (Instant, establish::EstablishResponse)>,
+    users: Option<UserTable>,
+    limiter: Limiter,
+    user_limiters: HashMap<String, Limiter>,
+    router: Option<Router>
 }
 
 impl Server {
-    /// Create a new server with the configuration flags.
-    pub fn new(flags: Flags) -> Server {
-        Server{flags: flags, sessions: Vec::new()}
+    /// Create a new server with the configuration flags, the static records
+    /// to serve, the users who may establish sessions, and the router for IP
+    /// tunnels.
+    ///
+    /// Without a user table, every client uses the server password.
+    pub fn new(
+        flags: Flags,
+        records: Vec<Record>,
+        users: Option<UserTable>,
+        router: Option<Router>
+    ) -> Server {
+        Server{
+            zone: Zone::new(&flags, records),
+            limiter: Limiter::new(flags.global_limit),
+            flags: flags,
+            sessions: Vec::new(),
+            establishments: HashMap::new(),
+            users: users,
+            user_limiters: HashMap::new(),
+            router: router
+        }
     }
 
-    /// Remove all closed or timed-out sessions.
+    /// Remove all closed or timed-out sessions, and forget old establishment
+    /// responses.
     pub fn garbage_collect(&mut self) {
+        let timeout = self.flags.session_timeout;
+        self.establishments.retain(|_, &mut (time, _)| time.elapsed() < timeout);
         for i in (0..self.sessions.len()).into_iter().rev() {
             if self.sessions[i].is_done(self.flags.session_timeout) {
-                println!("removing session {}", self.sessions[i].session_id());
+                let session = &self.sessions[i];
+                match session.user() {
+                    Some(user) => println!("removing session {} of user {} ({} invalid packets)",
+                        session.session_id(), user, session.invalid_packets()),
+                    None => println!("removing session {} ({} invalid packets)",
+                        session.session_id(), session.invalid_packets())
+                }
                 self.sessions.remove(i);
             }
         }
@@ -33,40 +76,122 @@ impl Server {
 
     /// Serve the API for the incoming message.
     ///
+    /// Queries outside of the server's domain are refused, and transfer
+    /// queries for unknown sessions get an NXDOMAIN so that clients notice
+    /// when their session is gone. Malformed API calls get a FORMERR.
+    ///
+    /// Transfer queries that would exceed the rate limit of their session,
+    /// their user, or the whole server get responses without data, so that
+    /// the client keeps polling at its own pace.
+    ///
     /// This should not block for very long.
-    pub fn handle_message(&mut self, message: Message) -> Result<Message, String> {
-        if discovery::is_domain_hash_query(&message) {
-            return discovery::domain_hash_response(&message);
+    pub fn handle_message(&mut self, message: Message) -> Message {
+        if !self.is_local_query(&message) {
+            return error_response(message, ResponseCode::Refused);
+        }
+        let mut response = self.handle_zone_message(message);
+        response.header.authoritative = true;
+        response
+    }
+
+    /// Check if a message is a query for a name under the server's domain.
+    fn is_local_query(&self, message: &Message) -> bool {
+        message.questions.len() == 1 &&
+            domain_ends_with(&message.questions[0].domain, &self.flags.host)
+    }
+
+    fn handle_zone_message(&mut self, message: Message) -> Message {
+        if let Some(response) = self.zone.answer(&message) {
+            return response;
+        }
+        // Keep enough of the query to build an error response, since
+        // transfer queries are consumed by their session.
+        let (header, question) = (message.header.clone(), message.questions[0].clone());
+        let xfer_id = xfer::xfer_query_session_id(&message);
+        let mut result = if discovery::is_domain_hash_query(&message) {
+            discovery::domain_hash_response(&message)
         } else if discovery::is_download_gen_query(&message) {
-            return discovery::download_gen_response(&message);
+            discovery::download_gen_response(&message)
         } else if establish::is_establish_query(&message) {
-            return self.handle_establish(message);
-        } else if let Some(id) = xfer::xfer_query_session_id(&message) {
-            let mut some_sess = (&mut self.sessions).into_iter().find(|x| x.session_id() == id);
-            if let Some(ref mut session) = some_sess {
-                return session.handle_message(message, &self.flags.host);
+            self.handle_establish(&message)
+        } else if let Some(id) = xfer_id {
+            match self.sessions.iter().position(|x| x.session_id() == id) {
+                Some(index) => self.handle_xfer(index, message),
+                None => return self.zone.empty_response(message, ResponseCode::NXDomain)
+            }
+        } else {
+            return self.zone.empty_response(message, ResponseCode::NXDomain);
+        };
+        // Transfer responses carry new data every time, so they must never be
+        // cached.
+        if let Ok(ref mut response) = result {
+            if xfer_id.is_none() {
+                for answer in &mut response.answers {
+                    answer.header.ttl = self.flags.response_ttl;
+                }
             }
         }
-        let mut response = message.clone();
-        response.header.is_response = true;
-        response.header.response_code = ResponseCode::NoError;
-        Ok(response)
+        result.unwrap_or_else(|err| {
+            eprintln!("error processing query for {}: {}", question.domain, err);
+            let query = Message{
+                header: header,
+                questions: vec![question],
+                answers: Vec::new(),
+                authorities: Vec::new(),
+                additional: Vec::new()
+            };
+            error_response(query, error_code(&err))
+        })
     }
 
-    fn handle_establish(&mut self, message: Message) -> Result<Message, String> {
+    fn handle_establish(&mut self, message: &Message) -> Result<Message, Error> {
+        // Resolvers may deliver a query more than once, so duplicates get the
+        // original response rather than a new session. Only successes are
+        // remembered, since failures are cheap to repeat.
+        let key = domain_lowercase(&message.questions[0].domain);
+        if let Some((_, response)) = self.establishments.get(&key) {
+            return establish::establish_response(message, &self.flags.host, response.clone());
+        }
         // TODO: less nesting here.
-        let query = establish::EstablishQuery::from_query(&message, &self.flags.host)?;
+        let query = establish::EstablishQuery::from_query(message, &self.flags.host)?;
         let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-        let response = if query.check_proof(&self.flags.password, epoch, self.flags.proof_window) {
+        let user = match (&self.users, &query.user) {
+            (Some(users), Some(name)) => users.get(name).cloned(),
+            _ => None
+        };
+        let secret = match self.users {
+            Some(_) => user.as_ref().map(|x| x.secret.as_str()),
+            None => Some(self.flags.password.as_str())
+        };
+        let proof_ok = secret.map(|x| query.check_proof(x, epoch, self.flags.proof_window))
+            .unwrap_or(false);
+        let response = if proof_ok {
             if let Some(id) = self.unused_session_id() {
                 // TODO: randomize seq_start.
                 let seq_start = 0;
                 let sess_res = Session::new(id, seq_start, message.questions[0].record_type,
-                    &query, self.flags.conn_timeout);
+                    &query, &self.flags, user.as_ref(), self.router.as_ref());
                 match sess_res {
                     Ok(sess) => {
+                        if let Some(ref user) = user {
+                            println!("session {} established for user {}", id, user.name);
+                            let limit = user.limit.or(self.flags.user_limit);
+                            self.user_limiters.entry(user.name.clone())
+                                .or_insert_with(|| Limiter::new(limit));
+                        }
+                        let response = match sess.ip_lease() {
+                            Some(lease) => {
+                                println!("session {} was assigned {}", id, lease.addr);
+                                establish::EstablishResponse::IpSuccess{
+                                    id: id,
+                                    seq: seq_start,
+                                    lease: lease
+                                }
+                            },
+                            None => establish::EstablishResponse::Success{id: id, seq: seq_start}
+                        };
                         self.sessions.push(sess);
-                        establish::EstablishResponse::Success{id: id, seq: seq_start}
+                        response
                     },
                     Err(msg) => establish::EstablishResponse::Failure(msg)
                 }
@@ -77,7 +202,45 @@ impl Server {
         } else {
             establish::EstablishResponse::Failure("invalid proof".to_owned())
         };
-        establish::establish_response(&message, &self.flags.host, response)
+        match response {
+            establish::EstablishResponse::Failure(_) => {},
+            _ => self.remember_establishment(key, response.clone())
+        }
+        establish::establish_response(message, &self.flags.host, response)
+    }
+
+    fn remember_establishment(&mut self, key: String, response: establish::EstablishResponse) {
+        if self.establishments.len() >= ESTABLISHMENT_CACHE_SIZE {
+            let oldest = self.establishments.iter().min_by_key(|&(_, &(time, _))| time)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.establishments.remove(&oldest);
+            }
+        }
+        self.establishments.insert(key, (Instant::now(), response));
+    }
+
+    /// Pass a transfer query to the session at an index.
+    fn handle_xfer(&mut self, index: usize, message: Message) -> Result<Message, Error> {
+        let session = &mut self.sessions[index];
+        let mut user_limiter = match session.user() {
+            Some(user) => self.user_limiters.get_mut(user),
+            None => None
+        };
+        let throttled = self.limiter.is_limited() || session.limiter().is_limited() ||
+            user_limiter.as_mut().map(|x| x.is_limited()).unwrap_or(false);
+        let result = session.handle_message(message, &self.flags.host, throttled);
+        // Replayed responses were already counted the first time.
+        if let Ok((_, Some(bytes))) = result {
+            if !throttled {
+                self.limiter.record(bytes);
+                session.limiter().record(bytes);
+                if let Some(limiter) = user_limiter {
+                    limiter.record(bytes);
+                }
+            }
+        }
+        result.map(|x| x.0)
     }
 
     fn unused_session_id(&self) -> Option<u16> {
@@ -89,3 +252,221 @@ impl Server {
         None
     }
 }
+
+/// Create an empty response to a query.
+pub fn error_response(query: Message, code: ResponseCode) -> Message {
+    let mut response = query;
+    response.header.is_response = true;
+    response.header.response_code = code;
+    response.answers.clear();
+    response.authorities.clear();
+    response.additional.clear();
+    response.header.answer_count = 0;
+    response.header.authority_count = 0;
+    response.header.additional_count = 0;
+    response
+}
+
+/// Get the response code for a failed API call.
+fn error_code(err: &Error) -> ResponseCode {
+    match *err {
+        Error::IncorrectHost => ResponseCode::Refused,
+        Error::UnknownNameCode(_) | Error::UnknownRecordCode(_) => ResponseCode::NotImplemented,
+        _ => ResponseCode::FormatError
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use myodine::dns_coding::dns_decode;
+    use myodine::dns_proto::{Question, RecordClass, RecordType};
+    use myodine::myo_proto::establish::{EstablishQuery, EstablishResponse, password_proof};
+    use myodine::myo_proto::name_code::get_name_code;
+    use myodine::myo_proto::record_code::get_record_code;
+    use myodine::myo_proto::xfer::{Ack, AckEncoding, BitMask, Chunk, Packet};
+
+    #[test]
+    fn error_codes() {
+        let mut server = test_server(&[]);
+        let cases = vec![
+            ("www.example.com", RecordType::A, ResponseCode::Refused),
+            ("www.tun.test", RecordType::A, ResponseCode::NXDomain),
+            ("t7.123.tun.test", RecordType::TXT, ResponseCode::NXDomain),
+            ("fraw.x.1.1.7.0.tun.test", RecordType::TXT, ResponseCode::FormatError),
+            ("fzzz.10.1.1.7.0.tun.test", RecordType::TXT, ResponseCode::NotImplemented)
+        ];
+        for (name, record_type, code) in cases {
+            let response = server.handle_message(query(name, record_type));
+            assert!(response.header.is_response);
+            assert_eq!(response.header.response_code, code, "{}", name);
+            assert_eq!(response.questions, query(name, record_type).questions);
+            assert_eq!(response.answers.len(), 0);
+        }
+
+        let response = server.handle_message(query("fraw.10.1.1.7.0.tun.test", RecordType::TXT));
+        assert_eq!(response.header.response_code, ResponseCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn authoritative_answers() {
+        let mut server = test_server(&[]);
+        let names = [
+            ("tun.test", RecordType::SOA),
+            ("www.tun.test", RecordType::A),
+            ("fraw.10.1.1.7.0.tun.test", RecordType::TXT)
+        ];
+        for &(name, record_type) in &names {
+            let response = server.handle_message(query(name, record_type));
+            assert!(response.header.authoritative, "{}", name);
+        }
+        let response = server.handle_message(query("www.example.com", RecordType::A));
+        assert!(!response.header.authoritative);
+    }
+
+    #[test]
+    fn response_ttls() {
+        let mut server = test_server(&["--ttl", "60"]);
+        let response = server.handle_message(query("fraw.10.1.1.7.0.tun.test", RecordType::TXT));
+        assert_eq!(response.answers[0].header.ttl, 60);
+
+        let (response, result) = establish(&mut server, 1, "password");
+        assert_eq!(response.answers[0].header.ttl, 60);
+        let id = match result {
+            EstablishResponse::Success{id, ..} => id,
+            x => panic!("unexpected establish response: {:?}", x)
+        };
+
+        let response = server.handle_message(poll_query(id));
+        assert_eq!(response.header.response_code, ResponseCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].header.ttl, 0);
+    }
+
+    #[test]
+    fn throttled_transfers() {
+        let mut server = test_server(&["--query-rate", "2"]);
+        let id = match establish(&mut server, 1, "password").1 {
+            EstablishResponse::Success{id, ..} => id,
+            x => panic!("unexpected establish response: {:?}", x)
+        };
+        let first = data_query(id, 0);
+        assert_eq!(transfer(&mut server, first.clone()).ack.window_start, 1);
+
+        // A replay does not count against the limit.
+        assert_eq!(transfer(&mut server, first).ack.window_start, 1);
+        assert_eq!(transfer(&mut server, data_query(id, 1)).ack.window_start, 2);
+
+        // Once throttled, the chunk is left for the client to send again.
+        let packet = transfer(&mut server, data_query(id, 2));
+        assert_eq!(packet.ack.window_start, 2);
+        assert_eq!(packet.chunk, None);
+    }
+
+    #[test]
+    fn duplicate_establishment() {
+        let mut server = test_server(&[]);
+        let (_, first) = establish(&mut server, 1, "password");
+        let (_, again) = establish(&mut server, 1, "password");
+        assert_eq!(first, again);
+        assert_eq!(server.sessions.len(), 1);
+
+        let (_, other) = establish(&mut server, 2, "password");
+        assert!(other != first);
+        assert_eq!(server.sessions.len(), 2);
+
+        // Failures are not remembered.
+        let (_, failure) = establish(&mut server, 3, "wrong");
+        assert_eq!(failure, EstablishResponse::Failure("invalid proof".to_owned()));
+        assert_eq!(server.establishments.len(), 2);
+    }
+
+    #[test]
+    fn establishment_cache_size() {
+        let mut server = test_server(&[]);
+        for i in 0..(ESTABLISHMENT_CACHE_SIZE + 1) {
+            server.remember_establishment(format!("{}", i),
+                EstablishResponse::Success{id: i as u16, seq: 0});
+        }
+        assert_eq!(server.establishments.len(), ESTABLISHMENT_CACHE_SIZE);
+        assert!(!server.establishments.contains_key("0"));
+        assert!(server.establishments.contains_key(&format!("{}", ESTABLISHMENT_CACHE_SIZE)));
+    }
+
+    fn test_server(args: &[&str]) -> Server {
+        let mut all_args = vec!["myodine-server", "-p", "password"];
+        all_args.extend(args);
+        all_args.push("tun.test");
+        Server::new(Flags::parse_from(all_args).unwrap(), Vec::new(), None, None)
+    }
+
+    fn query(name: &str, record_type: RecordType) -> Message {
+        Message::new_query(Question{
+            domain: name.parse().unwrap(),
+            record_type: record_type,
+            record_class: RecordClass::IN
+        })
+    }
+    /// Establish a multiplexed session, returning the response message and
+    /// the decoded establishment response.
+    fn establish(
+        server: &mut Server,
+        nonce: u32,
+        password: &str
+    ) -> (Message, EstablishResponse) {
+        let epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let domain = EstablishQuery{
+            response_encoding: "raw".to_owned(),
+            mtu: 100,
+            name_encoding: "b16".to_owned(),
+            query_window: 4,
+            response_window: 4,
+            ack_encoding: "mask".to_owned(),
+            proof: password_proof(password, epoch),
+            nonce: nonce,
+            user: None,
+            channel: "mux".to_owned(),
+            port: 0,
+            host: "localhost".parse().unwrap()
+        }.to_domain(&"tun.test".parse().unwrap()).unwrap();
+        let response = server.handle_message(Message::new_query(Question{
+            domain: domain,
+            record_type: RecordType::TXT,
+            record_class: RecordClass::IN
+        }));
+        let data = get_record_code(RecordType::TXT, "raw").unwrap()
+            .decode_body(&response.answers[0].body).unwrap();
+        let result = dns_decode(data).unwrap();
+        (response, result)
+    }
+
+    fn poll_query(id: u16) -> Message {
+        transfer_query(id, None)
+    }
+
+    /// Create a transfer query with a chunk that closes a stream which does
+    /// not exist, which the session's multiplexer ignores.
+    fn data_query(id: u16, seq: u32) -> Message {
+        transfer_query(id, Some(Chunk{seq: seq, data: vec![4, 0, 1]}))
+    }
+
+    /// Pass a transfer query to the server and decode the response.
+    fn transfer(server: &mut Server, query: Message) -> Packet {
+        let response = server.handle_message(query);
+        let data = get_record_code(RecordType::TXT, "raw").unwrap()
+            .decode_body(&response.answers[0].body).unwrap();
+        Packet::decode_response(&data, 4, AckEncoding::Mask).unwrap()
+    }
+
+    fn transfer_query(id: u16, chunk: Option<Chunk>) -> Message {
+        let packet = Packet{ack: Ack{window_start: 0, window_mask: BitMask::new(3)}, chunk: chunk};
+        let (api_code, data) = packet.encode_query(AckEncoding::Mask).unwrap();
+        Message::new_query(Question{
+            domain: get_name_code("b16").unwrap()
+                .encode_domain(api_code, id, &data, &"tun.test".parse().unwrap()).unwrap(),
+            record_type: RecordType::TXT,
+            record_class: RecordClass::IN
+        })
+    }
+}
@@ -1,52 +1,153 @@
+use std::collections::VecDeque;
 use std::net::TcpStream;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use myodine::conn::TcpChunker;
+use myodine::conn::{Chunker, DatagramSocket, TcpChunker};
 use myodine::dns_proto::{Domain, Message, Record, RecordHeader, RecordType};
+use myodine::myo_proto::Error;
+use myodine::myo_proto::datagram::{DatagramPacket, DatagramState};
 use myodine::myo_proto::establish::EstablishQuery;
+use myodine::myo_proto::ip::{IpLease, Lease, Router};
+use myodine::myo_proto::mux::{Mux, Side};
 use myodine::myo_proto::name_code::{NameCode, get_name_code};
 use myodine::myo_proto::record_code::{RecordCode, get_record_code};
-use myodine::myo_proto::xfer::{Packet, WwrState, handle_packet_in, next_packet_out};
+use myodine::myo_proto::util::domain_lowercase;
+use myodine::myo_proto::xfer::{AckEncoding, Packet, WwrState, handle_packet_in,
+    next_packet_out};
+
+use flags::Flags;
+use limits::Limiter;
+use users::User;
+
+/// The maximum number of responses remembered for replay.
+const REPLAY_CACHE_SIZE: usize = 256;
 
 /// The state of a single session.
 pub struct Session {
     id: u16,
+    user: Option<String>,
     last_used: Instant,
-    state: WwrState,
-    name_code: Box<NameCode>,
-    record_code: Box<RecordCode>,
-    conn: TcpChunker,
-    response_window: u16
+    transport: Transport,
+    name_code: Arc<dyn NameCode>,
+    record_code: Arc<dyn RecordCode>,
+    lease: Option<Lease>,
+    response_window: u16,
+    ack_encoding: AckEncoding,
+    invalid_packets: u64,
+    replay_window: Duration,
+    replay_cache: VecDeque<CachedResponse>,
+    limiter: Limiter
+}
+
+/// The way that a session carries data.
+enum Transport {
+    /// A reliable byte stream.
+    Stream{state: WwrState, conn: Box<dyn Chunker>},
+
+    /// Best-effort datagrams.
+    Datagram{state: DatagramState, socket: DatagramSocket}
+}
+
+struct CachedResponse {
+    domain: String,
+    identifier: u16,
+    time: Instant,
+    response: Message
 }
 
 impl Session {
     /// Establish a new session.
+    ///
+    /// Multiplexed sessions may only listen on ports if the flags allow it.
+    /// IP tunnels are attached to the router, if there is one.
+    ///
+    /// If the session belongs to a user with restricted destinations, it may
+    /// only connect to those destinations, and may not listen on ports or
+    /// carry IP packets.
     pub fn new(
         id: u16,
         seq_start: u32,
         query_type: RecordType,
         query: &EstablishQuery,
-        timeout: Duration
+        flags: &Flags,
+        user: Option<&User>,
+        router: Option<&Router>
     ) -> Result<Session, String> {
         let name_code = get_name_code(&query.name_encoding)
             .ok_or(format!("bad name code: {}", query.name_encoding))?;
         let record_code = get_record_code(query_type, &query.response_encoding)
             .ok_or("bad record code".to_owned())?;
+        let ack_encoding = AckEncoding::from_name(&query.ack_encoding)
+            .ok_or(format!("bad ack encoding: {}", query.ack_encoding))?;
+        if query.query_window == 0 || query.response_window == 0 || query.mtu == 0 {
+            return Err("window sizes and MTU must be non-zero".to_owned());
+        }
+        let restricted = user.map(|x| x.destinations.is_some()).unwrap_or(false);
+        if restricted && query.channel != "mux" {
+            let host = format!("{}", query.host);
+            if query.channel == "ip" || !user.unwrap().allows(&host, query.port) {
+                return Err("destination not allowed".to_owned());
+            }
+        }
+        let (timeout, coalesce_delay) = (flags.conn_timeout, flags.coalesce_delay);
+        let mut lease = None;
         let addr_str = format!("{}:{}", query.host, query.port);
-        let addr = addr_str.parse().map_err(|e| format!("parse {}: {}", addr_str, e))?;
-        let stream = TcpStream::connect_timeout(&addr, timeout)
-            .map_err(|e| format!("connect error: {}", e))?;
-        // TCP buffer sizes are chosen rather arbitrarily.
-        let conn = TcpChunker::new(stream, query.mtu as usize, query.response_window as usize,
-                query.query_window as usize).map_err(|e| format!("chunker error: {}", e))?;
+        let stream = |conn: Box<dyn Chunker>| Transport::Stream{
+            state: WwrState::new(query.query_window, query.response_window, seq_start),
+            conn: conn
+        };
+        let transport = match query.channel.as_str() {
+            "tcp" => {
+                let addr = addr_str.parse().map_err(|e| format!("parse {}: {}", addr_str, e))?;
+                let stream_conn = TcpStream::connect_timeout(&addr, timeout)
+                    .map_err(|e| format!("connect error: {}", e))?;
+                // TCP buffer sizes are chosen rather arbitrarily.
+                stream(Box::new(TcpChunker::new(stream_conn, query.mtu as usize,
+                        query.response_window as usize, query.query_window as usize,
+                        coalesce_delay)
+                    .map_err(|e| format!("chunker error: {}", e))?))
+            },
+            "mux" => {
+                let mut mux = Mux::new(Side::Server, query.mtu as usize, coalesce_delay, timeout);
+                mux.set_listen_enabled(flags.allow_listen && !restricted);
+                if restricted {
+                    let user = user.unwrap().clone();
+                    mux.set_open_filter(Box::new(move |host, port| user.allows(host, port)));
+                }
+                stream(Box::new(mux))
+            },
+            "ip" => {
+                let router = router.ok_or("IP tunnels are not enabled".to_owned())?;
+                let (ip_lease, conn) = router.attach(query.mtu as usize)
+                    .ok_or("no free IP addresses".to_owned())?;
+                lease = Some(ip_lease);
+                stream(Box::new(conn))
+            },
+            "udp" => {
+                let addr = addr_str.parse().map_err(|e| format!("parse {}: {}", addr_str, e))?;
+                Transport::Datagram{
+                    state: DatagramState::new(query.mtu as usize),
+                    socket: DatagramSocket::connect(&addr)
+                        .map_err(|e| format!("socket error: {}", e))?
+                }
+            },
+            x => return Err(format!("bad channel type: {}", x))
+        };
         Ok(Session{
             id: id,
+            user: user.map(|x| x.name.clone()),
             last_used: Instant::now(),
-            state: WwrState::new(query.query_window, query.response_window, seq_start),
+            transport: transport,
             name_code: name_code,
             record_code: record_code,
-            conn: conn,
-            response_window: query.response_window
+            lease: lease,
+            response_window: query.response_window,
+            ack_encoding: ack_encoding,
+            invalid_packets: 0,
+            replay_window: flags.replay_window,
+            replay_cache: VecDeque::new(),
+            limiter: Limiter::new(flags.session_limit)
         })
     }
 
@@ -55,6 +156,26 @@ impl Session {
         self.id
     }
 
+    /// Get the name of the user who established the session, if any.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    /// Get the addresses assigned to the session's IP tunnel, if it has one.
+    pub fn ip_lease(&self) -> Option<IpLease> {
+        self.lease.as_ref().map(|x| x.info().clone())
+    }
+
+    /// Get the number of packets that were dropped because they were invalid.
+    pub fn invalid_packets(&self) -> u64 {
+        self.invalid_packets
+    }
+
+    /// Get the rate limiter for this session alone.
+    pub fn limiter(&mut self) -> &mut Limiter {
+        &mut self.limiter
+    }
+
     /// Check if the session is ready to be cleaned up.
     pub fn is_done(&self, timeout: Duration) -> bool {
         // For now, don't check self.state.is_done() because of an EOF ack issue.
@@ -63,14 +184,111 @@ impl Session {
 
     /// Handle a message that was directed to the session.
     ///
+    /// A query with the same name (ignoring case) and ID as a recent query is a
+    /// retransmission, so it gets the earlier response instead of being
+    /// processed again.
+    ///
+    /// Returns the response and the number of bytes of data that it moved in
+    /// either direction, or None for the bytes if the response was replayed.
+    ///
     /// # Arguments
     ///
     /// * `message` - The message that was received.
     /// * `host` - The root domain name of the server.
-    pub fn handle_message(&mut self, message: Message, host: &Domain) -> Result<Message, String> {
+    /// * `throttled` - If true, only acknowledgements are handled, and the
+    ///   response carries no data. Incoming data is left for the client to
+    ///   retransmit.
+    pub fn handle_message(
+        &mut self,
+        message: Message,
+        host: &Domain,
+        throttled: bool
+    ) -> Result<(Message, Option<usize>), Error> {
+        // Resolvers may change the case of a name when they retransmit it.
+        let domain = domain_lowercase(&message.questions[0].domain);
+        let identifier = message.header.identifier;
+        if let Some(mut response) = self.cached_response(&domain, identifier) {
+            self.last_used = Instant::now();
+            for answer in &mut response.answers {
+                answer.header.domain = message.questions[0].domain.clone();
+            }
+            response.questions = message.questions;
+            return Ok((response, None));
+        }
+        let (response, bytes) = self.handle_new_message(message, host, throttled)?;
+        if self.replay_cache.len() == REPLAY_CACHE_SIZE {
+            self.replay_cache.pop_front();
+        }
+        self.replay_cache.push_back(CachedResponse{
+            domain: domain,
+            identifier: identifier,
+            time: Instant::now(),
+            response: response.clone()
+        });
+        Ok((response, Some(bytes)))
+    }
+
+    fn cached_response(&mut self, domain: &str, identifier: u16) -> Option<Message> {
+        while self.replay_cache.front().map(|x| x.time.elapsed() > self.replay_window)
+            .unwrap_or(false)
+        {
+            self.replay_cache.pop_front();
+        }
+        self.replay_cache.iter()
+            .find(|x| x.identifier == identifier && x.domain == domain)
+            .map(|x| x.response.clone())
+    }
+
+    fn handle_new_message(
+        &mut self,
+        message: Message,
+        host: &Domain,
+        throttled: bool
+    ) -> Result<(Message, usize), Error> {
         let (api, _, data) = self.name_code.decode_domain(&message.questions[0].domain, host)?;
-        let in_packet = Packet::decode_query(&data, self.response_window, api)?;
-        let response_packet = self.handle_packet(in_packet);
+        let mut bytes = 0;
+        let body = match self.transport {
+            Transport::Stream{ref mut state, ref mut conn} => {
+                let packet = Packet::decode_query(&data, self.response_window,
+                    self.ack_encoding, api)?;
+                let result = if throttled {
+                    state.handle_ack(&packet.ack)
+                } else {
+                    bytes += packet.chunk.as_ref().map(|x| x.data.len()).unwrap_or(0);
+                    handle_packet_in(packet, state, &mut **conn).map(|_| ())
+                };
+                if let Err(err) = result {
+                    self.invalid_packets += 1;
+                    return Err(err.into());
+                }
+                let packet_out = if throttled {
+                    Packet{ack: state.next_send_ack(), chunk: None}
+                } else {
+                    next_packet_out(state, &mut **conn).0
+                };
+                bytes += packet_out.chunk.as_ref().map(|x| x.data.len()).unwrap_or(0);
+                packet_out.encode_response(self.ack_encoding)?
+            },
+            Transport::Datagram{ref mut state, ref mut socket} => {
+                let packet = DatagramPacket::decode_query(&data, api)?;
+                if throttled {
+                    // Datagrams are best-effort, so dropping this one is fine.
+                    DatagramPacket{fragment: None}.encode_response()?
+                } else {
+                    bytes += packet.fragment.as_ref().map(|x| x.data.len()).unwrap_or(0);
+                    if let Some(datagram) = packet.fragment.and_then(|x| state.handle_fragment(x)) {
+                        socket.send(&datagram);
+                    }
+                    while let Some(datagram) = socket.recv() {
+                        state.push_send(datagram);
+                    }
+                    let fragment = state.next_send();
+                    bytes += fragment.as_ref().map(|x| x.data.len()).unwrap_or(0);
+                    DatagramPacket{fragment: fragment}.encode_response()?
+                }
+            }
+        };
+        self.last_used = Instant::now();
         let mut response = message;
         let record = Record{
             header: RecordHeader{
@@ -79,18 +297,110 @@ impl Session {
                 record_class: response.questions[0].record_class,
                 ttl: 0,
             },
-            body: self.record_code.encode_body(&response_packet.encode_response()?)?
+            body: self.record_code.encode_body(&body)?
         };
         response.answers.push(record);
         response.header.is_response = true;
         response.header.answer_count = 1;
-        Ok(response)
+        Ok((response, bytes))
     }
+}
 
-    fn handle_packet(&mut self, packet: Packet) -> Packet {
-        // TODO: verify packet using sequence number!
-        self.last_used = Instant::now();
-        handle_packet_in(packet, &mut self.state, &mut self.conn);
-        next_packet_out(&mut self.state, &mut self.conn).0
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+    use myodine::dns_proto::{Question, RecordClass};
+    use myodine::myo_proto::xfer::{Ack, BitMask};
+
+    #[test]
+    fn replay_hits() {
+        let mut session = test_session(&[]);
+        let host = "tun.test".parse().unwrap();
+        let query = poll_query(session.session_id(), 7);
+        let (response, _) = session.handle_message(query.clone(), &host, false).unwrap();
+        let (replay, bytes) = session.handle_message(query.clone(), &host, false).unwrap();
+        assert_eq!(replay, response);
+        assert_eq!(bytes, None);
+        assert_eq!(session.replay_cache.len(), 1);
+
+        // A retransmission may change the case of the name.
+        let mut shouted = query.clone();
+        shouted.questions[0].domain = format!("{}", query.questions[0].domain).to_uppercase()
+            .parse().unwrap();
+        let (replay, _) = session.handle_message(shouted.clone(), &host, false).unwrap();
+        assert_eq!(replay.questions, shouted.questions);
+        assert_eq!(replay.answers[0].header.domain, shouted.questions[0].domain);
+        assert_eq!(replay.answers[0].body, response.answers[0].body);
+        assert_eq!(session.replay_cache.len(), 1);
+
+        // The same name with another ID is a new query.
+        let mut other = query.clone();
+        other.header.identifier = 8;
+        session.handle_message(other, &host, false).unwrap();
+        assert_eq!(session.replay_cache.len(), 2);
+    }
+
+    #[test]
+    fn replay_expiry() {
+        let mut session = test_session(&["--replay-win", "20"]);
+        let host = "tun.test".parse().unwrap();
+        let query = poll_query(session.session_id(), 7);
+        let key = domain_lowercase(&query.questions[0].domain);
+        session.handle_message(query, &host, false).unwrap();
+        assert!(session.cached_response(&key, 7).is_some());
+        sleep(Duration::from_millis(40));
+        assert!(session.cached_response(&key, 7).is_none());
+        assert_eq!(session.replay_cache.len(), 0);
+    }
+
+    #[test]
+    fn replay_eviction() {
+        let mut session = test_session(&[]);
+        let host = "tun.test".parse().unwrap();
+        let first = poll_query(session.session_id(), 0);
+        let key = domain_lowercase(&first.questions[0].domain);
+        session.handle_message(first, &host, false).unwrap();
+        for i in 1..(REPLAY_CACHE_SIZE + 1) {
+            let query = poll_query(session.session_id(), i as u16);
+            session.handle_message(query, &host, false).unwrap();
+        }
+        assert_eq!(session.replay_cache.len(), REPLAY_CACHE_SIZE);
+        assert!(session.cached_response(&key, 0).is_none());
+    }
+
+    fn test_session(args: &[&str]) -> Session {
+        let mut all_args = vec!["myodine-server", "-p", "password"];
+        all_args.extend(args);
+        all_args.push("tun.test");
+        let flags = Flags::parse_from(all_args).unwrap();
+        let query = EstablishQuery{
+            response_encoding: "raw".to_owned(),
+            mtu: 100,
+            name_encoding: "b16".to_owned(),
+            query_window: 4,
+            response_window: 4,
+            ack_encoding: "mask".to_owned(),
+            proof: 0,
+            nonce: 0,
+            user: None,
+            channel: "mux".to_owned(),
+            port: 0,
+            host: "localhost".parse().unwrap()
+        };
+        Session::new(3, 0, RecordType::TXT, &query, &flags, None, None).unwrap()
+    }
+
+    fn poll_query(id: u16, identifier: u16) -> Message {
+        let packet = Packet{ack: Ack{window_start: 0, window_mask: BitMask::new(3)}, chunk: None};
+        let (api_code, data) = packet.encode_query(AckEncoding::Mask).unwrap();
+        let mut message = Message::new_query(Question{
+            domain: get_name_code("b16").unwrap()
+                .encode_domain(api_code, id, &data, &"tun.test".parse().unwrap()).unwrap(),
+            record_type: RecordType::TXT,
+            record_class: RecordClass::IN
+        });
+        message.header.identifier = identifier;
+        message
     }
 }
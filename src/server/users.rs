@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+
+use limits::Limit;
+
+/// Someone who may establish sessions with their own secret.
+#[derive(Clone)]
+pub struct User {
+    pub name: String,
+    pub secret: String,
+
+    /// The destinations that the user may connect to, or None if the user may
+    /// connect anywhere.
+    pub destinations: Option<Vec<Destination>>,
+
+    /// The rates that all of the user's sessions may not exceed together, in
+    /// place of the server's defaults.
+    pub limit: Limit
+}
+
+/// A pattern for the hosts and ports that a user may connect to.
+#[derive(Clone)]
+pub struct Destination {
+    host: Option<String>,
+    port: Option<u16>
+}
+
+/// The users that may establish sessions, by name.
+pub type UserTable = HashMap<String, User>;
+
+impl User {
+    /// Check if the user may connect to a host and port.
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        match self.destinations {
+            Some(ref destinations) => destinations.iter().any(|x| x.matches(host, port)),
+            None => true
+        }
+    }
+}
+
+impl Destination {
+    fn matches(&self, host: &str, port: u16) -> bool {
+        self.host.as_ref().map(|x| x.eq_ignore_ascii_case(host)).unwrap_or(true) &&
+            self.port.map(|x| x == port).unwrap_or(true)
+    }
+}
+
+/// Read the user table from a file.
+///
+/// Each line has the form `NAME SECRET [HOST:PORT ...] [query-rate=N]
+/// [byte-rate=N]`. If any destinations are listed, the user may only connect
+/// to those, and `*` matches any host or any port. The rates override the
+/// server's default user limits. Names are case-insensitive, and may only
+/// contain letters, digits and hyphens, since they go in a domain label.
+/// Comments start with `#`.
+pub fn read_users(path: &str) -> Result<UserTable, String> {
+    let mut contents = String::new();
+    File::open(path).and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| format!("read {}: {}", path, e))?;
+    parse_users(path, &contents)
+}
+
+fn parse_users(path: &str, contents: &str) -> Result<UserTable, String> {
+    let mut result = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let fields: Vec<&str> = line.split('#').next().unwrap().split_whitespace().collect();
+        if !fields.is_empty() {
+            let user = parse_user(&fields).map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+            if result.contains_key(&user.name) {
+                return Err(format!("{}:{}: duplicate user: {}", path, i + 1, user.name));
+            }
+            result.insert(user.name.clone(), user);
+        }
+    }
+    Ok(result)
+}
+
+fn parse_user(fields: &[&str]) -> Result<User, String> {
+    let name = fields[0].to_lowercase();
+    let valid_name = name.len() <= 62 && !name.ends_with('-') &&
+        name.chars().all(|x| x.is_ascii_alphanumeric() || x == '-');
+    if !valid_name {
+        return Err(format!("bad user name: {}", fields[0]));
+    }
+    let secret = fields.get(1).ok_or("missing secret".to_owned())?.to_string();
+    let mut destinations = Vec::new();
+    let mut limit = Limit::default();
+    for field in &fields[2..] {
+        if let Some(idx) = field.find('=') {
+            let rate = field[idx + 1..].parse().map_err(|e| format!("bad {}: {}", field, e))?;
+            match &field[..idx] {
+                "query-rate" => limit.queries = Some(rate),
+                "byte-rate" => limit.bytes = Some(rate),
+                x => return Err(format!("unknown limit: {}", x))
+            }
+        } else {
+            destinations.push(parse_destination(field)?);
+        }
+    }
+    Ok(User{
+        name: name,
+        secret: secret,
+        destinations: if destinations.is_empty() { None } else { Some(destinations) },
+        limit: limit
+    })
+}
+
+fn parse_destination(field: &str) -> Result<Destination, String> {
+    let idx = field.rfind(':').ok_or(format!("bad destination: {}", field))?;
+    let (host, port) = (&field[..idx], &field[idx + 1..]);
+    Ok(Destination{
+        host: if host == "*" { None } else { Some(host.to_owned()) },
+        port: if port == "*" {
+            None
+        } else {
+            Some(port.parse().map_err(|_| format!("bad destination: {}", field))?)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destinations() {
+        let users = parse_users("users", concat!(
+            "# name secret destinations\n",
+            "Alice s3cret example.com:443 *:22  # no web\n",
+            "bob hunter2 10.0.0.1:*\n",
+            "carol open\n"
+        )).unwrap();
+        assert_eq!(users.len(), 3);
+
+        let alice = &users["alice"];
+        assert_eq!(alice.name, "alice");
+        assert_eq!(alice.secret, "s3cret");
+        assert!(alice.allows("example.com", 443));
+        assert!(alice.allows("EXAMPLE.com", 443));
+        assert!(!alice.allows("example.com", 80));
+        assert!(alice.allows("anywhere.test", 22));
+
+        let bob = &users["bob"];
+        assert!(bob.allows("10.0.0.1", 1));
+        assert!(bob.allows("10.0.0.1", 65535));
+        assert!(!bob.allows("10.0.0.2", 1));
+
+        assert!(users["carol"].destinations.is_none());
+        assert!(users["carol"].allows("example.com", 80));
+    }
+
+    #[test]
+    fn rates() {
+        let users = parse_users("users", "alice s query-rate=10 byte-rate=5000\nbob s *:80 \
+            byte-rate=100\ncarol s").unwrap();
+        let limits = |name: &str| (users[name].limit.queries, users[name].limit.bytes);
+        assert_eq!(limits("alice"), (Some(10), Some(5000)));
+        assert_eq!(limits("bob"), (None, Some(100)));
+        assert!(users["bob"].allows("example.com", 80));
+        assert_eq!(limits("carol"), (None, None));
+    }
+
+    #[test]
+    fn errors() {
+        let error = |contents: &str| parse_users("users", contents).err().unwrap();
+        assert_eq!(error("alice a\n\nALICE b"), "users:3: duplicate user: alice");
+        assert_eq!(error("alice"), "users:1: missing secret");
+        assert_eq!(error("al_ice s"), "users:1: bad user name: al_ice");
+        assert_eq!(error("alice- s"), "users:1: bad user name: alice-");
+        assert_eq!(error(&format!("{} s", "a".repeat(63))),
+            format!("users:1: bad user name: {}", "a".repeat(63)));
+        assert_eq!(error("# users\nalice s example.com"),
+            "users:2: bad destination: example.com");
+        assert_eq!(error("alice s example.com:http"),
+            "users:1: bad destination: example.com:http");
+        assert_eq!(error("alice s speed=3"), "users:1: unknown limit: speed");
+        assert!(error("alice s query-rate=fast").starts_with("users:1: bad query-rate=fast: "));
+        assert!(parse_users("users", &format!("{} s", "a".repeat(62))).is_ok());
+    }
+}
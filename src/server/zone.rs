@@ -0,0 +1,285 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use myodine::dns_proto::{Domain, Message, Record, RecordBody, RecordClass, RecordHeader,
+    RecordType, ResponseCode, SOADetails};
+use myodine::myo_proto::util::domain_ends_with;
+
+use flags::Flags;
+
+/// The TTL for the zone's SOA and NS records and for glue.
+const ZONE_TTL: u32 = 300;
+
+/// The records that make the server authoritative for its domain, along
+/// with any static records that are served next to the tunnel.
+pub struct Zone {
+    host: Domain,
+    soa: SOADetails,
+    name_servers: Vec<Domain>,
+    addresses: Vec<GlueAddr>,
+    records: Vec<Record>
+}
+
+/// An address of a name server inside the zone, written as `[NAME=]IP`.
+///
+/// An address without a name belongs to every name server inside the zone.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GlueAddr {
+    pub name: Option<Domain>,
+    pub addr: IpAddr
+}
+
+impl Zone {
+    /// Create the zone described by the configuration flags and a list of
+    /// static records.
+    pub fn new(flags: &Flags, records: Vec<Record>) -> Zone {
+        Zone{
+            host: flags.host.clone(),
+            soa: flags.soa.clone(),
+            name_servers: flags.name_servers.clone(),
+            addresses: flags.ns_addrs.clone(),
+            records: records
+        }
+    }
+
+    /// Answer a query for a static record, the zone apex, or one of the
+    /// name servers.
+    ///
+    /// Returns None if the query is for some other name in the zone.
+    pub fn answer(&self, query: &Message) -> Option<Message> {
+        let question = &query.questions[0];
+        let static_answers: Vec<Record> = self.records.iter()
+            .filter(|r| same_domain(&r.header.domain, &question.domain) &&
+                (r.header.record_type == question.record_type ||
+                 r.header.record_type == RecordType::CNAME))
+            .cloned()
+            .collect();
+        if !static_answers.is_empty() {
+            return Some(records_response(query.clone(), static_answers, Vec::new(), Vec::new()));
+        }
+        if same_domain(&question.domain, &self.host) {
+            Some(match question.record_type {
+                RecordType::SOA => {
+                    let soa = self.soa_record(ZONE_TTL);
+                    records_response(query.clone(), vec![soa], Vec::new(), Vec::new())
+                },
+                RecordType::NS => {
+                    let answers = self.name_servers.iter().map(|ns| {
+                        zone_record(&self.host, RecordType::NS, RecordBody::Domain(ns.clone()))
+                    }).collect();
+                    records_response(query.clone(), answers, Vec::new(), self.glue_records())
+                },
+                _ => self.empty_response(query.clone(), ResponseCode::NoError)
+            })
+        } else if self.is_glue_name(&question.domain) {
+            let answers = self.glue_records().into_iter()
+                .filter(|r| same_domain(&r.header.domain, &question.domain) &&
+                    r.header.record_type == question.record_type)
+                .collect();
+            let response = records_response(query.clone(), answers, Vec::new(), Vec::new());
+            if response.answers.is_empty() {
+                Some(self.empty_response(query.clone(), ResponseCode::NoError))
+            } else {
+                Some(response)
+            }
+        } else if self.records.iter().any(|r| same_domain(&r.header.domain, &question.domain)) {
+            Some(self.empty_response(query.clone(), ResponseCode::NoError))
+        } else {
+            None
+        }
+    }
+
+    /// Create a response with no answers, such as NXDOMAIN.
+    ///
+    /// The SOA record is included so that resolvers know how long to cache
+    /// the negative answer.
+    pub fn empty_response(&self, query: Message, code: ResponseCode) -> Message {
+        let mut response = records_response(query, Vec::new(),
+            vec![self.soa_record(self.soa.minimum)], Vec::new());
+        response.header.response_code = code;
+        response
+    }
+
+    fn soa_record(&self, ttl: u32) -> Record {
+        let mut record = zone_record(&self.host, RecordType::SOA,
+            RecordBody::SOA(self.soa.clone()));
+        record.header.ttl = ttl;
+        record
+    }
+
+    fn glue_records(&self) -> Vec<Record> {
+        let mut result = Vec::new();
+        for ns in &self.name_servers {
+            if !domain_ends_with(ns, &self.host) {
+                continue;
+            }
+            let addresses = self.addresses.iter()
+                .filter(|x| x.name.as_ref().map(|name| same_domain(name, ns)).unwrap_or(true));
+            for glue in addresses {
+                result.push(match glue.addr {
+                    IpAddr::V4(ip) => zone_record(ns, RecordType::A, RecordBody::A(ip)),
+                    IpAddr::V6(ip) => zone_record(ns, RecordType::AAAA, RecordBody::AAAA(ip))
+                });
+            }
+        }
+        result
+    }
+
+    fn is_glue_name(&self, domain: &Domain) -> bool {
+        self.name_servers.iter().any(|ns| {
+            domain_ends_with(ns, &self.host) && same_domain(ns, domain)
+        })
+    }
+}
+
+impl FromStr for GlueAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<GlueAddr, String> {
+        let (name, addr) = match s.find('=') {
+            Some(idx) => (Some(s[..idx].parse().map_err(|e| format!("{}", e))?), &s[idx + 1..]),
+            None => (None, s)
+        };
+        Ok(GlueAddr{name: name, addr: addr.parse().map_err(|e| format!("{}", e))?})
+    }
+}
+
+/// Check if two domain names are the same, ignoring case.
+pub fn same_domain(x: &Domain, y: &Domain) -> bool {
+    x.parts().len() == y.parts().len() && domain_ends_with(x, y)
+}
+
+/// Create a response to a query with the given record sections.
+fn records_response(
+    query: Message,
+    answers: Vec<Record>,
+    authorities: Vec<Record>,
+    additional: Vec<Record>
+) -> Message {
+    let mut response = query;
+    response.header.is_response = true;
+    response.header.response_code = ResponseCode::NoError;
+    response.header.answer_count = answers.len() as u16;
+    response.header.authority_count = authorities.len() as u16;
+    response.header.additional_count = additional.len() as u16;
+    response.answers = answers;
+    response.authorities = authorities;
+    response.additional = additional;
+    response
+}
+
+fn zone_record(domain: &Domain, record_type: RecordType, body: RecordBody) -> Record {
+    Record{
+        header: RecordHeader{
+            domain: domain.clone(),
+            record_type: record_type,
+            record_class: RecordClass::IN,
+            ttl: ZONE_TTL
+        },
+        body: body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use myodine::dns_proto::Question;
+
+    #[test]
+    fn soa_and_ns() {
+        let zone = test_zone(&["--ns", "ns1.tun.test", "--ns", "ns.example.com",
+            "--ns-addr", "192.0.2.1", "--ns-addr", "2001:db8::1", "--soa-minimum", "30"]);
+
+        let response = zone.answer(&query("TUN.test", RecordType::SOA)).unwrap();
+        assert_eq!(response.header.response_code, ResponseCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].header.ttl, ZONE_TTL);
+        match response.answers[0].body {
+            RecordBody::SOA(ref soa) => {
+                assert_eq!(soa.master_name, "ns1.tun.test".parse().unwrap());
+                assert_eq!(soa.responsible_name, "hostmaster.tun.test".parse().unwrap());
+                assert_eq!(soa.minimum, 30);
+            },
+            ref x => panic!("unexpected record body: {:?}", x)
+        }
+
+        // Only the name server inside the zone gets glue.
+        let response = zone.answer(&query("tun.test", RecordType::NS)).unwrap();
+        let name_servers: Vec<_> = response.answers.iter().map(|x| x.body.clone()).collect();
+        assert_eq!(name_servers, vec![
+            RecordBody::Domain("ns1.tun.test".parse().unwrap()),
+            RecordBody::Domain("ns.example.com".parse().unwrap())
+        ]);
+        assert_eq!(response.header.additional_count, 2);
+        assert_eq!(response.additional[0].body, RecordBody::A("192.0.2.1".parse().unwrap()));
+        assert_eq!(response.additional[1].body,
+            RecordBody::AAAA("2001:db8::1".parse().unwrap()));
+        assert!(response.additional.iter()
+            .all(|x| x.header.domain == "ns1.tun.test".parse().unwrap()));
+
+        let response = zone.answer(&query("ns1.tun.test", RecordType::AAAA)).unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].body, RecordBody::AAAA("2001:db8::1".parse().unwrap()));
+
+        // Other names in the zone are left to the tunnel.
+        assert!(zone.answer(&query("foo.tun.test", RecordType::A)).is_none());
+    }
+
+    #[test]
+    fn glue_per_name_server() {
+        let zone = test_zone(&["--ns", "ns1.tun.test", "--ns", "ns2.tun.test",
+            "--ns-addr", "ns1.tun.test=192.0.2.1", "--ns-addr", "NS2.tun.test=192.0.2.2",
+            "--ns-addr", "192.0.2.3"]);
+        let addrs = |name: &str| -> Vec<RecordBody> {
+            zone.answer(&query(name, RecordType::A)).unwrap().answers.into_iter()
+                .map(|x| x.body).collect()
+        };
+        assert_eq!(addrs("ns1.tun.test"), vec![
+            RecordBody::A("192.0.2.1".parse().unwrap()),
+            RecordBody::A("192.0.2.3".parse().unwrap())
+        ]);
+        assert_eq!(addrs("ns2.tun.test"), vec![
+            RecordBody::A("192.0.2.2".parse().unwrap()),
+            RecordBody::A("192.0.2.3".parse().unwrap())
+        ]);
+
+        assert!(Flags::parse_from(vec!["myodine-server", "--ns-addr", "ns3.tun.test=192.0.2.3",
+            "tun.test"]).is_err());
+        assert!("ns1.tun.test=nope".parse::<GlueAddr>().is_err());
+    }
+
+    #[test]
+    fn negative_responses() {
+        let zone = test_zone(&["--ns-addr", "192.0.2.1", "--soa-minimum", "30"]);
+        let check_empty = |response: Message, code: ResponseCode| {
+            assert_eq!(response.header.response_code, code);
+            assert_eq!(response.answers.len(), 0);
+            assert_eq!(response.header.authority_count, 1);
+            assert_eq!(response.authorities[0].header.record_type, RecordType::SOA);
+            assert_eq!(response.authorities[0].header.ttl, 30);
+        };
+
+        // Types that the apex or a name server do not have get NODATA.
+        check_empty(zone.answer(&query("tun.test", RecordType::A)).unwrap(),
+            ResponseCode::NoError);
+        check_empty(zone.answer(&query("ns.tun.test", RecordType::AAAA)).unwrap(),
+            ResponseCode::NoError);
+        check_empty(zone.empty_response(query("foo.tun.test", RecordType::A),
+            ResponseCode::NXDomain), ResponseCode::NXDomain);
+    }
+
+    fn test_zone(args: &[&str]) -> Zone {
+        let mut all_args = vec!["myodine-server"];
+        all_args.extend(args);
+        all_args.push("tun.test");
+        Zone::new(&Flags::parse_from(all_args).unwrap(), Vec::new())
+    }
+
+    fn query(name: &str, record_type: RecordType) -> Message {
+        Message::new_query(Question{
+            domain: name.parse().unwrap(),
+            record_type: record_type,
+            record_class: RecordClass::IN
+        })
+    }
+}